@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::thread::{JoinHandle, sleep};
+use std::time::Duration;
+
+use jni::sys::jlong;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use wasapi::Direction;
+
+use crate::error::{ErrorKind, NativeError, Res};
+use crate::logging::enter_stream_context;
+use crate::samples::{add_samples, DevSampleFormat, SampleConverter, write_samples};
+use crate::wasapi_impl::{do_get_avail_bytes, do_get_buffer_bytes, do_get_relative_drift, do_read, do_start, do_write,
+                         get_line_format, get_queued_frames, is_closing, RuntimeData};
+
+// Asynchronous resampling bridge from a capture line to a playback line on devices with independent clocks.
+// The bridge thread reads the capture line, resamples the data by a variable ratio and writes them to the playback line.
+// The ratio is the nominal rate ratio corrected by the relative drift of both device clocks (feed-forward),
+// plus a slow PI correction keeping the playback queue at the target fill (feedback). The feed-forward part
+// takes the bulk of the drift once the clocks are estimated, the feedback part removes the residual error
+// and keeps the fill from wandering off over long runs.
+
+// period of checking for capture data and playback room
+const POLL_PERIOD: Duration = Duration::from_millis(2);
+// weight of a new fill measurement in the smoothed fill, per capture chunk
+const FILL_SMOOTHING: f64 = 0.02;
+// proportional gain of the fill controller, ratio correction per second of fill error
+const KP: f64 = 0.005;
+// integral gain of the fill controller, ratio correction per second of accumulated fill error per second
+const KI: f64 = 0.0002;
+// max. ratio correction by the fill controller (1000 ppm)
+const MAX_CORRECTION: f64 = 0.001;
+// min. playback queue room left above the target fill, in playback chunks
+const FILL_HEADROOM_CHUNKS: usize = 2;
+// max. capture/playback rate ratio, the interpolation has no low-pass filter and downsampling aliases
+// the content above the playback Nyquist frequency (e.g. 48 kHz to 44.1 kHz is still inaudible)
+const MAX_DOWNSAMPLING: f64 = 1.1;
+
+/// State of the bridge published by the bridge thread
+#[derive(Default)]
+pub struct BridgeInfo {
+    // f64 bits of output frames per input frame
+    ratio: AtomicU64,
+    // f64 bits of the smoothed playback queue fill in ms
+    fill_ms: AtomicU64,
+    // f64 bits of the fill controller correction in ppm
+    correction_ppm: AtomicU64,
+}
+
+impl BridgeInfo {
+    /// (output/input frame ratio, smoothed playback fill in ms, fill correction in ppm)
+    pub fn snapshot(&self) -> (f64, f64, f64) {
+        (f64::from_bits(self.ratio.load(Ordering::Relaxed)),
+         f64::from_bits(self.fill_ms.load(Ordering::Relaxed)),
+         f64::from_bits(self.correction_ppm.load(Ordering::Relaxed)))
+    }
+
+    fn publish(&self, ratio: f64, fill_ms: f64, correction: f64) {
+        self.ratio.store(ratio.to_bits(), Ordering::Relaxed);
+        self.fill_ms.store(fill_ms.to_bits(), Ordering::Relaxed);
+        self.correction_ppm.store((correction * 1e6).to_bits(), Ordering::Relaxed);
+    }
+}
+
+struct Bridge {
+    playback_handle: jlong,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<Res<()>>,
+    info: Arc<BridgeInfo>,
+}
+
+lazy_static! {
+    // bridges keyed by the handle of their capture line
+    static ref BRIDGES: Mutex<HashMap<jlong, Bridge>> = Mutex::new(HashMap::new());
+}
+
+/// Starts bridging the capture line into the playback line, starting both lines
+pub fn start_bridge(capture_handle: jlong, capture: Arc<RuntimeData>, playback_handle: jlong, playback: Arc<RuntimeData>,
+                    target_fill_ms: usize) -> Res<()> {
+    let capt_format = get_line_format(&capture);
+    let play_format = get_line_format(&playback);
+    if capt_format.dir != Direction::Capture || play_format.dir != Direction::Render {
+        return Err(NativeError::illegal_argument("Bridge requires a capture line and a playback line"));
+    }
+    if capt_format.rate as f64 > play_format.rate as f64 * MAX_DOWNSAMPLING {
+        let msg = format!("Bridge cannot downsample {} Hz to {} Hz without aliasing, max. rate ratio {}",
+                          capt_format.rate, play_format.rate, MAX_DOWNSAMPLING);
+        return Err(NativeError::new(ErrorKind::UnsupportedFormat, &msg));
+    }
+    let target_frames = target_fill_ms * play_format.rate / 1000;
+    let queue_frames = do_get_buffer_bytes(&playback, &Direction::Render)? / (play_format.sample_bytes * play_format.channels);
+    if target_frames == 0 || target_frames + FILL_HEADROOM_CHUNKS * play_format.chunk_frames > queue_frames {
+        let msg = format!("Bridge target fill {} ms does not fit the playback buffer of {} ms",
+                          target_fill_ms, queue_frames * 1000 / play_format.rate);
+        return Err(NativeError::illegal_argument(&msg));
+    }
+    let mut bridges = BRIDGES.lock()?;
+    bridges.retain(|_, bridge| !bridge.handle.is_finished());
+    if bridges.contains_key(&capture_handle) {
+        return Err(NativeError::illegal_state("Capture line is already bridged"));
+    }
+    if bridges.values().any(|bridge| bridge.playback_handle == playback_handle) {
+        return Err(NativeError::illegal_state("Playback line is already fed by a bridge"));
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let info = Arc::new(BridgeInfo::default());
+    let stop_cloned = stop.clone();
+    let info_cloned = info.clone();
+    let handle = thread::Builder::new()
+        .name("WasapiBridge".to_string())
+        .spawn(move || {
+            let _ctx_guard = enter_stream_context(&capture.log_ctx);
+            let result = bridge_loop(&capture, &playback, target_frames, &stop_cloned, &info_cloned);
+            if let Err(err) = result.as_ref() {
+                warn!("BRIDGE: bridge from {} to {} failed: {}", capture.device_name, playback.device_name, err);
+            }
+            result
+        })?;
+    bridges.insert(capture_handle, Bridge { playback_handle, stop, handle, info });
+    Ok(())
+}
+
+/// Stops the bridge of the capture line, returning the error the bridge failed with, if any
+pub fn stop_bridge(capture_handle: jlong) -> Res<()> {
+    let bridge = match BRIDGES.lock()?.remove(&capture_handle) {
+        Some(bridge) => bridge,
+        None => return Err(NativeError::illegal_state("Capture line is not bridged")),
+    };
+    join_bridge(bridge)
+}
+
+/// Stops bridges from or to the line being closed
+pub fn close_bridges(line_handle: jlong) -> Res<()> {
+    let closed: Vec<Bridge> = {
+        let mut bridges = BRIDGES.lock()?;
+        let keys: Vec<jlong> = bridges.iter()
+            .filter(|(capture_handle, bridge)| **capture_handle == line_handle || bridge.playback_handle == line_handle)
+            .map(|(capture_handle, _)| *capture_handle)
+            .collect();
+        keys.iter().filter_map(|key| bridges.remove(key)).collect()
+    };
+    for bridge in closed {
+        // the line is closing, a failed bridge was already logged
+        join_bridge(bridge).unwrap_or(());
+    }
+    Ok(())
+}
+
+fn join_bridge(bridge: Bridge) -> Res<()> {
+    bridge.stop.store(true, Ordering::Relaxed);
+    match bridge.handle.join() {
+        Ok(result) => result,
+        Err(_) => Err(NativeError::internal("Bridge thread panicked")),
+    }
+}
+
+pub fn get_bridge_info(capture_handle: jlong) -> Res<(f64, f64, f64)> {
+    match BRIDGES.lock()?.get(&capture_handle) {
+        Some(bridge) => Ok(bridge.info.snapshot()),
+        None => Err(NativeError::illegal_state("Capture line is not bridged")),
+    }
+}
+
+fn bridge_loop(capture: &RuntimeData, playback: &RuntimeData, target_frames: usize, stop: &AtomicBool, info: &BridgeInfo) -> Res<()> {
+    let capt_format = get_line_format(capture);
+    let play_format = get_line_format(playback);
+    let sample_bytes = play_format.sample_bytes;
+    let channels = play_format.channels;
+    let frame_bytes = sample_bytes * channels;
+    let nominal_step = capt_format.rate as f64 / play_format.rate as f64;
+    debug!("BRIDGE: bridging {} ({} Hz) to {} ({} Hz), target fill {} frames",
+           capture.device_name, capt_format.rate, playback.device_name, play_format.rate, target_frames);
+
+    // capture chunks converted to the playback sample format and channels, resampled in the playback format
+    let converter = SampleConverter::new(capt_format.sample_bytes, capt_format.channels, DevSampleFormat::Int(sample_bytes), channels);
+    let in_bytes = capt_format.chunk_frames * capt_format.sample_bytes * capt_format.channels;
+    let mut in_buf = vec![0u8; in_bytes];
+    let mut converted = vec![0u8; capt_format.chunk_frames * frame_bytes];
+    let mut in_samples = vec![0.0; capt_format.chunk_frames * channels];
+    let mut out_samples = Vec::new();
+    let mut out_buf = Vec::new();
+    let mut resampler = Resampler::new(channels);
+
+    // prefilling the playback queue with silence up to the target fill, never blocking on a full queue
+    let room = do_get_avail_bytes(playback, &Direction::Render)? / frame_bytes * frame_bytes;
+    let silence = vec![0u8; (target_frames * frame_bytes).min(room)];
+    do_write(playback, &silence, 0, silence.len())?;
+    do_start(playback, &Direction::Render)?;
+    do_start(capture, &Direction::Capture)?;
+
+    let mut fill = target_frames as f64;
+    let mut integral = 0.0;
+    let chunk_s = capt_format.chunk_frames as f64 / capt_format.rate as f64;
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            debug!("BRIDGE: stopped");
+            return Ok(());
+        }
+        if is_closing(capture) || is_closing(playback) {
+            info!("BRIDGE: line closed, bridge from {} to {} ends", capture.device_name, playback.device_name);
+            return Ok(());
+        }
+        // reading only complete chunks available, a blocking read would not notice the stop
+        if do_get_avail_bytes(capture, &Direction::Capture)? < in_bytes {
+            sleep(POLL_PERIOD);
+            continue;
+        }
+        if do_read(capture, &mut in_buf, 0, in_bytes)? < in_bytes {
+            // interrupted by flush or close
+            continue;
+        }
+        converter.convert(&in_buf, &mut converted);
+        in_samples.fill(0.0);
+        add_samples(&mut in_samples, &converted, sample_bytes, 1.0);
+
+        // fill controller: queue above target = consuming input faster
+        fill += FILL_SMOOTHING * (get_queued_frames(playback)? as f64 - fill);
+        let error_s = (fill - target_frames as f64) / play_format.rate as f64;
+        integral = (integral + error_s * chunk_s).clamp(-MAX_CORRECTION / KI, MAX_CORRECTION / KI);
+        let correction = (KP * error_s + KI * integral).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+        // input frames per output frame at the real device rates, nominal before both clocks are estimated
+        let relative_drift = match do_get_relative_drift(capture, playback)? {
+            ratio if ratio > 0. => ratio,
+            _ => 1.0,
+        };
+        let step = nominal_step * relative_drift * (1.0 + correction);
+        info.publish(1.0 / step, fill * 1000.0 / play_format.rate as f64, correction);
+
+        out_samples.clear();
+        resampler.process(&in_samples, step, &mut out_samples);
+        out_buf.resize(out_samples.len() / channels * frame_bytes, 0);
+        write_samples(&out_samples, &mut out_buf, sample_bytes, channels, 1.0, 1.0);
+        // waiting for room so that a stopped playback line does not block the stop,
+        // do_write returns only when the line is flushed or closed, not on the bridge stop
+        while do_get_avail_bytes(playback, &Direction::Render)? < out_buf.len() {
+            if stop.load(Ordering::Relaxed) || is_closing(playback) {
+                break;
+            }
+            sleep(POLL_PERIOD);
+        }
+        if stop.load(Ordering::Relaxed) || is_closing(playback) {
+            // the resampled chunk is dropped, the loop ends at its start
+            continue;
+        }
+        if do_write(playback, &out_buf, 0, out_buf.len())? < out_buf.len() {
+            debug!("BRIDGE: write interrupted by flush/close");
+        }
+    }
+}
+
+/// Variable-ratio cubic (Catmull-Rom) interpolation of interleaved frames
+struct Resampler {
+    channels: usize,
+    // input frames not consumed yet, incl. the frame preceding the position
+    input: Vec<f64>,
+    // position of the next output frame in input frames, at least 1
+    pos: f64,
+}
+
+impl Resampler {
+    fn new(channels: usize) -> Self {
+        // one frame of silence preceding the first input frame
+        Resampler { channels, input: vec![0.0; channels], pos: 1.0 }
+    }
+
+    /// Appends new input frames, adds output frames while 2 input frames past the position are available
+    fn process(&mut self, new_frames: &[f64], step: f64, out: &mut Vec<f64>) {
+        let channels = self.channels;
+        self.input.extend_from_slice(new_frames);
+        let frames = self.input.len() / channels;
+        while (self.pos as usize) + 2 < frames {
+            let idx = self.pos as usize;
+            let t = self.pos - idx as f64;
+            for ch in 0..channels {
+                let at = |frame: usize| self.input[frame * channels + ch];
+                out.push(catmull_rom(at(idx - 1), at(idx), at(idx + 1), at(idx + 2), t));
+            }
+            self.pos += step;
+        }
+        // keeping the frame preceding the position
+        let consumed = ((self.pos as usize) - 1).min(frames);
+        self.input.drain(..consumed * channels);
+        self.pos -= consumed as f64;
+    }
+}
+
+fn catmull_rom(y0: f64, y1: f64, y2: f64, y3: f64, t: f64) -> f64 {
+    0.5 * (2.0 * y1
+        + (y2 - y0) * t
+        + (2.0 * y0 - 5.0 * y1 + 4.0 * y2 - y3) * t * t
+        + (3.0 * y1 - y0 - 3.0 * y2 + y3) * t * t * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catmull_rom_passes_through_points() {
+        assert_eq!(catmull_rom(3.0, -1.0, 7.0, 2.0, 0.0), -1.0);
+        assert_eq!(catmull_rom(3.0, -1.0, 7.0, 2.0, 1.0), 7.0);
+        // linear data interpolated exactly
+        assert_eq!(catmull_rom(0.0, 1.0, 2.0, 3.0, 0.25), 1.25);
+    }
+
+    #[test]
+    fn unity_step_reproduces_input() {
+        let mut resampler = Resampler::new(2);
+        let input: Vec<f64> = (0..40).map(|value| value as f64).collect();
+        let mut out = Vec::new();
+        for chunk in input.chunks(10) {
+            resampler.process(chunk, 1.0, &mut out);
+        }
+        // the last 2 frames wait for the following input
+        assert_eq!(out.as_slice(), &input[..input.len() - 4]);
+    }
+
+    #[test]
+    fn output_frames_follow_ratio() {
+        for step in [0.5, 0.91875, 1.0884, 1.001] {
+            let mut resampler = Resampler::new(1);
+            let chunk = vec![0.0; 48];
+            let mut out = Vec::new();
+            for _ in 0..1000 {
+                resampler.process(&chunk, step, &mut out);
+            }
+            let expected = 48000.0 / step;
+            assert!((out.len() as f64 - expected).abs() < 4.0, "step {}: {} frames, expected {}", step, out.len(), expected);
+        }
+    }
+}
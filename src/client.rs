@@ -0,0 +1,301 @@
+use std::ptr;
+
+use log::{debug, warn};
+use wasapi::{Device, ShareMode, WaveFormat};
+use windows::core::{GUID, HSTRING, implement, PCWSTR};
+use windows::Win32::Foundation::{BOOL, CloseHandle, HANDLE, WAIT_OBJECT_0};
+use windows::Win32::Media::Audio::{AUDCLNT_SHAREMODE_EXCLUSIVE, AUDCLNT_SHAREMODE_SHARED, AudioSessionDisconnectReason,
+                                   AudioSessionState, IAudioCaptureClient, IAudioClient, IAudioClock, IAudioRenderClient,
+                                   IAudioSessionControl, IAudioSessionEvents, IAudioSessionEvents_Impl, IMMDevice,
+                                   IMMDeviceEnumerator, MMDeviceEnumerator, WAVEFORMATEX};
+use windows::Win32::System::Com::{CLSCTX_ALL, CoCreateInstance};
+use windows::Win32::System::Threading::{CreateEventW, WaitForSingleObject};
+
+use crate::error::{NativeError, Res};
+
+// Stream client of an opened line on IAudioClient: initialized either event-driven (AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+// signalling an event handle every device period) or without event callbacks for the polling wait mode,
+// for drivers failing the event-driven initialization. The formats are probed with the wasapi-rs client before.
+
+// audiosessiontypes.h / audioclient.h values
+const AUDCLNT_STREAMFLAGS_EVENTCALLBACK: u32 = 0x0004_0000;
+const AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY: u32 = 0x0800_0000;
+const AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM: u32 = 0x8000_0000;
+const AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY: u32 = 0x1;
+const AUDCLNT_BUFFERFLAGS_SILENT: u32 = 0x2;
+const AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR: u32 = 0x4;
+
+/// Event signalled by the device every period in the event-driven mode
+pub struct EventHandle {
+    handle: HANDLE,
+}
+
+impl EventHandle {
+    fn new() -> Res<Self> {
+        let handle = unsafe { CreateEventW(ptr::null(), false, false, PCWSTR::null())? };
+        Ok(EventHandle { handle })
+    }
+
+    /// Err on timeout or failure
+    pub fn wait_for_event(&self, timeout_ms: u32) -> Res<()> {
+        let result = unsafe { WaitForSingleObject(self.handle, timeout_ms) };
+        if result.0 != WAIT_OBJECT_0.0 {
+            return Err(NativeError::timeout(&format!("No device event within {} ms", timeout_ms)));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EventHandle {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.handle) };
+    }
+}
+
+/// Flags of a captured packet
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BufferFlags {
+    pub data_discontinuity: bool,
+    pub silent: bool,
+    pub timestamp_error: bool,
+}
+
+impl BufferFlags {
+    pub fn new(flags: u32) -> Self {
+        BufferFlags {
+            data_discontinuity: flags & AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY != 0,
+            silent: flags & AUDCLNT_BUFFERFLAGS_SILENT != 0,
+            timestamp_error: flags & AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR != 0,
+        }
+    }
+}
+
+pub struct StreamClient {
+    client: IAudioClient,
+    exclusive: bool,
+    event_driven: bool,
+}
+
+impl StreamClient {
+    /// Initializes a new client of the device.
+    /// event_driven: returns the event handle signalled by the device, otherwise the caller polls the buffer padding.
+    pub fn initialize(device: &Device, wvformat: &WaveFormat, buffer_duration: i64, period: i64, sharemode: &ShareMode,
+                      convert_rate: bool, event_driven: bool) -> Res<(Self, Option<EventHandle>)> {
+        let client = activate_client(&device.get_id()?)?;
+        let exclusive = matches!(sharemode, ShareMode::Exclusive);
+        let mut streamflags = if event_driven { AUDCLNT_STREAMFLAGS_EVENTCALLBACK } else { 0 };
+        if convert_rate {
+            streamflags |= AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM | AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY;
+        }
+        let (mode, periodicity) = if exclusive {
+            (AUDCLNT_SHAREMODE_EXCLUSIVE, period)
+        } else {
+            // the engine period is used in the shared mode
+            (AUDCLNT_SHAREMODE_SHARED, 0)
+        };
+        debug!("Initializing {} client, buffer {} x 100ns, period {} x 100ns, flags {:#x}",
+            if event_driven { "event-driven" } else { "polled" }, buffer_duration, periodicity, streamflags);
+        let format_ptr = &wvformat.wave_fmt as *const _ as *const WAVEFORMATEX;
+        unsafe { client.Initialize(mode, streamflags, buffer_duration, periodicity, format_ptr, ptr::null())? };
+        let handle = if event_driven {
+            let handle = EventHandle::new()?;
+            unsafe { client.SetEventHandle(handle.handle)? };
+            Some(handle)
+        } else {
+            None
+        };
+        Ok((StreamClient { client, exclusive, event_driven }, handle))
+    }
+
+    pub fn is_event_driven(&self) -> bool {
+        self.event_driven
+    }
+
+    pub fn get_bufferframecount(&self) -> Res<u32> {
+        Ok(unsafe { self.client.GetBufferSize()? })
+    }
+
+    pub fn get_current_padding(&self) -> Res<u32> {
+        Ok(unsafe { self.client.GetCurrentPadding()? })
+    }
+
+    /// Frames writable to the render buffer
+    pub fn get_available_space_in_frames(&self) -> Res<u32> {
+        let buffer_frames = self.get_bufferframecount()?;
+        if self.exclusive && self.event_driven {
+            // each event hands over a whole buffer
+            return Ok(buffer_frames);
+        }
+        Ok(buffer_frames.saturating_sub(self.get_current_padding()?))
+    }
+
+    pub fn start_stream(&self) -> Res<()> {
+        Ok(unsafe { self.client.Start()? })
+    }
+
+    /// Stopping a stopped stream is no error
+    pub fn stop_stream(&self) -> Res<()> {
+        Ok(unsafe { self.client.Stop()? })
+    }
+
+    pub fn reset_stream(&self) -> Res<()> {
+        Ok(unsafe { self.client.Reset()? })
+    }
+
+    /// Max. latency of the initialized stream reported by the driver, in 100ns units
+    pub fn get_stream_latency(&self) -> Res<i64> {
+        Ok(unsafe { self.client.GetStreamLatency()? })
+    }
+
+    pub fn get_audioclock(&self) -> Res<StreamClock> {
+        Ok(StreamClock { clock: unsafe { self.client.GetService::<IAudioClock>()? } })
+    }
+
+    pub fn get_audiorenderclient(&self) -> Res<RenderClient> {
+        Ok(RenderClient { client: unsafe { self.client.GetService::<IAudioRenderClient>()? } })
+    }
+
+    pub fn get_audiocaptureclient(&self) -> Res<CaptureClient> {
+        Ok(CaptureClient { client: unsafe { self.client.GetService::<IAudioCaptureClient>()? } })
+    }
+
+    /// Calls the callback from a COM thread when the session of the stream gets disconnected,
+    /// until the returned registration is dropped
+    pub fn on_disconnected<F>(&self, callback: F) -> Res<SessionRegistration>
+        where F: Fn(AudioSessionDisconnectReason) + 'static {
+        let control = unsafe { self.client.GetService::<IAudioSessionControl>()? };
+        let events: IAudioSessionEvents = SessionEvents { on_disconnected: Box::new(callback) }.into();
+        unsafe { control.RegisterAudioSessionNotification(&events)? };
+        Ok(SessionRegistration { control, events })
+    }
+}
+
+fn activate_client(endpoint_id: &str) -> Res<IAudioClient> {
+    let client = unsafe {
+        let enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+        let device: IMMDevice = enumerator.GetDevice(&HSTRING::from(endpoint_id))?;
+        device.Activate::<IAudioClient>(CLSCTX_ALL, ptr::null())?
+    };
+    Ok(client)
+}
+
+pub struct StreamClock {
+    clock: IAudioClock,
+}
+
+impl StreamClock {
+    pub fn get_frequency(&self) -> Res<u64> {
+        Ok(unsafe { self.clock.GetFrequency()? })
+    }
+
+    /// (device position in frequency units, QPC position in 100ns units)
+    pub fn get_position(&self) -> Res<(u64, u64)> {
+        let mut pos = 0;
+        let mut qpc_pos = 0;
+        unsafe { self.clock.GetPosition(&mut pos, &mut qpc_pos)? };
+        Ok((pos, qpc_pos))
+    }
+}
+
+pub struct RenderClient {
+    client: IAudioRenderClient,
+}
+
+impl RenderClient {
+    pub fn write_to_device(&self, nbr_frames: usize, frame_bytes: usize, data: &[u8]) -> Res<()> {
+        if nbr_frames == 0 {
+            return Ok(());
+        }
+        let nbr_bytes = nbr_frames * frame_bytes;
+        if nbr_bytes != data.len() {
+            let msg = format!("Wrong length of data, got {} bytes, expected {} bytes", data.len(), nbr_bytes);
+            return Err(NativeError::internal(&msg));
+        }
+        unsafe {
+            let buffer = self.client.GetBuffer(nbr_frames as u32)?;
+            ptr::copy_nonoverlapping(data.as_ptr(), buffer, nbr_bytes);
+            self.client.ReleaseBuffer(nbr_frames as u32, 0)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct CaptureClient {
+    client: IAudioCaptureClient,
+}
+
+impl CaptureClient {
+    /// Reads the next packet, returns the frames read (0 = no packet available)
+    pub fn read_from_device(&self, frame_bytes: usize, data: &mut [u8]) -> Res<(u32, BufferFlags)> {
+        let mut buffer: *mut u8 = ptr::null_mut();
+        let mut nbr_frames: u32 = 0;
+        let mut flags: u32 = 0;
+        unsafe { self.client.GetBuffer(&mut buffer, &mut nbr_frames, &mut flags, ptr::null_mut(), ptr::null_mut())? };
+        let flags = BufferFlags::new(flags);
+        if nbr_frames == 0 {
+            return Ok((0, flags));
+        }
+        let nbr_bytes = nbr_frames as usize * frame_bytes;
+        if nbr_bytes > data.len() {
+            unsafe { self.client.ReleaseBuffer(nbr_frames)? };
+            let msg = format!("Packet of {} bytes does not fit the buffer of {} bytes", nbr_bytes, data.len());
+            return Err(NativeError::internal(&msg));
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(buffer, data.as_mut_ptr(), nbr_bytes);
+            self.client.ReleaseBuffer(nbr_frames)?;
+        }
+        Ok((nbr_frames, flags))
+    }
+}
+
+/// Session notification registered by StreamClient::on_disconnected, unregistered when dropped
+pub struct SessionRegistration {
+    control: IAudioSessionControl,
+    events: IAudioSessionEvents,
+}
+
+impl Drop for SessionRegistration {
+    fn drop(&mut self) {
+        if let Err(err) = unsafe { self.control.UnregisterAudioSessionNotification(&self.events) } {
+            warn!("Failed to unregister session notification: {}", err);
+        }
+    }
+}
+
+#[implement(IAudioSessionEvents)]
+struct SessionEvents {
+    on_disconnected: Box<dyn Fn(AudioSessionDisconnectReason)>,
+}
+
+impl IAudioSessionEvents_Impl for SessionEvents {
+    fn OnDisplayNameChanged(&self, _newdisplayname: &PCWSTR, _eventcontext: *const GUID) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnIconPathChanged(&self, _newiconpath: &PCWSTR, _eventcontext: *const GUID) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnSimpleVolumeChanged(&self, _newvolume: f32, _newmute: BOOL, _eventcontext: *const GUID) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnChannelVolumeChanged(&self, _channelcount: u32, _newchannelvolumearray: *const f32, _changedchannel: u32,
+                              _eventcontext: *const GUID) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnGroupingParamChanged(&self, _newgroupingparam: *const GUID, _eventcontext: *const GUID) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnStateChanged(&self, _newstate: AudioSessionState) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnSessionDisconnected(&self, disconnectreason: AudioSessionDisconnectReason) -> windows::core::Result<()> {
+        (self.on_disconnected)(disconnectreason);
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::debug;
+
+// Drift of the device clock against QPC, estimated from the clock positions sampled by the inner loops.
+// IAudioClock reports each position together with the QPC time it was taken at, the ratio of the device time
+// and the QPC time elapsed over a sliding window gives the real device rate. The ratio is smoothed once per
+// reference point to stay stable.
+
+// interval between reference points of the sliding window, in seconds
+const REF_INTERVAL_S: f64 = 1.0;
+// reference points kept, the window spans up to REF_POINTS seconds
+const REF_POINTS: usize = 30;
+// shortest window producing an estimate, in seconds
+const MIN_WINDOW_S: f64 = 2.0;
+// weight of a new window ratio in the smoothed estimate
+const SMOOTHING: f64 = 0.2;
+// QPC positions of IAudioClock are in 100 ns units
+const QPC_UNITS_PER_S: f64 = 10_000_000.0;
+
+/// Drift estimate published by the inner thread, readable by the outer side
+#[derive(Default)]
+pub struct ClockDrift {
+    // f64 bits of the device time / QPC time ratio, 0 = no estimate yet
+    ratio: AtomicU64,
+    // f64 bits of the window of the last estimate in seconds
+    window_s: AtomicU64,
+}
+
+impl ClockDrift {
+    /// (device time / QPC time ratio, window of the estimate in seconds), (0, 0) before the first estimate
+    pub fn snapshot(&self) -> (f64, f64) {
+        (f64::from_bits(self.ratio.load(Ordering::Relaxed)),
+         f64::from_bits(self.window_s.load(Ordering::Relaxed)))
+    }
+
+    fn publish(&self, ratio: f64, window_s: f64) {
+        self.ratio.store(ratio.to_bits(), Ordering::Relaxed);
+        self.window_s.store(window_s.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Owned by the inner loop, restarted whenever the device clock stops or jumps (stream stop, reset, missed event)
+pub struct DriftEstimator {
+    // (device time s, QPC time s)
+    refs: VecDeque<(f64, f64)>,
+    ratio: Option<f64>,
+    log_prefix: String,
+}
+
+impl DriftEstimator {
+    pub fn new(log_prefix: String) -> Self {
+        DriftEstimator { refs: VecDeque::with_capacity(REF_POINTS + 1), ratio: None, log_prefix }
+    }
+
+    /// Starts a new window, the smoothed estimate is kept
+    pub fn restart(&mut self) {
+        self.refs.clear();
+    }
+
+    /// dev_time: clock position in seconds, qpc_pos: QPC time of the position in 100 ns units
+    pub fn update(&mut self, dev_time: f64, qpc_pos: u64, published: &ClockDrift) {
+        let qpc_time = qpc_pos as f64 / QPC_UNITS_PER_S;
+        let last_qpc_time = match self.refs.back() {
+            Some(&(_, last_qpc_time)) => last_qpc_time,
+            None => {
+                self.refs.push_back((dev_time, qpc_time));
+                return;
+            }
+        };
+        if qpc_time - last_qpc_time < REF_INTERVAL_S {
+            return;
+        }
+        self.refs.push_back((dev_time, qpc_time));
+        if self.refs.len() > REF_POINTS + 1 {
+            self.refs.pop_front();
+        }
+        let (first_dev_time, first_qpc_time) = self.refs[0];
+        let window_s = qpc_time - first_qpc_time;
+        if window_s < MIN_WINDOW_S {
+            return;
+        }
+        let window_ratio = (dev_time - first_dev_time) / window_s;
+        let ratio = match self.ratio {
+            Some(ratio) => ratio + SMOOTHING * (window_ratio - ratio),
+            None => {
+                debug!("{}: first clock drift estimate {:.1} ppm", self.log_prefix, (window_ratio - 1.0) * 1e6);
+                window_ratio
+            }
+        };
+        self.ratio = Some(ratio);
+        published.publish(ratio, window_s);
+    }
+}
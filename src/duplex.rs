@@ -0,0 +1,502 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+use lazy_static::lazy_static;
+use log::{debug, error, Level, trace, warn};
+use wasapi::{Device, Direction};
+
+use crate::client::{CaptureClient, EventHandle, SessionRegistration, StreamClient};
+use crate::drift::ClockDrift;
+use crate::error::{ErrorKind, NativeError, Res};
+use crate::gain::{FadeStop, GainStage};
+use crate::logging::{enter_stream_context, log_event, LogEvent, StreamLogContext};
+use crate::waiter::{PollTimer, WaitMode};
+use crate::wasapi_impl::{CaptSyncData, device_open, DeviceState, Disconnected, handle_capt_flags, LineStats, send_capt_chunk};
+
+// Full-duplex streams: the capture line of a duplex pair has no own device thread, the playback loop of the
+// exclusive render device opens the capture device, starts and stops both streams together and reads the capture
+// device right after each render event.
+// Frames of both streams are numbered by a shared sample counter. The render frame and the capture frame with
+// the same counter value pass the device converters in the same device period, the remaining offset is the fixed
+// converter latency of the device, reported by the driver as the stream latency of both clients.
+
+// max. part of the period the loop waits for the capture event after the render event,
+// the rest of the period is left for refilling the render buffer
+const CAPTURE_WAIT_DIVISOR: i64 = 4;
+
+lazy_static! {
+    // capture device ID -> render device ID whose playback loop drives the capture lines
+    static ref DUPLEX_DEVICES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    // render device ID -> duplex bus of its playing line
+    static ref DUPLEX_BUSES: Mutex<HashMap<String, DuplexBusEntry>> = Mutex::new(HashMap::new());
+}
+
+pub fn set_duplex_device(capture_device_id: &str, render_device_id: Option<String>) -> Res<()> {
+    let mut devices = DUPLEX_DEVICES.lock()?;
+    match render_device_id {
+        Some(render_device_id) => devices.insert(capture_device_id.to_owned(), render_device_id),
+        None => devices.remove(capture_device_id),
+    };
+    Ok(())
+}
+
+pub fn get_duplex_device(capture_device_id: &str) -> Res<Option<String>> {
+    Ok(DUPLEX_DEVICES.lock()?.get(capture_device_id).cloned())
+}
+
+/// Position of the last chunk transferred by a duplex loop, readable by the outer side
+#[derive(Default)]
+pub struct DuplexPosition {
+    // (line frames, shared counter)
+    pos: Mutex<(u64, u64)>,
+    // stream latency of the device client in frames
+    latency_frames: AtomicU64,
+}
+
+impl DuplexPosition {
+    fn update(&self, line_frames: u64, stream_frames: u64) {
+        // never blocking the loop, a position being read is updated by the next chunk
+        if let Ok(mut pos) = self.pos.try_lock() {
+            *pos = (line_frames, stream_frames);
+        }
+    }
+
+    /// (frame position of the line, shared counter value) of the first frame of the last transferred chunk
+    pub fn snapshot(&self) -> Res<(u64, u64)> {
+        Ok(*self.pos.lock()?)
+    }
+
+    fn set_latency(&self, frames: u64) {
+        self.latency_frames.store(frames, Ordering::Relaxed);
+    }
+
+    /// Stream latency of the device client in frames, 0 before the stream started
+    pub fn latency(&self) -> u64 {
+        self.latency_frames.load(Ordering::Relaxed)
+    }
+}
+
+/// Max. latency of the initialized stream reported by the driver, in frames.
+/// Read by IAudioClient::GetStreamLatency on the client of the line, the wasapi crate does not wrap it.
+fn stream_latency_frames(audio_client: &StreamClient, rate: usize) -> Res<u64> {
+    // a negative REFERENCE_TIME is a driver bug, not a latency
+    let latency_ns00 = audio_client.get_stream_latency()?.max(0) as u64;
+    Ok((latency_ns00 * rate as u64 + 5_000_000) / 10_000_000)
+}
+
+/// Registered by the playing line of an exclusive render device, used for attaching the capture line
+#[derive(Clone)]
+pub struct DuplexBusEntry {
+    tx_attach: Sender<DuplexCapture>,
+    // format and period of the playing line
+    pub rate: usize,
+    pub period_ns00: i64,
+    pub chunk_frames: usize,
+    // a capture line is attached or being attached
+    pub capture_attached: Arc<AtomicBool>,
+    // of the playback loop, the capture device runs on the same clock
+    pub clock_drift: Arc<ClockDrift>,
+}
+
+impl DuplexBusEntry {
+    pub fn new(tx_attach: Sender<DuplexCapture>, rate: usize, period_ns00: i64, chunk_frames: usize, capture_attached: Arc<AtomicBool>,
+               clock_drift: Arc<ClockDrift>) -> Self {
+        DuplexBusEntry { tx_attach, rate, period_ns00, chunk_frames, capture_attached, clock_drift }
+    }
+
+    /// Checks a capture line can join the duplex stream, reserving its place
+    pub fn reserve_capture(&self, rate: usize) -> Res<()> {
+        if rate != self.rate {
+            let msg = format!("Duplex capture must use the playback rate {}, requested {}", self.rate, rate);
+            return Err(NativeError::new(ErrorKind::UnsupportedFormat, &msg));
+        }
+        if self.capture_attached.compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+            return Err(NativeError::illegal_state("The duplex stream already has a capture line"));
+        }
+        Ok(())
+    }
+
+    pub fn attach(&self, capture: DuplexCapture) -> Res<()> {
+        if self.tx_attach.send(capture).is_err() {
+            self.capture_attached.store(false, Ordering::Relaxed);
+            return Err(NativeError::illegal_state("Duplex playback line already closed"));
+        }
+        Ok(())
+    }
+}
+
+pub fn register_duplex_bus(device_id: &str, entry: DuplexBusEntry) -> Res<()> {
+    DUPLEX_BUSES.lock()?.insert(device_id.to_owned(), entry);
+    Ok(())
+}
+
+pub fn unregister_duplex_bus(device_id: &str) -> Res<()> {
+    DUPLEX_BUSES.lock()?.remove(device_id);
+    Ok(())
+}
+
+pub fn get_duplex_bus(device_id: &str) -> Res<Option<DuplexBusEntry>> {
+    Ok(DUPLEX_BUSES.lock()?.get(device_id).cloned())
+}
+
+/// Capture line to be opened by the playback loop, the result is reported through tx_state
+pub struct DuplexCapture {
+    sync: CaptSyncData,
+    device_id: String,
+    validbits: usize,
+    frame_bytes: usize,
+    channels: usize,
+    gain: GainStage,
+    pos: Arc<DuplexPosition>,
+    log_ctx: Arc<StreamLogContext>,
+    tx_state: Sender<DeviceState>,
+}
+
+impl DuplexCapture {
+    pub fn new(sync: CaptSyncData, device_id: String, validbits: usize, frame_bytes: usize, channels: usize, gain: GainStage,
+               pos: Arc<DuplexPosition>, log_ctx: Arc<StreamLogContext>, tx_state: Sender<DeviceState>) -> Self {
+        DuplexCapture { sync, device_id, validbits, frame_bytes, channels, gain, pos, log_ctx, tx_state }
+    }
+}
+
+/// Capture device opened by the playback loop
+struct DuplexStream {
+    sync: CaptSyncData,
+    frame_bytes: usize,
+    gain: GainStage,
+    pos: Arc<DuplexPosition>,
+    log_ctx: Arc<StreamLogContext>,
+    _device: Device,
+    audio_client: StreamClient,
+    capture_client: CaptureClient,
+    // None for the polling wait mode
+    handle: Option<EventHandle>,
+    // created on the first poll
+    timer: Option<PollTimer>,
+    _session: SessionRegistration,
+    running: bool,
+    // delivering the captured chunks to the line
+    active: bool,
+    // delivering until faded out, then stopping
+    fade: FadeStop,
+    flush_confirmed: bool,
+    // shared counter value of the next captured frame
+    frames: u64,
+    chunk_nbr: u64,
+    // frames queued for the line incl. the dropped ones
+    queued_frames: u64,
+    saved_buffer: Option<Vec<u8>>,
+    // read target while the line takes no data
+    discard: Vec<u8>,
+}
+
+impl DuplexStream {
+    /// Returns false when the line was closed
+    fn handle_signals(&mut self) -> bool {
+        let sync = &self.sync;
+        if sync.exit_signal.swap(false, Ordering::Relaxed) {
+            return false;
+        }
+        if sync.start_signal.swap(false, Ordering::Relaxed) {
+            debug!("DUPLEX: capture line started");
+            self.fade.start(&mut self.gain, self.active);
+            self.active = true;
+        }
+        if self.fade.stop_requested(&self.sync.stop_signal, &mut self.gain, self.active) {
+            debug!("DUPLEX: capture line stopped, the capture stream runs with the playback stream");
+            self.active = false;
+        }
+        if self.sync.flush_signal.load(Ordering::Relaxed) {
+            if !self.flush_confirmed {
+                // the outer side clears the queue and restarts the line
+                self.sync.tx_flushed.try_send(self.active).unwrap_or(());
+                self.flush_confirmed = true;
+                self.active = false;
+                self.fade.cancel();
+            }
+        } else {
+            self.flush_confirmed = false;
+        }
+        true
+    }
+
+    /// Waits for the capture event, or polls the buffer padding without event callbacks.
+    /// Returns false on timeout.
+    fn wait_for_period(&mut self, chunk_frames: usize, wait_ms: u32) -> Res<bool> {
+        if let Some(handle) = self.handle.as_ref() {
+            return Ok(handle.wait_for_event(wait_ms).is_ok());
+        }
+        let timer = self.timer.get_or_insert_with(|| PollTimer::new("DUPLEX"));
+        let timeout = Duration::from_millis(wait_ms as u64);
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            // polling four times within the wait
+            timer.sleep(timeout / 4);
+            if self.audio_client.get_current_padding()? as usize >= chunk_frames {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn read_chunk(&mut self, chunk_frames: usize) -> Res<()> {
+        let chunk_bytes = chunk_frames * self.frame_bytes;
+        let taking = self.active && !self.sync.flush_signal.load(Ordering::Relaxed);
+        // empty buffers are received from the outer side, never blocking the loop
+        let buffer = if taking {
+            self.saved_buffer.take().or_else(|| self.sync.rx_prealloc.try_recv().ok())
+        } else {
+            None
+        };
+        let frames_read = match buffer {
+            Some(mut data) => {
+                if data.len() != chunk_bytes {
+                    data.resize(chunk_bytes, 0);
+                }
+                let (frames_read, flags) = self.capture_client.read_from_device(self.frame_bytes, &mut data[0..chunk_bytes])?;
+                handle_capt_flags(&self.sync, &flags, &mut data[0..chunk_bytes]);
+                self.gain.process(&mut data[0..chunk_bytes]);
+                if self.fade.processed(&self.gain, true) {
+                    debug!("DUPLEX: capture line faded out and stopped");
+                    self.active = false;
+                    self.fade.cancel();
+                }
+                let (_, dropped_frames, _) = self.sync.overruns.snapshot();
+                self.pos.update(self.queued_frames.saturating_sub(dropped_frames), self.frames);
+                self.saved_buffer = send_capt_chunk(&self.sync, self.chunk_nbr, data, frames_read as usize, self.frame_bytes)?;
+                LineStats::inc(&self.sync.stats.chunks);
+                frames_read
+            }
+            None => {
+                // nothing to fade without a free chunk
+                if self.fade.processed(&self.gain, false) {
+                    self.active = false;
+                    self.fade.cancel();
+                }
+                // the counter keeps running while the line takes no data
+                let (frames_read, _flags) = self.capture_client.read_from_device(self.frame_bytes, &mut self.discard[0..chunk_bytes])?;
+                if taking {
+                    log_event!(LogEvent::Overrun, Level::Debug, "DUPLEX: no free chunk, dropping the captured chunk {}", self.chunk_nbr);
+                    self.sync.overruns.record(frames_read as usize);
+                }
+                frames_read
+            }
+        };
+        if taking {
+            self.chunk_nbr += 1;
+            self.queued_frames += frames_read as u64;
+        }
+        self.frames += frames_read as u64;
+        Ok(())
+    }
+}
+
+/// Owned by the playback loop of the exclusive render device
+pub struct DuplexBus {
+    rx_attach: Receiver<DuplexCapture>,
+    capture: Option<DuplexStream>,
+    rate: usize,
+    period_ns00: i64,
+    chunk_frames: usize,
+    // shared counter value of the next rendered frame
+    render_frames: u64,
+    // frames of the playing line written to the device
+    line_frames: u64,
+    pos: Arc<DuplexPosition>,
+    capture_attached: Arc<AtomicBool>,
+}
+
+impl DuplexBus {
+    pub fn new(rx_attach: Receiver<DuplexCapture>, rate: usize, period_ns00: i64, chunk_frames: usize,
+               pos: Arc<DuplexPosition>, capture_attached: Arc<AtomicBool>) -> Self {
+        DuplexBus {
+            rx_attach,
+            capture: None,
+            rate,
+            period_ns00,
+            chunk_frames,
+            render_frames: 0,
+            line_frames: 0,
+            pos,
+            capture_attached,
+        }
+    }
+
+    /// Opens a newly attached capture line and handles the signals of the attached one.
+    /// Returns true if a capture line was attached, a running render stream must be restarted together with it.
+    pub fn service(&mut self) -> bool {
+        let mut attached = false;
+        while let Ok(request) = self.rx_attach.try_recv() {
+            let _ctx_guard = enter_stream_context(&request.log_ctx);
+            attached |= self.attach(request);
+        }
+        let closed = match self.capture.as_mut() {
+            Some(stream) => !stream.handle_signals(),
+            None => false,
+        };
+        if closed {
+            self.detach();
+        }
+        attached
+    }
+
+    /// Returns false if the capture device could not be opened, the error is reported to the opening side
+    fn attach(&mut self, request: DuplexCapture) -> bool {
+        let DuplexCapture { sync, device_id, validbits, frame_bytes, channels, gain, pos, log_ctx, tx_state } = request;
+        let (device, audio_client, handle, capture_client, session) =
+            match self.open_capture(&device_id, validbits, frame_bytes, channels, &sync, &log_ctx) {
+                Ok(opened) => opened,
+                Err(err) => {
+                    error!("DUPLEX: opening the capture device failed: {}", err);
+                    self.capture_attached.store(false, Ordering::Relaxed);
+                    tx_state.send(DeviceState::Error(err)).unwrap_or(());
+                    return false;
+                }
+            };
+        match stream_latency_frames(&audio_client, self.rate) {
+            Ok(frames) => {
+                debug!("DUPLEX: capture stream latency {} frames", frames);
+                pos.set_latency(frames);
+            }
+            Err(err) => warn!("DUPLEX: reading the capture stream latency failed: {}", err),
+        }
+        debug!("DUPLEX: capture device {} attached to the playback stream, {} frames per period", device_id, self.chunk_frames);
+        self.capture = Some(DuplexStream {
+            sync,
+            frame_bytes,
+            gain,
+            pos,
+            log_ctx,
+            _device: device,
+            audio_client,
+            capture_client,
+            handle,
+            timer: None,
+            _session: session,
+            running: false,
+            active: false,
+            fade: FadeStop::default(),
+            flush_confirmed: false,
+            frames: 0,
+            chunk_nbr: 0,
+            queued_frames: 0,
+            saved_buffer: None,
+            discard: vec![0u8; self.chunk_frames * frame_bytes],
+        });
+        tx_state.send(DeviceState::Ok(self.chunk_frames)).unwrap_or(());
+        true
+    }
+
+    fn open_capture(&self, device_id: &str, validbits: usize, frame_bytes: usize, channels: usize,
+                    sync: &CaptSyncData, log_ctx: &Arc<StreamLogContext>)
+                    -> Res<(Device, StreamClient, Option<EventHandle>, CaptureClient, SessionRegistration)> {
+        // only valid modes are stored by do_set_wait_mode
+        let wait_mode = WaitMode::try_from(sync.wait_mode.load(Ordering::Relaxed)).unwrap_or(WaitMode::Auto);
+        let (device, audio_client, handle, _converter, capture_frames) =
+            device_open(device_id, &Direction::Capture, self.rate, validbits, frame_bytes, channels, self.period_ns00, wait_mode)?;
+        if capture_frames != self.chunk_frames {
+            let msg = format!("Duplex capture device uses periods of {} frames, the playback device {}",
+                              capture_frames, self.chunk_frames);
+            return Err(NativeError::new(ErrorKind::UnsupportedFormat, &msg));
+        }
+        audio_client.stop_stream()?;
+        let capture_client = audio_client.get_audiocaptureclient()?;
+
+        // the callback is called from a COM thread
+        let tx_cb = sync.tx_cb.clone();
+        let log_ctx = log_ctx.clone();
+        let session = audio_client.on_disconnected(move |reason| {
+            let _ctx_guard = enter_stream_context(&log_ctx);
+            log_event!(LogEvent::Disconnect, Level::Warn, "DUPLEX: capture device disconnected, reason: {:?}", reason);
+            tx_cb.send(Disconnected::from_reason(reason)).unwrap_or(());
+        })?;
+        Ok((device, audio_client, handle, capture_client, session))
+    }
+
+    fn detach(&mut self) {
+        if let Some(stream) = self.capture.take() {
+            let _ctx_guard = enter_stream_context(&stream.log_ctx);
+            debug!("DUPLEX: capture line closed, releasing the capture device");
+            if let Err(err) = stream.audio_client.stop_stream() {
+                warn!("DUPLEX: stopping the capture stream failed: {}", err);
+            }
+        }
+        self.capture_attached.store(false, Ordering::Relaxed);
+    }
+
+    /// Starts the render stream together with the capture stream, aligning the shared counter of both
+    pub fn start(&mut self, render_client: &StreamClient) -> Res<()> {
+        self.pos.set_latency(stream_latency_frames(render_client, self.rate)?);
+        let stream = match self.capture.as_mut() {
+            Some(stream) => stream,
+            None => {
+                render_client.start_stream()?;
+                return Ok(());
+            }
+        };
+        // frames left in the render buffer are played first
+        let padding = render_client.get_current_padding()? as u64;
+        let base = cmp::max(stream.frames, self.render_frames.saturating_sub(padding));
+        self.render_frames = base + padding;
+        stream.frames = base;
+        // frames captured before the stop do not belong to the new start
+        stream.audio_client.reset_stream()?;
+        render_client.start_stream()?;
+        stream.audio_client.start_stream()?;
+        stream.running = true;
+        trace!("DUPLEX: streams started at counter {}", base);
+        Ok(())
+    }
+
+    pub fn stop(&mut self, render_client: &StreamClient) -> Res<()> {
+        render_client.stop_stream()?;
+        if let Some(stream) = self.capture.as_mut() {
+            if stream.running {
+                stream.audio_client.stop_stream()?;
+                stream.running = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts the chunk written to the render device, from_line: chunk of the playing line, not a fill
+    pub fn rendered(&mut self, frames: usize, from_line: bool) {
+        if from_line {
+            self.pos.update(self.line_frames, self.render_frames);
+            self.line_frames += frames as u64;
+        }
+        self.render_frames += frames as u64;
+    }
+
+    /// Reads the capture periods completed so far, called after each render event
+    pub fn capture(&mut self) -> Res<()> {
+        let chunk_frames = self.chunk_frames;
+        let wait_ms = cmp::max(1, self.period_ns00 / 10_000 / CAPTURE_WAIT_DIVISOR) as u32;
+        let stream = match self.capture.as_mut() {
+            Some(stream) if stream.running => stream,
+            _ => return Ok(()),
+        };
+        if (stream.audio_client.get_current_padding()? as usize) < chunk_frames {
+            // the capture period ending with the render event can be signalled a moment later,
+            // if not within the wait it is read after the next render event
+            if !stream.wait_for_period(chunk_frames, wait_ms)? {
+                trace!("DUPLEX: no capture data within {} ms, reading with the next period", wait_ms);
+            }
+        }
+        while stream.audio_client.get_current_padding()? as usize >= chunk_frames {
+            stream.read_chunk(chunk_frames)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DuplexBus {
+    fn drop(&mut self) {
+        // the capture line cannot capture anymore, pending attachments fail on the dropped tx_state
+        self.detach();
+    }
+}
@@ -0,0 +1,194 @@
+use std::{error, fmt, io};
+use std::num::ParseIntError;
+use std::sync::PoisonError;
+
+use crossbeam_channel::{RecvError, RecvTimeoutError, SendError, SendTimeoutError};
+
+// WASAPI HRESULTs with a dedicated error kind, see https://learn.microsoft.com/en-us/windows/win32/coreaudio/audclnt-e-constants
+const AUDCLNT_E_DEVICE_INVALIDATED: i32 = 0x88890004u32 as i32;
+const AUDCLNT_E_UNSUPPORTED_FORMAT: i32 = 0x88890008u32 as i32;
+const AUDCLNT_E_DEVICE_IN_USE: i32 = 0x8889000Au32 as i32;
+const AUDCLNT_E_EXCLUSIVE_MODE_NOT_ALLOWED: i32 = 0x8889000Eu32 as i32;
+const AUDCLNT_E_RESOURCES_INVALIDATED: i32 = 0x88890026u32 as i32;
+// HRESULT_FROM_WIN32(ERROR_NOT_FOUND), returned for removed endpoints
+const E_NOTFOUND: i32 = 0x80070490u32 as i32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    // format not supported by the device in exclusive mode
+    UnsupportedFormat,
+    // device used by another application
+    DeviceBusy,
+    // device removed or invalidated
+    DeviceGone,
+    // other WASAPI/COM failure
+    Device,
+    // wrong parameter from java
+    IllegalArgument,
+    // operation not possible in the current line state (closed, wrong direction, stale handle)
+    IllegalState,
+    // inner thread or device did not respond in time
+    Timeout,
+    // failure in the library itself (poisoned lock, broken channel)
+    Internal,
+}
+
+#[derive(Debug)]
+pub struct NativeError {
+    pub kind: ErrorKind,
+    pub desc: String,
+    pub hresult: Option<i32>,
+    pub device: Option<String>,
+}
+
+pub type Res<T> = Result<T, NativeError>;
+
+impl NativeError {
+    pub fn new(kind: ErrorKind, desc: &str) -> Self {
+        NativeError {
+            kind,
+            desc: desc.to_owned(),
+            hresult: None,
+            device: None,
+        }
+    }
+
+    pub fn illegal_argument(desc: &str) -> Self {
+        NativeError::new(ErrorKind::IllegalArgument, desc)
+    }
+
+    pub fn illegal_state(desc: &str) -> Self {
+        NativeError::new(ErrorKind::IllegalState, desc)
+    }
+
+    pub fn timeout(desc: &str) -> Self {
+        NativeError::new(ErrorKind::Timeout, desc)
+    }
+
+    pub fn device(desc: &str) -> Self {
+        NativeError::new(ErrorKind::Device, desc)
+    }
+
+    pub fn internal(desc: &str) -> Self {
+        NativeError::new(ErrorKind::Internal, desc)
+    }
+
+    /// Adds the device name unless already known
+    pub fn with_device(mut self, device_name: &str) -> Self {
+        if self.device.is_none() {
+            self.device = Some(device_name.to_owned());
+        }
+        self
+    }
+
+    fn from_hresult(hresult: i32, desc: String) -> Self {
+        let kind = match hresult {
+            AUDCLNT_E_UNSUPPORTED_FORMAT => ErrorKind::UnsupportedFormat,
+            AUDCLNT_E_DEVICE_IN_USE | AUDCLNT_E_EXCLUSIVE_MODE_NOT_ALLOWED => ErrorKind::DeviceBusy,
+            AUDCLNT_E_DEVICE_INVALIDATED | AUDCLNT_E_RESOURCES_INVALIDATED | E_NOTFOUND => ErrorKind::DeviceGone,
+            _ => ErrorKind::Device,
+        };
+        NativeError {
+            kind,
+            desc,
+            hresult: Some(hresult),
+            device: None,
+        }
+    }
+}
+
+/// Adding the device name to errors of calls on the device
+pub trait DeviceContext<T> {
+    fn device_ctx(self, device_name: &str) -> Res<T>;
+}
+
+impl<T, E: Into<NativeError>> DeviceContext<T> for Result<T, E> {
+    fn device_ctx(self, device_name: &str) -> Res<T> {
+        self.map_err(|err| err.into().with_device(device_name))
+    }
+}
+
+impl fmt::Display for NativeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.desc)?;
+        if let Some(device) = &self.device {
+            write!(f, ", device: {}", device)?;
+        }
+        if let Some(hresult) = self.hresult {
+            write!(f, ", HRESULT: {:#010X}", hresult)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for NativeError {}
+
+impl From<windows::core::Error> for NativeError {
+    fn from(err: windows::core::Error) -> Self {
+        NativeError::from_hresult(err.code().0, err.to_string())
+    }
+}
+
+// wasapi-rs returns boxed errors, mostly wrapping windows::core::Error
+impl From<Box<dyn error::Error>> for NativeError {
+    fn from(err: Box<dyn error::Error>) -> Self {
+        match err.downcast::<windows::core::Error>() {
+            Ok(win_err) => NativeError::from(*win_err),
+            Err(err) => NativeError::device(&err.to_string()),
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for NativeError {
+    fn from(err: PoisonError<T>) -> Self {
+        NativeError::internal(&err.to_string())
+    }
+}
+
+impl<T> From<SendError<T>> for NativeError {
+    fn from(err: SendError<T>) -> Self {
+        NativeError::internal(&err.to_string())
+    }
+}
+
+impl<T> From<SendTimeoutError<T>> for NativeError {
+    fn from(err: SendTimeoutError<T>) -> Self {
+        match err {
+            SendTimeoutError::Timeout(_) => NativeError::timeout(&err.to_string()),
+            SendTimeoutError::Disconnected(_) => NativeError::internal(&err.to_string()),
+        }
+    }
+}
+
+impl From<RecvError> for NativeError {
+    fn from(err: RecvError) -> Self {
+        NativeError::internal(&err.to_string())
+    }
+}
+
+impl From<RecvTimeoutError> for NativeError {
+    fn from(err: RecvTimeoutError) -> Self {
+        match err {
+            RecvTimeoutError::Timeout => NativeError::timeout(&err.to_string()),
+            RecvTimeoutError::Disconnected => NativeError::internal(&err.to_string()),
+        }
+    }
+}
+
+impl From<ParseIntError> for NativeError {
+    fn from(err: ParseIntError) -> Self {
+        NativeError::illegal_argument(&err.to_string())
+    }
+}
+
+impl From<jni::errors::Error> for NativeError {
+    fn from(err: jni::errors::Error) -> Self {
+        NativeError::internal(&err.to_string())
+    }
+}
+
+impl From<io::Error> for NativeError {
+    fn from(err: io::Error) -> Self {
+        NativeError::internal(&err.to_string())
+    }
+}
@@ -2,7 +2,7 @@ use lazy_static::lazy_static;
 use wasapi::{SampleType, WaveFormat};
 use std::collections::HashMap;
 use std::sync::Mutex;
-use crate::{Res};
+use crate::error::Res;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Format {
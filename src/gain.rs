@@ -0,0 +1,389 @@
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use log::debug;
+
+use crate::samples::{add_samples, Limiter, truncate_to_valid_bits, write_samples};
+
+// Software gain of a line, applied by the loop processing the line chunks: playback chunks before mixing and
+// conversion to the device format, capture chunks right after reading the device. The line data are always integer,
+// in shared mode the gain is applied before the conversion to the engine float format.
+// The mix gain of a playback line mixed with other lines multiplies the gain, both ramp together.
+// Gain changes ramp linearly over the configured time. At exactly 0 dB with no ramp running and the limiter
+// released the chunks pass untouched (bit-perfect).
+// Optional fades on start/stop multiply the gain: the loops fade in the first frames after a start and keep
+// the line running for the fade-out before a stop. No frames are inserted or dropped, the byte position
+// accounts for the faded frames like for any other.
+
+// ramp time of gain changes until configured
+pub const DEFAULT_RAMP_MS: u32 = 20;
+// max. software gain
+pub const MAX_GAIN_DB: f32 = 24.0;
+
+fn load_gain(gain: &AtomicU32) -> f64 {
+    f32::from_bits(gain.load(Ordering::Relaxed)) as f64
+}
+
+/// Gain settings of the line, set by the outer side
+pub struct LineGain {
+    // f32 bits of the linear target gain
+    gain: AtomicU32,
+    // f32 bits of the linear gain when mixed with other lines
+    mix_gain: AtomicU32,
+    ramp_ms: AtomicU32,
+    limiter: AtomicBool,
+    // 0 = starting/stopping without fades
+    fade_ms: AtomicU32,
+}
+
+impl Default for LineGain {
+    fn default() -> Self {
+        LineGain {
+            gain: AtomicU32::new(1.0f32.to_bits()),
+            mix_gain: AtomicU32::new(1.0f32.to_bits()),
+            ramp_ms: AtomicU32::new(DEFAULT_RAMP_MS),
+            limiter: AtomicBool::new(false),
+            fade_ms: AtomicU32::new(0),
+        }
+    }
+}
+
+impl LineGain {
+    /// gain_db: -inf = silence, ramp_ms: duration of the change, 0 = next chunk
+    pub fn set(&self, gain_db: f32, ramp_ms: u32) {
+        // the ramp time must be in place when the loop notices the new gain
+        self.ramp_ms.store(ramp_ms, Ordering::Relaxed);
+        self.gain.store(10f32.powf(gain_db / 20.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Linear gain, ramped over the ramp time of the last set()
+    pub fn set_mix_gain(&self, gain: f32) {
+        self.mix_gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    fn target(&self) -> f64 {
+        load_gain(&self.gain) * load_gain(&self.mix_gain)
+    }
+
+    pub fn set_limiter(&self, enabled: bool) {
+        self.limiter.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn set_fade(&self, fade_ms: u32) {
+        self.fade_ms.store(fade_ms, Ordering::Relaxed);
+    }
+}
+
+/// Owned by the loop processing the line chunks
+pub struct GainStage {
+    settings: Arc<LineGain>,
+    rate: usize,
+    sample_bytes: usize,
+    channels: usize,
+    valid_bits: usize,
+    // gain applied to the last processed frame
+    gain: f64,
+    ramp_target: f64,
+    // gain change per frame and frames left of the running ramp
+    ramp_step: f64,
+    ramp_frames: usize,
+    // start/stop fade multiplier, ramped like the gain
+    fade: f64,
+    fade_target: f64,
+    fade_step: f64,
+    fade_frames: usize,
+    limiter: Limiter,
+    acc: Vec<f64>,
+}
+
+impl GainStage {
+    pub fn new(settings: Arc<LineGain>, rate: usize, sample_bytes: usize, channels: usize, valid_bits: usize) -> Self {
+        GainStage {
+            settings,
+            rate,
+            sample_bytes,
+            channels,
+            valid_bits,
+            gain: 1.0,
+            ramp_target: 1.0,
+            ramp_step: 0.0,
+            ramp_frames: 0,
+            fade: 1.0,
+            fade_target: 1.0,
+            fade_step: 0.0,
+            fade_frames: 0,
+            limiter: Limiter::new(),
+            acc: Vec::new(),
+        }
+    }
+
+    fn configured_fade_frames(&self) -> usize {
+        self.settings.fade_ms.load(Ordering::Relaxed) as usize * self.rate / 1000
+    }
+
+    fn start_fade(&mut self, target: f64, frames: usize) {
+        self.fade_step = (target - self.fade) / frames as f64;
+        self.fade_frames = frames;
+        self.fade_target = target;
+    }
+
+    /// Fades in the next processed frames: from silence after a stop, from the current level during a fade-out
+    pub fn fade_in(&mut self) {
+        let frames = self.configured_fade_frames();
+        if frames == 0 {
+            self.fade = 1.0;
+            self.fade_frames = 0;
+            return;
+        }
+        if self.fade_frames == 0 {
+            self.fade = 0.0;
+        }
+        debug!("GAIN: fading in over {} frames", frames);
+        self.start_fade(1.0, frames);
+    }
+
+    /// Starts fading out the next processed frames, returns false if fades are disabled
+    pub fn fade_out(&mut self) -> bool {
+        let frames = self.configured_fade_frames();
+        if frames == 0 {
+            return false;
+        }
+        debug!("GAIN: fading out over {} frames", frames);
+        self.start_fade(0.0, frames);
+        true
+    }
+
+    /// The fade-out has finished, the following frames are silent
+    pub fn faded_out(&self) -> bool {
+        self.fade == 0.0 && self.fade_frames == 0
+    }
+
+    /// Applies the gain to the chunk in place
+    pub fn process(&mut self, data: &mut [u8]) {
+        let target = self.settings.target();
+        if target != self.ramp_target {
+            let ramp_ms = self.settings.ramp_ms.load(Ordering::Relaxed) as usize;
+            let frames = cmp::max(1, ramp_ms * self.rate / 1000);
+            debug!("GAIN: ramping gain {:.3} -> {:.3} over {} frames", self.gain, target, frames);
+            self.ramp_step = (target - self.gain) / frames as f64;
+            self.ramp_frames = frames;
+            self.ramp_target = target;
+        }
+        let limiting = self.settings.limiter.load(Ordering::Relaxed);
+        if self.ramp_frames == 0 && self.gain == 1.0 && self.fade_frames == 0 && self.fade == 1.0 && self.limiter.gain() == 1.0 {
+            // unity gain cannot exceed full scale, the limiter has nothing to do
+            return;
+        }
+        let frame_bytes = self.sample_bytes * self.channels;
+        self.acc.clear();
+        self.acc.resize(data.len() / frame_bytes * self.channels, 0.0);
+        add_samples(&mut self.acc, data, self.sample_bytes, 1.0);
+        for frame in self.acc.chunks_exact_mut(self.channels) {
+            if self.ramp_frames > 0 {
+                self.ramp_frames -= 1;
+                // no rounding errors left at the end of the ramp
+                self.gain = if self.ramp_frames == 0 { self.ramp_target } else { self.gain + self.ramp_step };
+            }
+            if self.fade_frames > 0 {
+                self.fade_frames -= 1;
+                self.fade = if self.fade_frames == 0 { self.fade_target } else { self.fade + self.fade_step };
+            }
+            let gain = self.gain * self.fade;
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+        }
+        let (start_gain, end_gain) = if limiting {
+            let prev_gain = self.limiter.gain();
+            let gains = self.limiter.next_gains(&self.acc);
+            if gains.0 < 1.0 && prev_gain == 1.0 {
+                debug!("GAIN: line exceeds full scale, limiting by {:.1} dB", 20.0 * gains.0.log10());
+            }
+            gains
+        } else {
+            // samples over full scale are clipped
+            self.limiter = Limiter::new();
+            (1.0, 1.0)
+        };
+        write_samples(&self.acc, data, self.sample_bytes, self.channels, start_gain, end_gain);
+        truncate_to_valid_bits(data, self.sample_bytes, self.valid_bits);
+    }
+}
+
+/// Start/stop state of a line with fades, used together with its GainStage: a stop request fades out first,
+/// the line stops once faded out or out of data
+#[derive(Default)]
+pub struct FadeStop {
+    // playing until faded out
+    fading_out: bool,
+    // the fade-out finished, the line must stop
+    faded: bool,
+}
+
+impl FadeStop {
+    /// Start request of a line, fades in unless already playing at full level
+    pub fn start(&mut self, gain: &mut GainStage, running: bool) {
+        if !running {
+            gain.fade_in();
+        } else if self.fading_out || self.faded {
+            debug!("GAIN: started during the fade-out, fading in again");
+            gain.fade_in();
+        }
+        self.cancel();
+    }
+
+    /// Checks the stop signal, returns true if the line must stop now: without fades or when not running right away,
+    /// otherwise after the fade-out started by the signal finished
+    pub fn stop_requested(&mut self, stop_signal: &AtomicBool, gain: &mut GainStage, running: bool) -> bool {
+        if self.faded {
+            stop_signal.store(false, Ordering::Relaxed);
+            self.cancel();
+            return true;
+        }
+        if !stop_signal.swap(false, Ordering::Relaxed) {
+            return false;
+        }
+        if running && (self.fading_out || gain.fade_out()) {
+            // the line keeps playing until faded out
+            self.fading_out = true;
+            return false;
+        }
+        self.cancel();
+        true
+    }
+
+    /// Called after processing a chunk of the line (has_data = false if there was none),
+    /// returns true once the fade-out finished and the line must stop
+    pub fn processed(&mut self, gain: &GainStage, has_data: bool) -> bool {
+        if self.fading_out && (!has_data || gain.faded_out()) {
+            debug!("GAIN: faded out, stopping");
+            self.fading_out = false;
+            self.faded = true;
+        }
+        self.faded
+    }
+
+    /// The line stopped or was flushed, no fade-out pending
+    pub fn cancel(&mut self) {
+        self.fading_out = false;
+        self.faded = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: usize = 48000;
+
+    // pseudo-random samples of the container, the bits below valid_bits cleared
+    fn samples(sample_bytes: usize, valid_bits: usize, frames: usize, channels: usize) -> Vec<u8> {
+        let mut data = vec![0u8; frames * channels * sample_bytes];
+        let mut seed = 0x1234_5678u32;
+        for byte in data.iter_mut() {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            *byte = (seed >> 16) as u8;
+        }
+        truncate_to_valid_bits(&mut data, sample_bytes, valid_bits);
+        data
+    }
+
+    #[test]
+    fn unity_gain_is_bit_perfect() {
+        for (sample_bytes, valid_bits) in [(2, 16), (3, 24), (4, 24), (4, 32)] {
+            let mut stage = GainStage::new(Arc::new(LineGain::default()), RATE, sample_bytes, 2, valid_bits);
+            let original = samples(sample_bytes, valid_bits, 480, 2);
+            let mut data = original.clone();
+            stage.process(&mut data);
+            assert_eq!(data, original, "{} bytes, {} valid bits", sample_bytes, valid_bits);
+        }
+    }
+
+    #[test]
+    fn unity_gain_after_ramp_is_bit_perfect() {
+        for (sample_bytes, valid_bits) in [(2, 16), (3, 24), (4, 24), (4, 32)] {
+            let settings = Arc::new(LineGain::default());
+            let mut stage = GainStage::new(settings.clone(), RATE, sample_bytes, 2, valid_bits);
+            let original = samples(sample_bytes, valid_bits, 480, 2);
+            settings.set(-6.0, 5);
+            stage.process(&mut original.clone());
+            settings.set(0.0, 5);
+            // 5 ms ramp = 240 frames, ending within the chunk
+            stage.process(&mut original.clone());
+            let mut data = original.clone();
+            stage.process(&mut data);
+            assert_eq!(data, original, "{} bytes, {} valid bits", sample_bytes, valid_bits);
+        }
+    }
+
+    #[test]
+    fn ramp_ends_exactly_on_target() {
+        let settings = Arc::new(LineGain::default());
+        let mut stage = GainStage::new(settings.clone(), RATE, 2, 1, 16);
+        settings.set(-20.0, 1);
+        // 1 ms ramp = 48 frames
+        let mut data: Vec<u8> = [10000i16; 100].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        stage.process(&mut data);
+        let target = load_gain(&settings.gain);
+        assert_eq!(stage.gain, target);
+        assert_eq!(stage.ramp_frames, 0);
+        let expected = ((10000i32 << 16) as f64 * target) as i32 >> 16;
+        let last = i16::from_le_bytes([data[198], data[199]]);
+        assert_eq!(last as i32, expected);
+        // first frame one step below unity
+        let first = i16::from_le_bytes([data[0], data[1]]);
+        assert!(first < 10000 && first as i32 > expected);
+    }
+
+    #[test]
+    fn mix_gain_is_ramped() {
+        let settings = Arc::new(LineGain::default());
+        let mut stage = GainStage::new(settings.clone(), RATE, 2, 1, 16);
+        settings.set(0.0, 1);
+        settings.set_mix_gain(0.5);
+        // 1 ms ramp = 48 frames
+        let mut data: Vec<u8> = [10000i16; 100].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        stage.process(&mut data);
+        assert_eq!(stage.gain, 0.5);
+        let first = i16::from_le_bytes([data[0], data[1]]);
+        assert!(first > 5000 && first < 10000);
+        let last = i16::from_le_bytes([data[198], data[199]]);
+        assert_eq!(last, 5000);
+    }
+
+    #[test]
+    fn fade_out_ends_silent() {
+        let settings = Arc::new(LineGain::default());
+        settings.set_fade(1);
+        let mut stage = GainStage::new(settings, RATE, 2, 1, 16);
+        assert!(stage.fade_out());
+        let mut data: Vec<u8> = [10000i16; 100].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        stage.process(&mut data);
+        assert!(stage.faded_out());
+        assert!(data[96..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn stop_takes_effect_after_fade_out() {
+        let settings = Arc::new(LineGain::default());
+        let mut stage = GainStage::new(settings.clone(), RATE, 2, 1, 16);
+        let mut fade = FadeStop::default();
+        let stop_signal = AtomicBool::new(true);
+        // without fades right away
+        assert!(fade.stop_requested(&stop_signal, &mut stage, true));
+        assert!(!stop_signal.load(Ordering::Relaxed));
+
+        settings.set_fade(1);
+        stop_signal.store(true, Ordering::Relaxed);
+        assert!(!fade.stop_requested(&stop_signal, &mut stage, true));
+        let mut data = vec![0u8; 2 * 100];
+        assert!(!fade.processed(&stage, true));
+        stage.process(&mut data);
+        assert!(fade.processed(&stage, true));
+        assert!(fade.stop_requested(&stop_signal, &mut stage, true));
+        // a stopped line stops right away
+        stop_signal.store(true, Ordering::Relaxed);
+        assert!(fade.stop_requested(&stop_signal, &mut stage, false));
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex};
+
+use jni::sys::jlong;
+use lazy_static::lazy_static;
+use log::{Level, trace};
+
+use crate::error::{NativeError, Res};
+use crate::logging::{enter_stream_context, log_event, LogEvent};
+use crate::wasapi_impl::RuntimeData;
+
+// Registry of open lines. Java receives an opaque handle instead of a pointer:
+// lower 32 bits = slot index + 1 (0 is never a valid handle, SimpleDataLine.doOpen checks for 0),
+// upper 32 bits = slot generation, incremented on every release of the slot to detect stale handles.
+
+struct Slot {
+    generation: u32,
+    rtd: Option<Arc<RuntimeData>>,
+}
+
+lazy_static! {
+    static ref SLOTS: Mutex<Vec<Slot>> = Mutex::new(Vec::new());
+}
+
+fn to_handle(idx: usize, generation: u32) -> jlong {
+    ((generation as i64) << 32) | (idx as i64 + 1)
+}
+
+fn from_handle(handle: jlong) -> (Option<usize>, u32) {
+    let idx_part = (handle & 0xFFFF_FFFF) as usize;
+    let generation = (handle >> 32) as u32;
+    (idx_part.checked_sub(1), generation)
+}
+
+fn check_slot<'a>(slots: &'a mut Vec<Slot>, handle: jlong) -> Res<&'a mut Slot> {
+    let (idx, generation) = from_handle(handle);
+    let slot = match idx.and_then(|idx| slots.get_mut(idx)) {
+        Some(slot) => slot,
+        None => {
+            let msg = format!("Unknown line handle {:#x}", handle);
+            return Err(NativeError::illegal_state(&msg));
+        }
+    };
+    if slot.generation != generation {
+        let msg = format!("Stale line handle {:#x}, slot reused with generation {}", handle, slot.generation);
+        return Err(NativeError::illegal_state(&msg));
+    }
+    if slot.rtd.is_none() {
+        let msg = format!("Line handle {:#x} already closed", handle);
+        return Err(NativeError::illegal_state(&msg));
+    }
+    Ok(slot)
+}
+
+pub fn register_rtd(rtd: RuntimeData) -> Res<jlong> {
+    let mut slots = SLOTS.lock()?;
+    let idx = match slots.iter().position(|slot| slot.rtd.is_none()) {
+        Some(idx) => idx,
+        None => {
+            slots.push(Slot { generation: 1, rtd: None });
+            slots.len() - 1
+        }
+    };
+    let slot = &mut slots[idx];
+    let handle = to_handle(idx, slot.generation);
+    rtd.log_ctx.set_handle(handle);
+    let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+    log_event!(LogEvent::Open, Level::Info, "Opened device {}, line handle {:#x}", rtd.device_name, handle);
+    slot.rtd = Some(Arc::new(rtd));
+    Ok(handle)
+}
+
+// java -> rust
+// the line stays alive until the last concurrent call finishes, even if closed in the meantime
+pub fn get_rtd(handle: jlong) -> Res<Arc<RuntimeData>> {
+    let mut slots = SLOTS.lock()?;
+    let slot = check_slot(&mut slots, handle)?;
+    Ok(slot.rtd.as_ref().unwrap().clone())
+}
+
+/// Removes the line from the registry, the handle becomes stale
+pub fn unregister_rtd(handle: jlong) -> Res<Arc<RuntimeData>> {
+    let mut slots = SLOTS.lock()?;
+    let slot = check_slot(&mut slots, handle)?;
+    let rtd = slot.rtd.take().unwrap();
+    slot.generation = slot.generation.wrapping_add(1).max(1);
+    trace!("Unregistered line handle {:#x}", handle);
+    Ok(rtd)
+}
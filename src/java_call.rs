@@ -0,0 +1,61 @@
+use jni::{JavaVM, JNIEnv};
+use jni::objects::{GlobalRef, JClass, JStaticMethodID, JValue};
+use jni::signature::{JavaType, Primitive};
+
+use crate::error::Res;
+
+// Static void java methods called from native threads (log writer, volume watcher).
+// The threads attach to the JVM as daemon and never return to java: local refs are released explicitly
+// and a java exception must not stay pending after a failed call.
+
+pub struct StaticCallback {
+    vm: JavaVM,
+    class_ref: GlobalRef,
+    // resolved once, valid as long as the class is loaded (kept by class_ref)
+    method_id: JStaticMethodID<'static>,
+}
+
+// the method ID is not bound to the thread resolving it
+unsafe impl Send for StaticCallback {}
+
+impl StaticCallback {
+    /// class: the java class of the method, must be resolved in a java thread
+    /// (native threads see only the system class loader)
+    pub fn new(env: JNIEnv, class: JClass, name: &str, sig: &str) -> Res<Self> {
+        let vm = env.get_java_vm()?;
+        // also checks the method exists before the first call
+        let method_id = JStaticMethodID::from(env.get_static_method_id(class, name, sig)?.into_inner());
+        let class_ref = env.new_global_ref(class)?;
+        Ok(StaticCallback { vm, class_ref, method_id })
+    }
+
+    /// Attaches the calling thread to the JVM as daemon, no-op if already attached
+    pub fn attach(&self) -> jni::errors::Result<JNIEnv> {
+        self.vm.attach_current_thread_as_daemon()
+    }
+
+    /// Calls the method in the attached thread with the arguments created by make_args.
+    /// The object arguments are deleted after the call, a pending java exception is cleared on failure.
+    pub fn call<'a, const N: usize, F>(&self, env: JNIEnv<'a>, make_args: F) -> jni::errors::Result<()>
+        where F: FnOnce(JNIEnv<'a>) -> jni::errors::Result<[JValue<'a>; N]> {
+        let result = self.call_unchecked(env, make_args);
+        if result.is_err() && env.exception_check().unwrap_or(false) {
+            let _ = env.exception_clear();
+        }
+        result
+    }
+
+    fn call_unchecked<'a, const N: usize, F>(&self, env: JNIEnv<'a>, make_args: F) -> jni::errors::Result<()>
+        where F: FnOnce(JNIEnv<'a>) -> jni::errors::Result<[JValue<'a>; N]> {
+        let args = make_args(env)?;
+        // the signature was verified when resolving the method ID
+        let result = env.call_static_method_unchecked(JClass::from(self.class_ref.as_obj()), self.method_id,
+                                                      JavaType::Primitive(Primitive::Void), &args);
+        for arg in args {
+            if let JValue::Object(obj) = arg {
+                env.delete_local_ref(obj)?;
+            }
+        }
+        result.map(|_| ())
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+use jni::JNIEnv;
+use jni::objects::{JClass, JValue};
+use log::Level;
+
+use crate::error::Res;
+use crate::java_call::StaticCallback;
+use crate::logging::{LogRecordData, LogSink};
+
+// Log sink forwarding records to the java provider:
+// static void SimpleMixerProvider.nativeLog(int levelID, String module, String thread, String msg)
+// Called by the log writer thread, attached to the JVM as daemon.
+
+const LOG_METHOD: &str = "nativeLog";
+const LOG_METHOD_SIG: &str = "(ILjava/lang/String;Ljava/lang/String;Ljava/lang/String;)V";
+// failing nativeLog calls are echoed to stderr at most once per period, with their count
+const FAILURE_REPORT_PERIOD: Duration = Duration::from_secs(10);
+
+pub struct JvmSink {
+    callback: StaticCallback,
+    failures: FailureReport,
+}
+
+#[derive(Default)]
+struct FailureReport {
+    // failed records not reported yet
+    count: u64,
+    last_report: Option<Instant>,
+}
+
+impl FailureReport {
+    fn is_due(&self) -> bool {
+        self.last_report.map_or(true, |last| last.elapsed() >= FAILURE_REPORT_PERIOD)
+    }
+
+    fn failed(&mut self, record: &LogRecordData, err: jni::errors::Error) {
+        self.count += 1;
+        if self.is_due() {
+            eprintln!("Failed to forward {} native log records to JVM, last record '{}': {}", self.count, record.msg, err);
+            self.count = 0;
+            self.last_report = Some(Instant::now());
+        }
+    }
+
+    fn succeeded(&mut self) {
+        // failures since the last report, reported once the period has passed
+        if self.count > 0 && self.is_due() {
+            eprintln!("Failed to forward {} further native log records to JVM", self.count);
+            self.count = 0;
+            self.last_report = Some(Instant::now());
+        }
+    }
+}
+
+impl JvmSink {
+    /// provider_class: the java class receiving the records, must be resolved in a java thread
+    /// (native threads see only the system class loader)
+    pub fn new(env: JNIEnv, provider_class: JClass) -> Res<Self> {
+        let callback = StaticCallback::new(env, provider_class, LOG_METHOD, LOG_METHOD_SIG)?;
+        Ok(JvmSink { callback, failures: FailureReport::default() })
+    }
+}
+
+impl LogSink for JvmSink {
+    fn write_batch(&mut self, records: &[LogRecordData]) {
+        // no-op if the writer thread is already attached
+        let env = match self.callback.attach() {
+            Ok(env) => env,
+            Err(err) => {
+                eprintln!("Failed to attach log writer to JVM, dropping {} records: {}", records.len(), err);
+                return;
+            }
+        };
+        for record in records {
+            match forward_record(env, &self.callback, record) {
+                Ok(()) => self.failures.succeeded(),
+                Err(err) => self.failures.failed(record, err),
+            }
+        }
+    }
+}
+
+// same constants as in the java provider
+pub fn level_to_id(level: Level) -> i32 {
+    match level {
+        Level::Error => 0,
+        Level::Warn => 1,
+        Level::Info => 2,
+        Level::Debug => 3,
+        Level::Trace => 4,
+    }
+}
+
+fn forward_record(env: JNIEnv, callback: &StaticCallback, record: &LogRecordData) -> jni::errors::Result<()> {
+    callback.call(env, |env| Ok([
+        JValue::Int(level_to_id(record.level)),
+        JValue::from(env.new_string(&record.target)?),
+        JValue::from(env.new_string(&record.thread)?),
+        JValue::from(env.new_string(&record.msg)?),
+    ]))
+}
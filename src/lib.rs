@@ -4,35 +4,55 @@ extern crate core;
 
 use core::slice;
 use std::any::Any;
-use std::error::Error;
-use std::fs::File;
 use std::panic;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use ::function_name::named;
-use fast_log::appender::{Command, FastLogRecord, RecordFormat};
-use fast_log::Config;
 use jni::JNIEnv;
 use jni::objects::{AutoArray, AutoPrimitiveArray, JClass, JObject, JString, JValue, ReleaseMode};
 use jni::signature::TypeSignature;
-use jni::sys::{jboolean, jbyteArray, jint, jintArray, jlong, jobject};
+use jni::sys::{jboolean, jbyteArray, jdouble, jdoubleArray, jfloat, jfloatArray, jint, jintArray, jlong, jlongArray, jobject};
 use lazy_static::lazy_static;
-use log::{debug, error, info, LevelFilter, trace};
-use time::{format_description, OffsetDateTime};
+use log::{debug, error, info, trace};
 use wasapi::Direction;
 
 use wasapi_impl::*;
 
+use crate::bridge::{close_bridges, get_bridge_info, start_bridge, stop_bridge};
+use crate::error::{ErrorKind, NativeError};
 use crate::formats::init_format_variants;
+use crate::handles::{get_rtd, register_rtd, unregister_rtd};
+use crate::logging::{create_sink, enter_stream_context, init_logging, level_filter_from_id, LogFilters, set_filters, set_sink};
+use crate::mmcss::{MmcssPriority, MmcssTask, ThreadConfig};
+use crate::volume::{get_endpoint_volume, set_endpoint_channel_volume, set_endpoint_mute, set_endpoint_volume, VolumeNotifier,
+                    watch_endpoint_volume};
+use crate::waiter::WaitMode;
 
 mod wasapi_impl;
+mod bridge;
+mod client;
+mod drift;
+mod duplex;
+mod error;
 mod formats;
-
-pub type Res<T> = Result<T, Box<dyn Error>>;
+mod gain;
+mod samples;
+mod handles;
+mod java_call;
+mod jvm_log;
+mod log_file;
+mod logging;
+mod mixer;
+mod mmcss;
+mod timing;
+mod volume;
+mod waiter;
 
 pub struct MixerDesc {
     deviceID: String,
-    max_lines: usize,
+    // NOT_SPECIFIED = unlimited
+    max_lines: i32,
     name: String,
     description: String,
 }
@@ -41,88 +61,48 @@ const ADD_FORMAT_METHOD: &'static str = "addFormat";
 const ADD_FORMAT_SIGNATURE: &'static str = "(Ljava/util/Vector;IIIIIZZ)V";
 
 
-pub struct LogFormat {
-    pub display_line_level: LevelFilter,
-}
-
 lazy_static! {
-    static ref TIME_FORMAT: Vec<format_description::FormatItem<'static>>= format_description::parse("[hour]:[minute]:[second].[subsecond]").unwrap();
     static ref BACKTRACE: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
 }
 
-fn systemtime_strftime<T>(dt: T) -> String
-    where T: Into<OffsetDateTime> {
-    dt.into().format(&TIME_FORMAT).unwrap()
-}
-
-
-impl RecordFormat for LogFormat {
-    fn do_format(&self, arg: &mut FastLogRecord) -> String {
-        match &arg.command {
-            Command::CommandRecord => {
-                let data;
-                let time_str = systemtime_strftime(arg.now);
-                if arg.level.to_level_filter() >= self.display_line_level {
-                    data = format!(
-                        "{} {} [{}:{}] {}\n",
-                        time_str,
-                        arg.level,
-                        arg.file,
-                        arg.line.unwrap_or_default(),
-                        arg.args,
-                    );
-                } else {
-                    data = format!(
-                        "{} {} {} - {}\n",
-                        time_str, arg.level, arg.module_path, arg.args
-                    );
-                }
-                return data;
+// Looks up the line of the native handle, throws and returns the sentinel (nothing by default) from the enclosing
+// JNI closure if the handle is not valid. Used inside #[named] functions.
+macro_rules! rtd_or_throw {
+    ($env:expr, $native_ptr:expr) => {
+        rtd_or_throw!($env, $native_ptr, ())
+    };
+    ($env:expr, $native_ptr:expr, $sentinel:expr) => {
+        match get_rtd($native_ptr) {
+            Ok(rtd) => rtd,
+            Err(err) => {
+                throw_error($env, function_name!(), &err);
+                return $sentinel;
             }
-            Command::CommandExit => {}
-            Command::CommandFlush(_) => {}
         }
-        return String::new();
-    }
+    };
 }
 
 #[named]
 #[no_mangle]
 pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixerProvider_nInit
-(env: JNIEnv, _clazz: JClass, logLevelID: jint, logTarget: JString,
+(env: JNIEnv, clazz: JClass, logLevelID: jint, logTarget: JString,
  jrates: jintArray, jchannels: jintArray, maxRatesLimit: jint, maxChannelsLimit: jint) -> jboolean {
     panic::set_hook(Box::new(panic_hook));
     let panicResult = panic::catch_unwind(|| {
         // logging initialization
         let log_target_str = get_string(env, logTarget);
-        let log_level: LevelFilter = match logLevelID as usize {
-            // same constants as in the java provider
-            0 => LevelFilter::Error,
-            1 => LevelFilter::Warn,
-            2 => LevelFilter::Info,
-            3 => LevelFilter::Debug,
-            4 => LevelFilter::Trace,
-            _ => LevelFilter::Error,
-        };
-
-        let mut config = Config::new().level(log_level).format(LogFormat { display_line_level: LevelFilter::Off });
-
-        config = match log_target_str.as_str() {
-            "stdout" => { config.console() }
-            "stderr" => { /* for now now stderr support */ config.console() }
-            other => {
-                // checking if the log file can be created
-                let _file = match File::create(other) {
-                    Ok(file) => { file }
-                    Err(err) => {
-                        error!("{} [{}]: Failed to create log file {}: {}", function_name!(), get_thread_name(env), other, err);
-                        return 0 as jboolean;
-                    }
-                };
-                config.file(other)
+        let log_level = level_filter_from_id(logLevelID as usize);
+        let sink = match create_sink(env, clazz, &log_target_str) {
+            Ok(sink) => sink,
+            Err(err) => {
+                eprintln!("{} [{}]: Failed to create log target {}: {}", function_name!(), get_thread_name(env), log_target_str, err);
+                return 0 as jboolean;
             }
         };
-        fast_log::init(config).unwrap();
+        if let Err(err) = init_logging(sink, LogFilters::new(log_level)) {
+            eprintln!("{} [{}]: Failed to init logging: {}", function_name!(), get_thread_name(env), err);
+            return 0 as jboolean;
+        }
         // logging ready
 
         info!("Build Timestamp: {}", env!("VERGEN_BUILD_TIMESTAMP"));
@@ -164,6 +144,56 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixerProvider_nIn
 }
 
 
+/*
+JNIEXPORT jboolean JNICALL Java_com_cleansine_sound_provider_SimpleMixerProvider_nSetLogConfig
+(JNIEnv *env, jclass clazz, jint logLevelID, jstring logModuleLevels, jstring logTarget)
+*/
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixerProvider_nSetLogConfig
+(env: JNIEnv, clazz: JClass, logLevelID: jint, logModuleLevels: JString, logTarget: JString) -> jboolean {
+    let panicResult = panic::catch_unwind(|| {
+        trace!("{}", function_name!());
+        let log_level = level_filter_from_id(logLevelID as usize);
+        // null or empty = no module levels
+        let module_levels = if logModuleLevels.is_null() { String::new() } else { get_string(env, logModuleLevels) };
+        let filters = match LogFilters::parse(log_level, &module_levels) {
+            Ok(filters) => filters,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                return 0 as jboolean;
+            }
+        };
+        // null = keeping the current target
+        let log_target_str = if logTarget.is_null() { None } else { Some(get_string(env, logTarget)) };
+        // the whole configuration is checked before anything is applied
+        let sink = match log_target_str.as_ref().map(|target| create_sink(env, clazz, target)).transpose() {
+            Ok(sink) => sink,
+            Err(err) => {
+                let msg = format!("Failed to create log target {}: {}", log_target_str.unwrap_or_default(), err);
+                throw_error(env, function_name!(), &NativeError::illegal_argument(&msg));
+                return 0 as jboolean;
+            }
+        };
+        if let Err(err) = set_filters(filters) {
+            throw_error(env, function_name!(), &err);
+            return 0 as jboolean;
+        }
+        info!("Log level set to {}, module levels: '{}'", log_level, module_levels);
+        if let (Some(sink), Some(log_target_str)) = (sink, log_target_str) {
+            if let Err(err) = set_sink(sink) {
+                let msg = format!("Failed to switch log target to {}: {}", log_target_str, err);
+                throw_error(env, function_name!(), &NativeError::internal(&msg));
+                return 0 as jboolean;
+            }
+            info!("Log target switched to {}", log_target_str);
+        }
+        1 as jboolean
+    });
+    return check_panic_result(env, panicResult, 0 as jboolean);
+}
+
+
 /*
 JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetFormats
 (JNIEnv *env, jclass clazz, jstring deviceID, jboolean isSource, jobject formats)
@@ -174,7 +204,7 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetFormats
 (env: JNIEnv, clazz: JClass, deviceID: JString, isSource: jboolean, formatsVec: JObject) {
     let panicResult = panic::catch_unwind(|| {
         if let Err(err) = do_initialize_wasapi() {
-            error!("{} [{}]: WASAPI init failed: {}", function_name!(), get_thread_name(env), err);
+            throw_error(env, function_name!(), &err);
             return;
         }
         let deviceIDStr = get_string(env, deviceID);
@@ -182,7 +212,7 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetFormats
         let formats = match do_get_formats(deviceIDStr, &get_direction(isSource)) {
             Ok(formats) => formats,
             Err(err) => {
-                error!("{} [{}]: get_fmts failed: {:?}\n", function_name!(), get_thread_name(env), err);
+                throw_error(env, function_name!(), &err);
                 return;
             }
         };
@@ -231,7 +261,7 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nOpen
  _isSigned: jboolean, _isBigEndian: jboolean, bufferBytes: jint) -> jlong {
     let panicResult = panic::catch_unwind(|| {
         if let Err(err) = do_initialize_wasapi() {
-            error!("{} [{}]: WASAPI init failed: {}", function_name!(), get_thread_name(env), err);
+            throw_error(env, function_name!(), &err);
             return 0;
         }
         let direction = get_direction(isSource);
@@ -242,13 +272,19 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nOpen
                                                  channels as usize, bufferBytes as usize) {
             Ok(rtd) => rtd,
             Err(err) => {
-                error!("{} [{}]: open_dev failed: {:?}\n", function_name!(), get_thread_name(env), err);
+                throw_error(env, function_name!(), &err);
                 // SimpleDataLine.doOpen checks for 0 (= NULL)
                 return 0;
             }
         };
-        // getting the pointer
-        get_rtd_box_ptr(rtd)
+        // java gets an opaque handle
+        match register_rtd(rtd) {
+            Ok(handle) => handle,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                0
+            }
+        }
     });
     return check_panic_result(env, panicResult, 0);
 }
@@ -264,11 +300,12 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nStart
 (env: JNIEnv, _clazz: JClass, nativePtr: jlong, isSource: jboolean) {
     trace!("{}", function_name!());
     let panicResult = panic::catch_unwind(|| {
-        let rtd = get_rtd(nativePtr);
-        match do_start(rtd, &get_direction(isSource)) {
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        match do_start(&rtd, &get_direction(isSource)) {
             Ok(_) => {}
             Err(err) => {
-                error!("{} [{}]: start failed: {:?}\n", function_name!(), get_thread_name(env), err);
+                throw_error(env, function_name!(), &err);
             }
         }
     });
@@ -286,11 +323,12 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nStop
 (env: JNIEnv, _clazz: JClass, nativePtr: jlong, isSource: jboolean) {
     trace!("{}", function_name!());
     let panicResult = panic::catch_unwind(|| {
-        let rtd = get_rtd(nativePtr);
-        match do_stop(rtd, &get_direction(isSource)) {
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        match do_stop(&rtd, &get_direction(isSource)) {
             Ok(_) => {}
             Err(err) => {
-                error!("{} [{}]: stop failed: {:?}\n", function_name!(), get_thread_name(env), err);
+                throw_error(env, function_name!(), &err);
             }
         }
     });
@@ -308,15 +346,27 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nClose
 (env: JNIEnv, _clazz: JClass, nativePtr: jlong, isSource: jboolean) {
     trace!("{}", function_name!());
     let panicResult = panic::catch_unwind(|| {
-        // need to release the allocated memory => getting the box
-        let rtd = get_rtd_box(nativePtr);
-        match do_close(&rtd, &get_direction(isSource)) {
+        // bridges from/to the line stop before the line releases its data
+        if let Err(err) = close_bridges(nativePtr) {
+            throw_error(env, function_name!(), &err);
+            return;
+        }
+        // removing from the registry, further calls with this handle are rejected
+        let rtd = match unregister_rtd(nativePtr) {
+            Ok(rtd) => rtd,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                return;
+            }
+        };
+        // waits for the inner thread to release the device
+        match do_close(&rtd, &get_direction(isSource), CLOSE_TIMEOUT) {
             Ok(_) => {}
             Err(err) => {
-                error!("{} [{}]: closing failed: {:?}\n", function_name!(), get_thread_name(env), err);
+                throw_error(env, function_name!(), &err);
             }
         }
-        // freeing rtd from heap
+        // rtd is freed from heap once concurrent calls holding it finish
         drop(rtd);
     });
     check_panic_result(env, panicResult, ());
@@ -333,15 +383,19 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nWrite
 (env: JNIEnv, _clazz: JClass, nativePtr: jlong, jData: jbyteArray, offset: jint, len: jint) -> jint {
     trace!("{}", function_name!());
     let panicResult = panic::catch_unwind(|| {
-        let rtd = get_rtd(nativePtr);
+        let rtd = rtd_or_throw!(env, nativePtr, -1 as jint);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
         // warn - AutoPrimitiveArray disables GC in java until the array is dropped in rust
         let jarr: AutoPrimitiveArray = env.get_primitive_array_critical(jData, ReleaseMode::NoCopyBack).unwrap();
         let size = jarr.size().unwrap() as usize;
         let items: &[u8] = unsafe { slice::from_raw_parts(jarr.as_ptr() as *const u8, size) };
-        let cnt = match do_write(rtd, items, offset as usize, len as usize) {
+        let result = do_write(&rtd, items, offset as usize, len as usize);
+        // no JNI calls allowed within the critical region
+        drop(jarr);
+        let cnt = match result {
             Ok(cnt) => cnt,
             Err(e) => {
-                error!("{} [{}]: Writing failed: {:?}", function_name!(), get_thread_name(env), e);
+                throw_error(env, function_name!(), &e);
                 return -1 as jint;
             }
         };
@@ -361,14 +415,18 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nRead
 (env: JNIEnv, _clazz: JClass, nativePtr: jlong, jData: jbyteArray, offset: jint, len: jint) -> jint {
     trace!("{}", function_name!());
     let panicResult = panic::catch_unwind(|| {
-        let rtd = get_rtd(nativePtr);
+        let rtd = rtd_or_throw!(env, nativePtr, -1 as jint);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
         let jarr: AutoPrimitiveArray = env.get_primitive_array_critical(jData, ReleaseMode::CopyBack).unwrap();
         let size = jarr.size().unwrap() as usize;
         let items: &mut [u8] = unsafe { slice::from_raw_parts_mut(jarr.as_ptr() as *mut u8, size) };
-        let cnt = match do_read(rtd, items, offset as usize, len as usize) {
+        let result = do_read(&rtd, items, offset as usize, len as usize);
+        // no JNI calls allowed within the critical region
+        drop(jarr);
+        let cnt = match result {
             Ok(cnt) => cnt,
             Err(e) => {
-                error!("{} [{}]: Reading failed: {:?}", function_name!(), get_thread_name(env), e);
+                throw_error(env, function_name!(), &e);
                 return -1 as jint;
             }
         };
@@ -389,11 +447,12 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetBufferB
     let dir = get_direction(isSource);
     trace!("{} {}", function_name!(), dir);
     let panicResult = panic::catch_unwind(|| {
-        let rtd = get_rtd(nativePtr);
-        let bytes = match do_get_buffer_bytes(rtd, &dir) {
+        let rtd = rtd_or_throw!(env, nativePtr, 0 as jint);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        let bytes = match do_get_buffer_bytes(&rtd, &dir) {
             Ok(size) => size,
             Err(e) => {
-                error!("{} [{}]: Getting buffer_bytes failed: {:?}", function_name!(), get_thread_name(env),  e);
+                throw_error(env, function_name!(), &e);
                 return 0 as jint;
             }
         };
@@ -406,16 +465,25 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetBufferB
 
 /*
 JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nDrain
-    (JNIEnv* env, jclass clazz, jlong nativePtr)
+    (JNIEnv* env, jclass clazz, jlong nativePtr, jint timeoutMillis)
  */
+// timeoutMillis <= 0: timeout derived from the line buffer size
 #[named]
 #[no_mangle]
 pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nDrain
-(env: JNIEnv, _clazz: JClass, nativePtr: jlong) {
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong, timeoutMillis: jint) {
     trace!("{}", function_name!());
     let panicResult = panic::catch_unwind(|| {
-        let rtd = get_rtd(nativePtr);
-        do_drain(rtd);
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        let timeout = if timeoutMillis > 0 {
+            Duration::from_millis(timeoutMillis as u64)
+        } else {
+            get_default_drain_timeout(&rtd)
+        };
+        do_drain(&rtd, timeout).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
+        });
     });
     check_panic_result(env, panicResult, ());
 }
@@ -431,9 +499,10 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nFlush
 (env: JNIEnv, _clazz: JClass, nativePtr: jlong, _isSource: jboolean) {
     trace!("{}", function_name!());
     let panicResult = panic::catch_unwind(|| {
-        let rtd = get_rtd(nativePtr);
-        do_flush(rtd).unwrap_or_else(|err| {
-            error!("{} [{}]: failed: {:?}", function_name!(), get_thread_name(env),  err);
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        do_flush(&rtd).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
         });
     });
     check_panic_result(env, panicResult, ());
@@ -450,11 +519,12 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetAvailBy
     let dir = get_direction(isSource);
     trace!("{} {}", function_name!(), dir);
     let panicResult = panic::catch_unwind(|| {
-        let rtd = get_rtd(nativePtr);
-        let bytes = match do_get_avail_bytes(rtd, &dir) {
+        let rtd = rtd_or_throw!(env, nativePtr, 0 as jint);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        let bytes = match do_get_avail_bytes(&rtd, &dir) {
             Ok(size) => size,
             Err(e) => {
-                error!("{} [{}]: Getting avail_bytes failed: {:?}", function_name!(), get_thread_name(env),  e);
+                throw_error(env, function_name!(), &e);
                 return 0 as jint;
             }
         };
@@ -475,11 +545,12 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetBytePos
 (env: JNIEnv, _clazz: JClass, nativePtr: jlong, isSource: jboolean, javaBytePos: jlong) -> jlong {
     trace!("{}", function_name!());
     let panicResult = panic::catch_unwind(|| {
-        let rtd = get_rtd(nativePtr);
-        let bytes = match do_get_byte_pos(rtd, &get_direction(isSource), javaBytePos as u64) {
+        let rtd = rtd_or_throw!(env, nativePtr, 0 as jlong);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        let bytes = match do_get_byte_pos(&rtd, &get_direction(isSource), javaBytePos as u64) {
             Ok(size) => size,
             Err(e) => {
-                error!("{} [{}]: Getting avail_bytes failed: {:?}", function_name!(), get_thread_name(env),  e);
+                throw_error(env, function_name!(), &e);
                 return 0 as jlong;
             }
         };
@@ -489,6 +560,720 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetBytePos
     return check_panic_result(env, panicResult, -1);
 }
 
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetUnderrunPolicy
+    (JNIEnv* env, jclass clazz, jlong nativePtr, jint policyID)
+ */
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetUnderrunPolicy
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong, policyID: jint) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        UnderrunPolicy::try_from(policyID as usize)
+            .and_then(|policy| do_set_underrun_policy(&rtd, policy))
+            .unwrap_or_else(|err| {
+                throw_error(env, function_name!(), &err);
+            });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+
+/*
+JNIEXPORT jlongArray JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetUnderrunInfo
+    (JNIEnv* env, jclass clazz, jlong nativePtr)
+ */
+/// Returns [underruns count, frames written as silence/repeated, time of last underrun in millis since epoch (0 = none)]
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetUnderrunInfo
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong) -> jlongArray {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr, JObject::null().into_inner());
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        let (count, frames, last_time_ms) = match do_get_underrun_info(&rtd) {
+            Ok(info) => info,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                return JObject::null().into_inner();
+            }
+        };
+        to_jlong_array(env, &[count as jlong, frames as jlong, last_time_ms as jlong])
+    });
+    return check_panic_result(env, panicResult, JObject::null().into_inner());
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetMixGain
+    (JNIEnv* env, jclass clazz, jlong nativePtr, jfloat gain)
+ */
+// Linear gain of the playback line when mixed with other lines of the exclusive device (1.0 = unchanged), ramped like nSetGain
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetMixGain
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong, gain: jfloat) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        do_set_mix_gain(&rtd, gain).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
+        });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetGain
+    (JNIEnv* env, jclass clazz, jlong nativePtr, jfloat gainDb, jint rampMs)
+ */
+// Software gain of the line in dB (0 = bit-perfect, -inf = silence), ramped from the current gain over rampMs
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetGain
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong, gainDb: jfloat, rampMs: jint) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        if rampMs < 0 {
+            let err = NativeError::illegal_argument(&format!("Invalid gain ramp {} ms", rampMs));
+            throw_error(env, function_name!(), &err);
+            return;
+        }
+        do_set_gain(&rtd, gainDb, rampMs as u32).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
+        });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetGainLimiter
+    (JNIEnv* env, jclass clazz, jlong nativePtr, jboolean enabled)
+ */
+// Limiting the software gain output to full scale instead of clipping
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetGainLimiter
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong, enabled: jboolean) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        do_set_gain_limiter(&rtd, enabled != 0).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
+        });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetFade
+    (JNIEnv* env, jclass clazz, jlong nativePtr, jint fadeMs)
+ */
+// Fade-in after start and fade-out before stop of the line, 0 = starting/stopping immediately (default)
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetFade
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong, fadeMs: jint) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        if fadeMs < 0 {
+            let err = NativeError::illegal_argument(&format!("Invalid fade {} ms", fadeMs));
+            throw_error(env, function_name!(), &err);
+            return;
+        }
+        do_set_fade(&rtd, fadeMs as u32).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
+        });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetOverrunPolicy
+    (JNIEnv* env, jclass clazz, jlong nativePtr, jint policyID)
+ */
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetOverrunPolicy
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong, policyID: jint) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        OverrunPolicy::try_from(policyID as usize)
+            .and_then(|policy| do_set_overrun_policy(&rtd, policy))
+            .unwrap_or_else(|err| {
+                throw_error(env, function_name!(), &err);
+            });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetMissedEventPolicy
+    (JNIEnv* env, jclass clazz, jlong nativePtr, jint policyID)
+ */
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetMissedEventPolicy
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong, policyID: jint) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        MissedEventPolicy::try_from(policyID as usize)
+            .and_then(|policy| do_set_missed_event_policy(&rtd, policy))
+            .unwrap_or_else(|err| {
+                throw_error(env, function_name!(), &err);
+            });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+
+/*
+JNIEXPORT jlongArray JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetOverrunInfo
+    (JNIEnv* env, jclass clazz, jlong nativePtr)
+ */
+// Returns [overruns count, lost frames, time of last overrun in millis since epoch (0 = none)]
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetOverrunInfo
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong) -> jlongArray {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr, JObject::null().into_inner());
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        let (count, frames, last_time_ms) = match do_get_overrun_info(&rtd) {
+            Ok(info) => info,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                return JObject::null().into_inner();
+            }
+        };
+        to_jlong_array(env, &[count as jlong, frames as jlong, last_time_ms as jlong])
+    });
+    return check_panic_result(env, panicResult, JObject::null().into_inner());
+}
+
+
+/*
+JNIEXPORT jboolean JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetDiscontinuity
+    (JNIEnv* env, jclass clazz, jlong nativePtr)
+ */
+// Returns true if data returned by nRead since the previous call contained a gap
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetDiscontinuity
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong) -> jboolean {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr, 0 as jboolean);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        match do_get_discontinuity(&rtd) {
+            Ok(discontinuity) => discontinuity as jboolean,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                0 as jboolean
+            }
+        }
+    });
+    return check_panic_result(env, panicResult, 0 as jboolean);
+}
+
+
+/*
+JNIEXPORT jlongArray JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetStats
+    (JNIEnv* env, jclass clazz, jlong nativePtr)
+ */
+// Returns [chunks, underruns (playback) / overruns (capture), missed events, zero-frame reads, silent flags,
+// discontinuity flags, timestamp errors, stream resets, wait_for_event min/avg/max in microseconds]
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetStats
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong) -> jlongArray {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr, JObject::null().into_inner());
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        match do_get_stats(&rtd) {
+            Ok(stats) => {
+                let values: Vec<jlong> = stats.iter().map(|&value| value as jlong).collect();
+                to_jlong_array(env, &values)
+            }
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                JObject::null().into_inner()
+            }
+        }
+    });
+    return check_panic_result(env, panicResult, JObject::null().into_inner());
+}
+
+
+/*
+JNIEXPORT jlongArray JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetTimings
+    (JNIEnv* env, jclass clazz, jlong nativePtr)
+ */
+// Returns bucket counts of the loop timing histograms: 4 phases (outside, transfer, wait, jitter) x 12 buckets
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetTimings
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong) -> jlongArray {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr, JObject::null().into_inner());
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        match do_get_timings(&rtd) {
+            Ok(counts) => {
+                let values: Vec<jlong> = counts.iter().map(|&value| value as jlong).collect();
+                to_jlong_array(env, &values)
+            }
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                JObject::null().into_inner()
+            }
+        }
+    });
+    return check_panic_result(env, panicResult, JObject::null().into_inner());
+}
+
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetThreadConfig
+    (JNIEnv* env, jclass clazz, jlong nativePtr, jint taskID, jint priorityID, jlong affinityMask)
+ */
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetThreadConfig
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong, taskID: jint, priorityID: jint, affinityMask: jlong) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        let config = MmcssTask::try_from(taskID as usize).and_then(|task| {
+            let priority = MmcssPriority::try_from(priorityID as usize)?;
+            Ok(ThreadConfig { task, priority, affinity_mask: affinityMask as u64 })
+        });
+        config.and_then(|config| do_set_thread_config(&rtd, config)).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
+        });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+
+/*
+JNIEXPORT jlongArray JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetThreadInfo
+    (JNIEnv* env, jclass clazz, jlong nativePtr)
+ */
+// Returns [MMCSS task index (0 = not boosted), applied priority ID (0 = default), applied affinity mask (0 = none)]
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetThreadInfo
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong) -> jlongArray {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr, JObject::null().into_inner());
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        let (task_idx, priority, affinity_mask) = match do_get_thread_info(&rtd) {
+            Ok(info) => info,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                return JObject::null().into_inner();
+            }
+        };
+        to_jlong_array(env, &[task_idx as jlong, priority as jlong, affinity_mask as jlong])
+    });
+    return check_panic_result(env, panicResult, JObject::null().into_inner());
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetWaitMode
+    (JNIEnv* env, jclass clazz, jstring deviceID, jint modeID)
+ */
+// Wait mode for lines opened on the device from now on: 0 = event, 1 = polling, 2 = auto (default)
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetWaitMode
+(env: JNIEnv, _clazz: JClass, deviceID: JString, modeID: jint) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        if let Err(err) = do_initialize_wasapi() {
+            throw_error(env, function_name!(), &err);
+            return;
+        }
+        let deviceIDStr = get_string(env, deviceID);
+        WaitMode::try_from(modeID as usize)
+            .and_then(|mode| do_set_wait_mode(deviceIDStr, mode))
+            .unwrap_or_else(|err| {
+                throw_error(env, function_name!(), &err);
+            });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+
+/*
+JNIEXPORT jint JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetWaitMode
+    (JNIEnv* env, jclass clazz, jlong nativePtr)
+ */
+// Returns the wait mode used by the line, 2 = auto while waiting for the first device event
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetWaitMode
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong) -> jint {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr, -1);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        match do_get_wait_mode(&rtd) {
+            Ok(mode) => mode as jint,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                -1
+            }
+        }
+    });
+    return check_panic_result(env, panicResult, -1);
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetDuplexDevice
+    (JNIEnv* env, jclass clazz, jstring deviceID, jstring renderDeviceID)
+ */
+// Capture lines opened on the capture device from now on are driven by the playback line of the exclusive render device,
+// empty renderDeviceID = own capture loop (default)
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetDuplexDevice
+(env: JNIEnv, _clazz: JClass, deviceID: JString, renderDeviceID: JString) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        if let Err(err) = do_initialize_wasapi() {
+            throw_error(env, function_name!(), &err);
+            return;
+        }
+        let deviceIDStr = get_string(env, deviceID);
+        let renderDeviceIDStr = get_string(env, renderDeviceID);
+        do_set_duplex_device(deviceIDStr, renderDeviceIDStr).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
+        });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+/*
+JNIEXPORT jlongArray JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetDuplexPosition
+    (JNIEnv* env, jclass clazz, jlong nativePtr)
+ */
+// Returns [frame position of the line, shared sample counter] of the first frame of the last chunk played/captured
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetDuplexPosition
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong) -> jlongArray {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr, JObject::null().into_inner());
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        let (line_frames, stream_frames) = match do_get_duplex_pos(&rtd) {
+            Ok(pos) => pos,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                return JObject::null().into_inner();
+            }
+        };
+        to_jlong_array(env, &[line_frames as jlong, stream_frames as jlong])
+    });
+    return check_panic_result(env, panicResult, JObject::null().into_inner());
+}
+
+/*
+JNIEXPORT jlong JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetStreamLatency
+    (JNIEnv* env, jclass clazz, jlong nativePtr)
+ */
+// Returns the stream latency of the device client in frames as reported by the driver, 0 before the stream started
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetStreamLatency
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong) -> jlong {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr, -1);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        match do_get_stream_latency(&rtd) {
+            Ok(frames) => frames as jlong,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                -1
+            }
+        }
+    });
+    return check_panic_result(env, panicResult, -1);
+}
+
+/*
+JNIEXPORT jdoubleArray JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetClockDrift
+    (JNIEnv* env, jclass clazz, jlong nativePtr)
+ */
+// Returns [device clock / QPC ratio, real device rate in Hz, estimate window in seconds], zeros before the first estimate
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetClockDrift
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong) -> jdoubleArray {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr, JObject::null().into_inner());
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        let (ratio, rate, window_s) = match do_get_clock_drift(&rtd) {
+            Ok(drift) => drift,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                return JObject::null().into_inner();
+            }
+        };
+        to_jdouble_array(env, &[ratio, rate, window_s])
+    });
+    return check_panic_result(env, panicResult, JObject::null().into_inner());
+}
+
+/*
+JNIEXPORT jdouble JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetRelativeDrift
+    (JNIEnv* env, jclass clazz, jlong nativePtr, jlong otherNativePtr)
+ */
+// Returns the device clock ratio of the line to the line of otherNativePtr, 0 before both are estimated
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetRelativeDrift
+(env: JNIEnv, _clazz: JClass, nativePtr: jlong, otherNativePtr: jlong) -> jdouble {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let rtd = rtd_or_throw!(env, nativePtr, 0.);
+        let other = rtd_or_throw!(env, otherNativePtr, 0.);
+        let _ctx_guard = enter_stream_context(&rtd.log_ctx);
+        match do_get_relative_drift(&rtd, &other) {
+            Ok(ratio) => ratio,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                0.
+            }
+        }
+    });
+    return check_panic_result(env, panicResult, 0.);
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nStartBridge
+    (JNIEnv* env, jclass clazz, jlong captureNativePtr, jlong playbackNativePtr, jint targetFillMs)
+ */
+// Starts resampling the capture line into the playback line, keeping targetFillMs of data queued in the playback line
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nStartBridge
+(env: JNIEnv, _clazz: JClass, captureNativePtr: jlong, playbackNativePtr: jlong, targetFillMs: jint) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let capture = rtd_or_throw!(env, captureNativePtr);
+        let playback = rtd_or_throw!(env, playbackNativePtr);
+        let _ctx_guard = enter_stream_context(&capture.log_ctx);
+        if targetFillMs <= 0 {
+            let err = NativeError::illegal_argument(&format!("Invalid bridge target fill {} ms", targetFillMs));
+            throw_error(env, function_name!(), &err);
+            return;
+        }
+        if let Err(err) = start_bridge(captureNativePtr, capture, playbackNativePtr, playback, targetFillMs as usize) {
+            throw_error(env, function_name!(), &err);
+        }
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nStopBridge
+    (JNIEnv* env, jclass clazz, jlong captureNativePtr)
+ */
+// Stops the bridge of the capture line, the lines keep running
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nStopBridge
+(env: JNIEnv, _clazz: JClass, captureNativePtr: jlong) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        if let Err(err) = stop_bridge(captureNativePtr) {
+            throw_error(env, function_name!(), &err);
+        }
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+/*
+JNIEXPORT jdoubleArray JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetBridgeInfo
+    (JNIEnv* env, jclass clazz, jlong captureNativePtr)
+ */
+// Returns [output/input frame ratio, playback fill in ms, fill correction in ppm] of the bridge of the capture line
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetBridgeInfo
+(env: JNIEnv, _clazz: JClass, captureNativePtr: jlong) -> jdoubleArray {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let (ratio, fill_ms, correction_ppm) = match get_bridge_info(captureNativePtr) {
+            Ok(info) => info,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                return JObject::null().into_inner();
+            }
+        };
+        to_jdouble_array(env, &[ratio, fill_ms, correction_ppm])
+    });
+    return check_panic_result(env, panicResult, JObject::null().into_inner());
+}
+
+/*
+JNIEXPORT jfloatArray JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nGetEndpointVolume
+    (JNIEnv* env, jclass clazz, jstring deviceID)
+ */
+// Returns [volume dB, min dB, max dB, step dB, mute (0/1), channel volumes dB...] of the device endpoint
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nGetEndpointVolume
+(env: JNIEnv, _clazz: JClass, deviceID: JString) -> jfloatArray {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        if let Err(err) = do_initialize_wasapi() {
+            throw_error(env, function_name!(), &err);
+            return JObject::null().into_inner();
+        }
+        let deviceIDStr = get_string(env, deviceID);
+        let volume = match get_endpoint_volume(&deviceIDStr) {
+            Ok(volume) => volume,
+            Err(err) => {
+                throw_error(env, function_name!(), &err);
+                return JObject::null().into_inner();
+            }
+        };
+        let mut values = vec![volume.volume_db, volume.min_db, volume.max_db, volume.step_db, volume.mute as u8 as jfloat];
+        values.extend_from_slice(&volume.channels_db);
+        to_jfloat_array(env, &values)
+    });
+    return check_panic_result(env, panicResult, JObject::null().into_inner());
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetEndpointVolume
+    (JNIEnv* env, jclass clazz, jstring deviceID, jfloat volumeDb)
+ */
+// Sets the master volume of the device endpoint in dB, within the range of nGetEndpointVolume
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetEndpointVolume
+(env: JNIEnv, _clazz: JClass, deviceID: JString, volumeDb: jfloat) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        if let Err(err) = do_initialize_wasapi() {
+            throw_error(env, function_name!(), &err);
+            return;
+        }
+        let deviceIDStr = get_string(env, deviceID);
+        set_endpoint_volume(&deviceIDStr, volumeDb).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
+        });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetEndpointChannelVolume
+    (JNIEnv* env, jclass clazz, jstring deviceID, jint channel, jfloat volumeDb)
+ */
+// Sets the volume of one channel of the device endpoint in dB
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetEndpointChannelVolume
+(env: JNIEnv, _clazz: JClass, deviceID: JString, channel: jint, volumeDb: jfloat) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        if let Err(err) = do_initialize_wasapi() {
+            throw_error(env, function_name!(), &err);
+            return;
+        }
+        if channel < 0 {
+            let err = NativeError::illegal_argument(&format!("Invalid channel {}", channel));
+            throw_error(env, function_name!(), &err);
+            return;
+        }
+        let deviceIDStr = get_string(env, deviceID);
+        set_endpoint_channel_volume(&deviceIDStr, channel as usize, volumeDb).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
+        });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nSetEndpointMute
+    (JNIEnv* env, jclass clazz, jstring deviceID, jboolean mute)
+ */
+// Mutes/unmutes the device endpoint
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nSetEndpointMute
+(env: JNIEnv, _clazz: JClass, deviceID: JString, mute: jboolean) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        if let Err(err) = do_initialize_wasapi() {
+            throw_error(env, function_name!(), &err);
+            return;
+        }
+        let deviceIDStr = get_string(env, deviceID);
+        set_endpoint_mute(&deviceIDStr, mute != 0).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
+        });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
+/*
+JNIEXPORT void JNICALL Java_com_cleansine_sound_provider_SimpleMixer_nWatchEndpointVolume
+    (JNIEnv* env, jclass clazz, jstring deviceID, jboolean enable)
+ */
+// Starts/stops calling SimpleMixer.onEndpointVolume on changes of the device endpoint volume made outside of the library
+#[named]
+#[no_mangle]
+pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixer_nWatchEndpointVolume
+(env: JNIEnv, clazz: JClass, deviceID: JString, enable: jboolean) {
+    trace!("{}", function_name!());
+    let panicResult = panic::catch_unwind(|| {
+        let deviceIDStr = get_string(env, deviceID);
+        let notifier = if enable != 0 {
+            match VolumeNotifier::new(env, clazz) {
+                Ok(notifier) => Some(notifier),
+                Err(err) => {
+                    throw_error(env, function_name!(), &err);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        watch_endpoint_volume(deviceIDStr, notifier).unwrap_or_else(|err| {
+            throw_error(env, function_name!(), &err);
+        });
+    });
+    check_panic_result(env, panicResult, ());
+}
+
 /*
 JNIEXPORT jint JNICALL Java_com_cleansine_sound_provider_SimpleMixerProvider_nGetMixerCnt
     (JNIEnv *env, jclass clazz)
@@ -562,7 +1347,7 @@ pub extern "system" fn Java_com_cleansine_sound_provider_SimpleMixerProvider_nCr
                                        MIXER_INFO_SIGNATURE,
                                        &[JValue::Int(idx),
                                            JValue::from(deviceID),
-                                           JValue::Int(desc.max_lines as jint),
+                                           JValue::Int(desc.max_lines),
                                            JValue::from(name),
                                            JValue::from(vendor),
                                            JValue::from(description)
@@ -585,36 +1370,6 @@ fn get_direction(isSource: jboolean) -> Direction {
 }
 
 
-fn get_rtd_box_ptr(rtd: RuntimeData) -> jlong {
-    let rtd_box: Box<RuntimeData> = Box::new(rtd);
-    let raw: *mut RuntimeData = Box::into_raw(rtd_box);
-    raw as jlong
-}
-
-
-// java -> rust
-// static - HACK!
-fn get_rtd(ptr: jlong) -> &'static mut RuntimeData {
-    // TODO - check for ptr != 0
-    let rtd: &mut RuntimeData = unsafe { jlong_to_pointer::<RuntimeData>(ptr).as_mut().unwrap() };
-    rtd
-}
-
-fn get_rtd_box(ptr: jlong) -> Box<RuntimeData> {
-    // TODO - check for ptr != 0
-    // Box destructor will free the allocated heap memory
-    let rtd_box = unsafe { Box::from_raw(jlong_to_pointer::<RuntimeData>(ptr)) };
-    rtd_box
-}
-
-#[cfg(target_pointer_width = "32")]
-unsafe fn jlong_to_pointer<T>(ptr: jlong) -> *mut T { (ptr as u32) as *mut T }
-
-#[cfg(target_pointer_width = "64")]
-unsafe fn jlong_to_pointer<T>(ptr: jlong) -> *mut T {
-    ptr as *mut T
-}
-
 fn get_string(env: JNIEnv, str: JString) -> String {
     env.get_string(str)
         .expect("Couldn't get java string!")
@@ -633,6 +1388,24 @@ fn from_jint_array(env: JNIEnv, jarr: jintArray) -> Vec<usize> {
     values
 }
 
+fn to_jlong_array(env: JNIEnv, values: &[jlong]) -> jlongArray {
+    let jarr = env.new_long_array(values.len() as jint).unwrap();
+    env.set_long_array_region(jarr, 0, values).unwrap();
+    jarr
+}
+
+fn to_jfloat_array(env: JNIEnv, values: &[jfloat]) -> jfloatArray {
+    let jarr = env.new_float_array(values.len() as jint).unwrap();
+    env.set_float_array_region(jarr, 0, values).unwrap();
+    jarr
+}
+
+fn to_jdouble_array(env: JNIEnv, values: &[jdouble]) -> jdoubleArray {
+    let jarr = env.new_double_array(values.len() as jint).unwrap();
+    env.set_double_array_region(jarr, 0, values).unwrap();
+    jarr
+}
+
 #[named]
 fn get_thread_name(env: JNIEnv) -> String {
     let clazzName = "java/lang/Thread";
@@ -686,6 +1459,19 @@ fn get_thread_name(env: JNIEnv) -> String {
     return get_string(env, JString::from(name));
 }
 
+/// Logs the error and throws the java exception corresponding to its kind
+fn throw_error(env: JNIEnv, fn_name: &str, err: &NativeError) {
+    error!("{} [{}]: {}", fn_name, get_thread_name(env), err);
+    let class = match err.kind {
+        ErrorKind::DeviceBusy | ErrorKind::DeviceGone => "javax/sound/sampled/LineUnavailableException",
+        ErrorKind::UnsupportedFormat | ErrorKind::IllegalArgument => "java/lang/IllegalArgumentException",
+        ErrorKind::IllegalState | ErrorKind::Timeout => "java/lang/IllegalStateException",
+        ErrorKind::Device | ErrorKind::Internal => "java/lang/RuntimeException",
+    };
+    // the message contains the error kind, device name and HRESULT
+    let _ = env.throw_new(class, err.to_string());
+}
+
 fn check_panic_result<T>(env: JNIEnv, result: Result<T, Box<dyn Any + Send>>, panicValue: T) -> T {
     return match result {
         Ok(v) => v,
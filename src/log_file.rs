@@ -0,0 +1,225 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::error::{NativeError, Res};
+use crate::logging::{LogFormat, LogRecordData, LogSink};
+
+// File log target with optional rotation:
+// <path>[;append][;maxSize=<n>[K|M|G]][;maxAge=<n>[s|m|h|d]][;keep=<n>][;compress]
+// Rotated files are <path>.1 (newest) ... <path>.<keep>, with .gz suffix when compressed.
+// Rotation runs in the log writer thread, compression of the rotated file in a separate thread.
+
+const DEFAULT_KEEP: usize = 5;
+
+#[derive(Debug)]
+struct FileOptions {
+    append: bool,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    keep: usize,
+    compress: bool,
+}
+
+impl FileOptions {
+    fn parse(spec: &str) -> Res<(String, FileOptions)> {
+        let mut parts = spec.split(';');
+        let path = parts.next().unwrap_or_default().trim().to_owned();
+        if path.is_empty() {
+            return Err(NativeError::illegal_argument("Empty log file path"));
+        }
+        let mut options = FileOptions { append: false, max_size: None, max_age: None, keep: DEFAULT_KEEP, compress: false };
+        for part in parts.map(str::trim).filter(|part| !part.is_empty()) {
+            match part.split_once('=') {
+                None if part == "append" => options.append = true,
+                None if part == "compress" => options.compress = true,
+                Some(("maxSize", value)) => options.max_size = Some(parse_size(value)?),
+                Some(("maxAge", value)) => options.max_age = Some(parse_age(value)?),
+                Some(("keep", value)) => {
+                    options.keep = value.parse()?;
+                    if options.keep == 0 {
+                        return Err(NativeError::illegal_argument("Log file option keep must be at least 1"));
+                    }
+                }
+                _ => {
+                    let msg = format!("Unknown log file option '{}'", part);
+                    return Err(NativeError::illegal_argument(&msg));
+                }
+            }
+        }
+        Ok((path, options))
+    }
+}
+
+fn split_suffix(value: &str) -> (&str, Option<char>) {
+    match value.chars().last() {
+        Some(c) if c.is_ascii_alphabetic() => (&value[..value.len() - 1], Some(c.to_ascii_uppercase())),
+        _ => (value, None),
+    }
+}
+
+fn parse_size(value: &str) -> Res<u64> {
+    let (number, suffix) = split_suffix(value);
+    let multiplier = match suffix {
+        None => 1,
+        Some('K') => 1 << 10,
+        Some('M') => 1 << 20,
+        Some('G') => 1 << 30,
+        Some(_) => return Err(NativeError::illegal_argument(&format!("Invalid log file size '{}'", value))),
+    };
+    Ok(number.parse::<u64>()? * multiplier)
+}
+
+fn parse_age(value: &str) -> Res<Duration> {
+    let (number, suffix) = split_suffix(value);
+    let multiplier = match suffix {
+        None | Some('S') => 1,
+        Some('M') => 60,
+        Some('H') => 3600,
+        Some('D') => 86400,
+        Some(_) => return Err(NativeError::illegal_argument(&format!("Invalid log file age '{}'", value))),
+    };
+    Ok(Duration::from_secs(number.parse::<u64>()? * multiplier))
+}
+
+pub struct FileSink {
+    path: String,
+    options: FileOptions,
+    format: LogFormat,
+    // None while rotating (windows cannot rename open files)
+    writer: Option<BufWriter<File>>,
+    written: u64,
+    // start of the current file content, for maxAge
+    created: SystemTime,
+    // compression of the last rotated file
+    compressor: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl FileSink {
+    pub fn new(spec: &str, format: LogFormat) -> Res<Self> {
+        let (path, options) = FileOptions::parse(spec)?;
+        let (file, written, created) = open_file(&path, options.append)
+            .map_err(|err| NativeError::illegal_argument(&format!("Failed to open log file {}: {}", path, err)))?;
+        Ok(FileSink {
+            path,
+            options,
+            format,
+            writer: Some(BufWriter::new(file)),
+            written,
+            created,
+            compressor: None,
+        })
+    }
+
+    fn needs_rotation(&self, next_len: usize) -> bool {
+        let size_exceeded = self.options.max_size
+            .map_or(false, |max_size| self.written > 0 && self.written + next_len as u64 > max_size);
+        let age_exceeded = self.options.max_age
+            .map_or(false, |max_age| self.created.elapsed().unwrap_or_default() >= max_age);
+        size_exceeded || age_exceeded
+    }
+
+    fn rotated_name(&self, idx: usize) -> String {
+        format!("{}.{}{}", self.path, idx, if self.options.compress { ".gz" } else { "" })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            let _ = writer.flush();
+        }
+        let result = self.rotate_files();
+        // the current file is truncated after successful rotation, appended to otherwise
+        let (file, written, created) = open_file(&self.path, result.is_err())?;
+        self.writer = Some(BufWriter::new(file));
+        self.written = written;
+        self.created = created;
+        result
+    }
+
+    fn rotate_files(&mut self) -> io::Result<()> {
+        // the previous compression must finish before shifting the files, usually long done
+        if let Some(compressor) = self.compressor.take() {
+            match compressor.join() {
+                Ok(Err(err)) => eprintln!("Failed to compress rotated log file {}: {}", self.rotated_name(1), err),
+                Err(_) => eprintln!("Compression of rotated log file {} panicked", self.rotated_name(1)),
+                Ok(Ok(())) => {}
+            }
+        }
+        // dropping the oldest, shifting the rest
+        let oldest = self.rotated_name(self.options.keep);
+        if fs::metadata(&oldest).is_ok() {
+            fs::remove_file(&oldest)?;
+        }
+        for idx in (1..self.options.keep).rev() {
+            let name = self.rotated_name(idx);
+            if fs::metadata(&name).is_ok() {
+                fs::rename(&name, self.rotated_name(idx + 1))?;
+            }
+        }
+        if self.options.compress {
+            // compressing the renamed file, the writer continues with a new file meanwhile
+            let src = format!("{}.1", self.path);
+            let dst = self.rotated_name(1);
+            fs::rename(&self.path, &src)?;
+            self.compressor = Some(thread::Builder::new()
+                .name("NativeLogCompress".to_string())
+                .spawn(move || compress_file(&src, &dst))?);
+        } else {
+            fs::rename(&self.path, self.rotated_name(1))?;
+        }
+        Ok(())
+    }
+}
+
+impl LogSink for FileSink {
+    fn write_batch(&mut self, records: &[LogRecordData]) {
+        for record in records {
+            let line = self.format.do_format(record);
+            if self.needs_rotation(line.len()) {
+                if let Err(err) = self.rotate() {
+                    eprintln!("Failed to rotate log file {}: {}", self.path, err);
+                }
+            }
+            if let Some(writer) = self.writer.as_mut() {
+                if writer.write_all(line.as_bytes()).is_ok() {
+                    self.written += line.len() as u64;
+                }
+            }
+        }
+        if let Some(writer) = self.writer.as_mut() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+/// Returns the file, its length and the time its content was started
+fn open_file(path: &str, append: bool) -> io::Result<(File, u64, SystemTime)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    let metadata = file.metadata()?;
+    let len = metadata.len();
+    // an empty file starts now. Windows can report the creation time of a file just rotated under the same name
+    // (file system tunneling), the creation time is trusted only for existing content
+    let created = if len > 0 { metadata.created().or_else(|_| metadata.modified())? } else { SystemTime::now() };
+    Ok((file, len, created))
+}
+
+fn compress_file(src: &str, dst: &str) -> io::Result<()> {
+    {
+        let mut input = File::open(src)?;
+        let mut encoder = GzEncoder::new(File::create(dst)?, Compression::default());
+        io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?;
+    }
+    // src must be closed before removal on windows
+    fs::remove_file(src)
+}
@@ -0,0 +1,520 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::SystemTime;
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use jni::JNIEnv;
+use jni::objects::JClass;
+use lazy_static::lazy_static;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use time::{format_description, OffsetDateTime};
+use time::format_description::well_known::Rfc3339;
+
+use crate::error::{NativeError, Res};
+use crate::jvm_log::JvmSink;
+use crate::log_file::FileSink;
+
+// Native logging: the logging threads (incl. the real-time device threads) only check the filters
+// and enqueue the record without blocking. A single writer thread passes the records in batches
+// to the current sink. Level filters and the sink can be changed at runtime.
+// The records are preallocated and recycled by the writer thread, logging does not allocate.
+
+pub const JVM_LOG_TARGET: &str = "jvm";
+// records queued for the writer thread, further records are dropped (and counted) when all are queued
+const LOG_QUEUE_LEN: usize = 1024;
+// preallocated string capacities of the records, longer texts are truncated
+const MSG_CAPACITY: usize = 512;
+const FIELD_CAPACITY: usize = 64;
+
+/// Audio incidents tagged in the records, for aggregation of the JSON logs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogEvent {
+    Open,
+    Close,
+    Underrun,
+    Overrun,
+    MissedEvent,
+    Disconnect,
+}
+
+impl LogEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            LogEvent::Open => "open",
+            LogEvent::Close => "close",
+            LogEvent::Underrun => "underrun",
+            LogEvent::Overrun => "overrun",
+            LogEvent::MissedEvent => "missed_event",
+            LogEvent::Disconnect => "disconnect",
+        }
+    }
+}
+
+/// Stream identification attached to records logged by threads serving the line
+pub struct StreamLogContext {
+    pub device_id: String,
+    pub dir: String,
+    // line handle, 0 until the line is registered
+    handle: AtomicI64,
+}
+
+impl StreamLogContext {
+    pub fn new(device_id: &str, dir: &str) -> Self {
+        StreamLogContext { device_id: device_id.to_owned(), dir: dir.to_owned(), handle: AtomicI64::new(0) }
+    }
+
+    pub fn set_handle(&self, handle: i64) {
+        self.handle.store(handle, Ordering::Relaxed);
+    }
+
+    pub fn handle(&self) -> i64 {
+        self.handle.load(Ordering::Relaxed)
+    }
+}
+
+thread_local! {
+    static STREAM_CTX: RefCell<Option<Arc<StreamLogContext>>> = RefCell::new(None);
+    static CURRENT_EVENT: Cell<Option<LogEvent>> = Cell::new(None);
+}
+
+/// Restores the previous stream context of the thread when dropped
+pub struct StreamContextGuard {
+    prev: Option<Arc<StreamLogContext>>,
+}
+
+impl Drop for StreamContextGuard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        STREAM_CTX.with(|ctx| *ctx.borrow_mut() = prev);
+    }
+}
+
+/// Records of the current thread carry the stream context until the guard is dropped
+pub fn enter_stream_context(stream: &Arc<StreamLogContext>) -> StreamContextGuard {
+    let prev = STREAM_CTX.with(|ctx| ctx.borrow_mut().replace(stream.clone()));
+    StreamContextGuard { prev }
+}
+
+pub fn current_stream_context() -> Option<Arc<StreamLogContext>> {
+    STREAM_CTX.with(|ctx| ctx.borrow().clone())
+}
+
+pub fn with_event<F: FnOnce()>(event: LogEvent, f: F) {
+    CURRENT_EVENT.with(|cur| cur.set(Some(event)));
+    f();
+    CURRENT_EVENT.with(|cur| cur.set(None));
+}
+
+/// log! with the event type attached to the record
+macro_rules! log_event {
+    (target: $target:expr, $event:expr, $lvl:expr, $($arg:tt)+) => {
+        $crate::logging::with_event($event, || log::log!(target: $target, $lvl, $($arg)+))
+    };
+    ($event:expr, $lvl:expr, $($arg:tt)+) => {
+        $crate::logging::with_event($event, || log::log!($lvl, $($arg)+))
+    };
+}
+
+pub(crate) use log_event;
+
+pub struct LogRecordData {
+    pub level: Level,
+    // module path, or explicit target of the log macro
+    pub target: String,
+    pub thread: String,
+    pub msg: String,
+    pub file: String,
+    pub line: u32,
+    pub time: SystemTime,
+    pub event: Option<LogEvent>,
+    pub stream: Option<Arc<StreamLogContext>>,
+}
+
+impl LogRecordData {
+    fn preallocated() -> Self {
+        LogRecordData {
+            level: Level::Error,
+            target: String::with_capacity(FIELD_CAPACITY),
+            thread: String::with_capacity(FIELD_CAPACITY),
+            msg: String::with_capacity(MSG_CAPACITY),
+            file: String::with_capacity(FIELD_CAPACITY),
+            line: 0,
+            time: SystemTime::UNIX_EPOCH,
+            event: None,
+            stream: None,
+        }
+    }
+}
+
+/// Writes into the preallocated string, truncating at its capacity instead of reallocating
+struct BoundedWriter<'a>(&'a mut String);
+
+impl fmt::Write for BoundedWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.0.capacity() - self.0.len();
+        if s.len() <= room {
+            self.0.push_str(s);
+            return Ok(());
+        }
+        let mut end = room;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.0.push_str(&s[..end]);
+        // stops formatting the rest
+        Err(fmt::Error)
+    }
+}
+
+fn copy_bounded(dst: &mut String, src: &str) {
+    dst.clear();
+    let _ = BoundedWriter(dst).write_str(src);
+}
+
+pub trait LogSink: Send {
+    fn write_batch(&mut self, records: &[LogRecordData]);
+}
+
+enum LogMsg {
+    Record(LogRecordData),
+    SetSink(Box<dyn LogSink>),
+}
+
+/// Default level + levels for module paths (incl. submodules and explicit targets under the path)
+pub struct LogFilters {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilters {
+    pub fn new(default: LevelFilter) -> Self {
+        LogFilters { default, modules: Vec::new() }
+    }
+
+    /// module_levels: comma-separated module=level pairs, e.g. "wasapi_impl::playback_loop=trace,handles=debug".
+    /// Module paths are relative to the library crate, the crate prefix is optional.
+    pub fn parse(default: LevelFilter, module_levels: &str) -> Res<Self> {
+        let mut filters = LogFilters::new(default);
+        for item in module_levels.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (module, level) = match item.split_once('=') {
+                Some((module, level)) => (module.trim(), level.trim()),
+                None => {
+                    let msg = format!("Invalid module log level '{}', expected module=level", item);
+                    return Err(NativeError::illegal_argument(&msg));
+                }
+            };
+            let level = match LevelFilter::from_str(level) {
+                Ok(level) => level,
+                Err(_) => {
+                    let msg = format!("Invalid log level '{}' for module {}", level, module);
+                    return Err(NativeError::illegal_argument(&msg));
+                }
+            };
+            let crate_prefix = concat!(env!("CARGO_CRATE_NAME"), "::");
+            let module = if module.starts_with(crate_prefix) || module == env!("CARGO_CRATE_NAME") {
+                module.to_owned()
+            } else {
+                format!("{}{}", crate_prefix, module)
+            };
+            filters.modules.push((module, level));
+        }
+        Ok(filters)
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        // the most specific module wins
+        self.modules.iter()
+            .filter(|(module, _)| target == module
+                || (target.starts_with(module.as_str()) && target[module.len()..].starts_with("::")))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|&(_, level)| level).fold(self.default, LevelFilter::max)
+    }
+
+    // enabled for all modules
+    fn min_level(&self) -> LevelFilter {
+        self.modules.iter().map(|&(_, level)| level).fold(self.default, LevelFilter::min)
+    }
+}
+
+// levels of the current filters as LevelFilter discriminants, records at or below MIN_LEVEL pass
+// and records above MAX_LEVEL are rejected without locking the filters
+static MIN_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Error as usize);
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Error as usize);
+
+lazy_static! {
+    static ref FILTERS: RwLock<LogFilters> = RwLock::new(LogFilters::new(LevelFilter::Error));
+    // control path to the writer thread, None until logging is initialized
+    static ref LOG_TX: Mutex<Option<Sender<LogMsg>>> = Mutex::new(None);
+    static ref TIME_FORMAT: Vec<format_description::FormatItem<'static>>= format_description::parse("[hour]:[minute]:[second].[subsecond]").unwrap();
+}
+
+struct NativeLogger {
+    tx: Sender<LogMsg>,
+    // free preallocated records
+    rx_free: Receiver<LogRecordData>,
+    tx_free: Sender<LogRecordData>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Log for NativeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = metadata.level() as usize;
+        if level <= MIN_LEVEL.load(Ordering::Relaxed) {
+            return true;
+        }
+        if level > MAX_LEVEL.load(Ordering::Relaxed) {
+            return false;
+        }
+        // module specific level
+        match FILTERS.read() {
+            Ok(filters) => metadata.level() <= filters.level_for(metadata.target()),
+            Err(_) => false,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // never blocking or allocating in the caller
+        let mut data = match self.rx_free.try_recv() {
+            Ok(data) => data,
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        data.level = record.level();
+        copy_bounded(&mut data.target, record.target());
+        copy_bounded(&mut data.thread, thread::current().name().unwrap_or("unnamed"));
+        data.msg.clear();
+        let _ = write!(BoundedWriter(&mut data.msg), "{}", record.args());
+        copy_bounded(&mut data.file, record.file().unwrap_or_default());
+        data.line = record.line().unwrap_or_default();
+        data.time = SystemTime::now();
+        data.event = CURRENT_EVENT.with(|cur| cur.get());
+        data.stream = current_stream_context();
+        if let Err(TrySendError::Full(LogMsg::Record(data))) = self.tx.try_send(LogMsg::Record(data)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            self.tx_free.try_send(data).unwrap_or(());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// same constants as in the java provider
+pub fn level_filter_from_id(level_id: usize) -> LevelFilter {
+    match level_id {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        4 => LevelFilter::Trace,
+        _ => LevelFilter::Error,
+    }
+}
+
+/// Creates sink for the log target passed from java: stdout, stderr, jvm, or a file path with options (see log_file).
+/// Option json (e.g. stdout;json) selects JSON lines instead of text.
+pub fn create_sink(env: JNIEnv, provider_class: JClass, log_target: &str) -> Res<Box<dyn LogSink>> {
+    let mut json = false;
+    let log_target = log_target.split(';')
+        .filter(|part| if part.trim() == "json" {
+            json = true;
+            false
+        } else {
+            true
+        })
+        .collect::<Vec<_>>()
+        .join(";");
+    let format = LogFormat { display_line_level: LevelFilter::Off, json };
+    let sink: Box<dyn LogSink> = match log_target.as_str() {
+        "stdout" => Box::new(WriterSink { writer: Box::new(std::io::stdout()), format }),
+        "stderr" => Box::new(WriterSink { writer: Box::new(std::io::stderr()), format }),
+        JVM_LOG_TARGET => Box::new(JvmSink::new(env, provider_class)?),
+        file_spec => Box::new(FileSink::new(file_spec, format)?),
+    };
+    Ok(sink)
+}
+
+/// Installs the native logger on first call, later calls only replace filters and sink
+pub fn init_logging(sink: Box<dyn LogSink>, filters: LogFilters) -> Res<()> {
+    let mut log_tx = LOG_TX.lock()?;
+    set_filters(filters)?;
+    if let Some(tx) = log_tx.as_ref() {
+        tx.send(LogMsg::SetSink(sink))?;
+        return Ok(());
+    }
+    let (tx, rx) = bounded(LOG_QUEUE_LEN);
+    let (tx_free, rx_free) = bounded(LOG_QUEUE_LEN);
+    for _ in 0..LOG_QUEUE_LEN {
+        tx_free.send(LogRecordData::preallocated())?;
+    }
+    let dropped = Arc::new(AtomicU64::new(0));
+    let dropped_cloned = dropped.clone();
+    let tx_free_cloned = tx_free.clone();
+    thread::Builder::new()
+        .name("NativeLogWriter".to_string())
+        .spawn(move || writer_loop(sink, rx, tx_free_cloned, dropped_cloned))?;
+    let logger = NativeLogger { tx: tx.clone(), rx_free, tx_free, dropped };
+    // set_boxed_logger requires the std feature of log
+    log::set_logger(Box::leak(Box::new(logger)))
+        .map_err(|err| NativeError::illegal_state(&err.to_string()))?;
+    log::set_max_level(FILTERS.read()?.max_level());
+    *log_tx = Some(tx);
+    Ok(())
+}
+
+pub fn set_filters(filters: LogFilters) -> Res<()> {
+    let (min_level, max_level) = (filters.min_level(), filters.max_level());
+    *FILTERS.write()? = filters;
+    MAX_LEVEL.store(max_level as usize, Ordering::Relaxed);
+    MIN_LEVEL.store(min_level as usize, Ordering::Relaxed);
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Replaces the sink, records queued before are still written to the previous sink
+pub fn set_sink(sink: Box<dyn LogSink>) -> Res<()> {
+    match LOG_TX.lock()?.as_ref() {
+        Some(tx) => Ok(tx.send(LogMsg::SetSink(sink))?),
+        None => Err(NativeError::illegal_state("Logging not initialized")),
+    }
+}
+
+// written records are returned to the free records
+fn recycle(batch: &mut Vec<LogRecordData>, tx_free: &Sender<LogRecordData>) {
+    for mut record in batch.drain(..) {
+        record.stream = None;
+        tx_free.try_send(record).unwrap_or(());
+    }
+}
+
+fn writer_loop(mut sink: Box<dyn LogSink>, rx: Receiver<LogMsg>, tx_free: Sender<LogRecordData>, dropped: Arc<AtomicU64>) {
+    let mut batch: Vec<LogRecordData> = Vec::with_capacity(LOG_QUEUE_LEN + 1);
+    // blocking for the first record of the batch, then taking everything queued meanwhile
+    while let Ok(first) = rx.recv() {
+        for msg in std::iter::once(first).chain(rx.try_iter()) {
+            match msg {
+                LogMsg::Record(record) => batch.push(record),
+                LogMsg::SetSink(new_sink) => {
+                    sink.write_batch(&batch);
+                    recycle(&mut batch, &tx_free);
+                    sink = new_sink;
+                }
+            }
+        }
+        let dropped_cnt = dropped.swap(0, Ordering::Relaxed);
+        if dropped_cnt > 0 {
+            // recycled like the other records
+            let mut notice = LogRecordData::preallocated();
+            notice.level = Level::Warn;
+            copy_bounded(&mut notice.target, module_path!());
+            copy_bounded(&mut notice.thread, thread::current().name().unwrap_or("unnamed"));
+            let _ = write!(BoundedWriter(&mut notice.msg), "Log queue full, dropped {} native log records", dropped_cnt);
+            copy_bounded(&mut notice.file, file!());
+            notice.line = line!();
+            notice.time = SystemTime::now();
+            batch.push(notice);
+        }
+        sink.write_batch(&batch);
+        recycle(&mut batch, &tx_free);
+    }
+}
+
+struct WriterSink {
+    writer: Box<dyn Write + Send>,
+    format: LogFormat,
+}
+
+impl LogSink for WriterSink {
+    fn write_batch(&mut self, records: &[LogRecordData]) {
+        for record in records {
+            let _ = self.writer.write_all(self.format.do_format(record).as_bytes());
+        }
+        let _ = self.writer.flush();
+    }
+}
+
+pub struct LogFormat {
+    pub display_line_level: LevelFilter,
+    pub json: bool,
+}
+
+fn systemtime_strftime<T>(dt: T) -> String
+    where T: Into<OffsetDateTime> {
+    dt.into().format(&TIME_FORMAT).unwrap()
+}
+
+impl LogFormat {
+    pub fn do_format(&self, record: &LogRecordData) -> String {
+        if self.json {
+            return format_json(record);
+        }
+        let time_str = systemtime_strftime(record.time);
+        if record.level.to_level_filter() >= self.display_line_level {
+            format!(
+                "{} {} [{}:{}] {}\n",
+                time_str,
+                record.level,
+                record.file,
+                record.line,
+                record.msg,
+            )
+        } else {
+            format!(
+                "{} {} {} - {}\n",
+                time_str, record.level, record.target, record.msg
+            )
+        }
+    }
+}
+
+// one JSON object per line, absent fields omitted
+fn format_json(record: &LogRecordData) -> String {
+    let ts = OffsetDateTime::from(record.time).format(&Rfc3339).unwrap_or_default();
+    let mut line = format!(
+        "{{\"ts\":\"{}\",\"level\":\"{}\",\"thread\":\"{}\",\"target\":\"{}\",\"file\":\"{}\",\"line\":{}",
+        ts, record.level, json_escape(&record.thread), json_escape(&record.target), json_escape(&record.file), record.line
+    );
+    if let Some(event) = record.event {
+        line.push_str(&format!(",\"event\":\"{}\"", event.name()));
+    }
+    if let Some(stream) = &record.stream {
+        line.push_str(&format!(",\"device_id\":\"{}\",\"direction\":\"{}\"",
+                               json_escape(&stream.device_id), json_escape(&stream.dir)));
+        let handle = stream.handle();
+        if handle != 0 {
+            line.push_str(&format!(",\"handle\":{}", handle));
+        }
+    }
+    line.push_str(&format!(",\"msg\":\"{}\"}}\n", json_escape(&record.msg)));
+    line
+}
+
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+
+use crossbeam_channel::{Receiver, Sender};
+use lazy_static::lazy_static;
+use log::{debug, Level, warn};
+
+use crate::drift::ClockDrift;
+use crate::error::{ErrorKind, NativeError, Res};
+use crate::gain::{FadeStop, GainStage};
+use crate::logging::{log_event, LogEvent};
+use crate::samples::{add_samples, apply_ramp, Limiter, SampleConverter, write_samples};
+use crate::wasapi_impl::{LineStats, PlaySyncData, UnderrunPolicy};
+
+// Software mixing of several playback lines into one exclusive stream.
+// The first line opened on an exclusive render device opens the AudioClient and runs its playback loop (the bus owner).
+// Lines opened later on the same device are attached to the bus as inputs: they have no own device thread,
+// the owner loop services their signals, pulls their chunks, converts them to the owner format and sums them.
+// Without attached inputs and with unity gain the owner chunks are written unchanged (bit-perfect).
+// The stream is shared by all open lines (MixStream) and closed with the last of them, any line incl. the owner
+// can be closed independently. The loop of a closed owner line keeps running for the mixed lines, contributing silence.
+// A mixed line running out of data follows its own UnderrunPolicy, its drain is confirmed from the clock of the owner stream.
+
+// max. lines mixed into one exclusive stream, incl. the owner
+pub const MAX_MIX_LINES: usize = 8;
+
+/// Registered by the bus owner, used for attaching new lines
+#[derive(Clone)]
+pub struct MixBusEntry {
+    tx_attach: Sender<MixInput>,
+    // format of the owner line
+    pub rate: usize,
+    pub sample_bytes: usize,
+    pub channels: usize,
+    pub chunk_frames: usize,
+    pub stream: Arc<MixStream>,
+    // of the owner loop
+    pub clock_drift: Arc<ClockDrift>,
+}
+
+impl MixBusEntry {
+    pub fn new(tx_attach: Sender<MixInput>, rate: usize, sample_bytes: usize, channels: usize, chunk_frames: usize,
+               stream: Arc<MixStream>, clock_drift: Arc<ClockDrift>) -> Self {
+        MixBusEntry { tx_attach, rate, sample_bytes, channels, chunk_frames, stream, clock_drift }
+    }
+
+    /// Checks a new line can join the bus, reserving its place
+    pub fn reserve_line(&self, rate: usize) -> Res<()> {
+        if rate != self.rate {
+            let msg = format!("Mixed lines must use the device rate {}, requested {}", self.rate, rate);
+            return Err(NativeError::new(ErrorKind::UnsupportedFormat, &msg));
+        }
+        self.stream.reserve_line()
+    }
+
+    pub fn attach(&self, input: MixInput) -> Res<()> {
+        if self.tx_attach.send(input).is_err() {
+            self.stream.release_line();
+            return Err(NativeError::illegal_state("Device mixer already closed"));
+        }
+        Ok(())
+    }
+}
+
+/// Exclusive stream shared by the lines of the bus, closed with the last open line
+pub struct MixStream {
+    // open lines incl. the owner
+    lines: AtomicUsize,
+    // the owner line was closed, its loop keeps running for the mixed lines
+    own_closed: Arc<AtomicBool>,
+    // exit signal of the owner loop
+    exit_signal: Arc<AtomicBool>,
+    // owner loop, joined by the last closed line
+    handle: Mutex<Option<JoinHandle<Res<()>>>>,
+}
+
+impl MixStream {
+    pub fn new(own_closed: Arc<AtomicBool>, exit_signal: Arc<AtomicBool>) -> Self {
+        MixStream { lines: AtomicUsize::new(1), own_closed, exit_signal, handle: Mutex::new(None) }
+    }
+
+    pub fn set_handle(&self, handle: Option<JoinHandle<Res<()>>>) -> Res<()> {
+        *self.handle.lock()? = handle;
+        Ok(())
+    }
+
+    fn reserve_line(&self) -> Res<()> {
+        // no line can join a stream being closed by its last line
+        let reserved = self.lines.fetch_update(Ordering::Relaxed, Ordering::Relaxed,
+                                               |lines| if lines > 0 && lines < MAX_MIX_LINES { Some(lines + 1) } else { None });
+        match reserved {
+            Ok(_) => Ok(()),
+            Err(0) => Err(NativeError::illegal_state("Device mixer already closed")),
+            Err(_) => {
+                let msg = format!("All {} lines of the device mixer are in use", MAX_MIX_LINES);
+                Err(NativeError::illegal_state(&msg))
+            }
+        }
+    }
+
+    /// Returns true if the last open line was released, the stream must be closed
+    pub fn release_line(&self) -> bool {
+        self.lines.fetch_sub(1, Ordering::Relaxed) == 1
+    }
+
+    pub fn open_lines(&self) -> usize {
+        self.lines.load(Ordering::Relaxed)
+    }
+
+    /// The owner line stops contributing to the stream, its queued chunks are discarded
+    pub fn close_own_line(&self) {
+        self.own_closed.store(true, Ordering::Relaxed);
+    }
+
+    /// Signals the owner loop to stop the stream and exit, returns the loop handle for joining
+    pub fn exit(&self) -> Res<Option<JoinHandle<Res<()>>>> {
+        self.exit_signal.store(true, Ordering::Relaxed);
+        Ok(self.handle.lock()?.take())
+    }
+}
+
+lazy_static! {
+    static ref MIX_BUSES: Mutex<HashMap<String, MixBusEntry>> = Mutex::new(HashMap::new());
+}
+
+pub fn register_mix_bus(device_id: &str, entry: MixBusEntry) -> Res<()> {
+    MIX_BUSES.lock()?.insert(device_id.to_owned(), entry);
+    Ok(())
+}
+
+pub fn unregister_mix_bus(device_id: &str) -> Res<()> {
+    MIX_BUSES.lock()?.remove(device_id);
+    Ok(())
+}
+
+pub fn get_mix_bus(device_id: &str) -> Res<Option<MixBusEntry>> {
+    Ok(MIX_BUSES.lock()?.get(device_id).cloned())
+}
+
+/// Line attached to the bus, its sync data are serviced by the owner loop instead of an own device thread
+pub struct MixInput {
+    sync: PlaySyncData,
+    converter: SampleConverter,
+    gain: GainStage,
+    // set when the bus is gone, the line cannot play anymore
+    detached: Arc<AtomicBool>,
+    active: bool,
+    // playing until faded out, then stopping
+    fade: FadeStop,
+    flush_confirmed: bool,
+    // end of the last chunk mixed from this line, in frames of the owner stream
+    written_frames: u64,
+    drain_target_frames: Option<u64>,
+    // kept for RepeatFade underrun policy
+    last_chunk: Option<Vec<u8>>,
+    in_underrun: bool,
+    // line stopped by UnderrunPolicy::Stop, an underrun only if the data resume without a stop/drain/flush
+    starved_stop: bool,
+}
+
+impl MixInput {
+    pub fn new(sync: PlaySyncData, converter: SampleConverter, gain: GainStage, detached: Arc<AtomicBool>) -> Self {
+        MixInput {
+            sync,
+            converter,
+            gain,
+            detached,
+            active: false,
+            fade: FadeStop::default(),
+            flush_confirmed: false,
+            written_frames: 0,
+            drain_target_frames: None,
+            last_chunk: None,
+            in_underrun: false,
+            starved_stop: false,
+        }
+    }
+
+    /// Returns false when the line was closed
+    fn handle_signals(&mut self) -> bool {
+        let sync = &self.sync;
+        if sync.exit_signal.swap(false, Ordering::Relaxed) {
+            debug!("PB MIX: mixed line closed, detaching");
+            return false;
+        }
+        if sync.start_signal.swap(false, Ordering::Relaxed) {
+            self.fade.start(&mut self.gain, self.active);
+            self.active = true;
+        }
+        if self.fade.stop_requested(&self.sync.stop_signal, &mut self.gain, self.active) {
+            // the line ran out of data at its normal end
+            self.starved_stop = false;
+            self.finish_stop();
+        }
+        if self.sync.flush_signal.load(Ordering::Relaxed) {
+            if !self.flush_confirmed {
+                // the outer side clears the queue and restarts the line
+                self.sync.tx_flushed.try_send(self.active).unwrap_or(());
+                self.flush_confirmed = true;
+                self.starved_stop = false;
+                self.last_chunk = None;
+                self.finish_stop();
+            }
+        } else {
+            self.flush_confirmed = false;
+        }
+        true
+    }
+
+    fn finish_stop(&mut self) {
+        self.active = false;
+        self.in_underrun = false;
+        self.fade.cancel();
+        self.finish_drain();
+    }
+
+    fn finish_drain(&mut self) {
+        self.drain_target_frames = None;
+        if self.sync.drain_signal.swap(false, Ordering::Relaxed) {
+            self.sync.tx_drained.try_send(()).unwrap_or(());
+        }
+    }
+
+    /// Returns the next chunk of the line, flagged true for the last chunk repeated on underrun (to be faded out)
+    fn next_chunk(&mut self, chunk_frames: usize) -> Option<(Vec<u8>, bool)> {
+        if self.sync.flush_signal.load(Ordering::Relaxed) {
+            // not consuming chunks until the outer side has cleared the queue
+            return None;
+        }
+        if let Ok(chunk) = self.sync.rx_dev.try_recv() {
+            if self.starved_stop {
+                // data resumed after stopping the starved line, the gap was audible
+                log_event!(LogEvent::Underrun, Level::Debug, "PB MIX: data resumed after underrun stop");
+                self.sync.underruns.record(0);
+                self.starved_stop = false;
+            }
+            if !self.active {
+                debug!("PB MIX: received chunk in stopped mixed line, starting automatically");
+                self.active = true;
+                self.gain.fade_in();
+            }
+            if self.in_underrun {
+                debug!("PB MIX: underrun finished, data available again");
+                self.in_underrun = false;
+            }
+            LineStats::inc(&self.sync.stats.chunks);
+            return Some((chunk, false));
+        }
+        if self.fade.processed(&self.gain, false) {
+            // nothing left to fade
+            self.finish_stop();
+        } else if self.sync.drain_signal.load(Ordering::Relaxed) {
+            if !self.active {
+                debug!("PB MIX: drain requested in stopped mixed line, nothing to render");
+                self.starved_stop = false;
+                self.finish_drain();
+            } else if self.drain_target_frames.is_none() {
+                // all data were mixed, waiting for the device to render them, not an underrun
+                debug!("PB MIX: draining, waiting for the device to render {} frames", self.written_frames);
+                self.drain_target_frames = Some(self.written_frames);
+            }
+        } else if self.active && self.drain_target_frames.is_none() {
+            return self.underrun(chunk_frames);
+        }
+        None
+    }
+
+    /// Applies the UnderrunPolicy of the line running out of data
+    fn underrun(&mut self, chunk_frames: usize) -> Option<(Vec<u8>, bool)> {
+        // only valid policies are stored by do_set_underrun_policy
+        let policy = UnderrunPolicy::try_from(self.sync.underrun_policy.load(Ordering::Relaxed)).unwrap_or(UnderrunPolicy::Stop);
+        if policy == UnderrunPolicy::Stop {
+            // counted only when the data resume, running out of data at the end of playback is no underrun
+            debug!("PB MIX: no data, stopping mixed line");
+            self.starved_stop = true;
+            self.active = false;
+            return None;
+        }
+        if self.in_underrun {
+            // the faded chunk is repeated only once, then silence
+            self.sync.underruns.add_frames(chunk_frames);
+            return None;
+        }
+        log_event!(LogEvent::Underrun, Level::Warn, "PB MIX: underrun, mixing {:?} until data available", policy);
+        self.in_underrun = true;
+        self.sync.underruns.record(chunk_frames);
+        match policy {
+            UnderrunPolicy::RepeatFade => self.last_chunk.take().map(|chunk| (chunk, true)),
+            _ => None,
+        }
+    }
+}
+
+/// Owned by the playback loop of the bus owner
+pub struct MixBus {
+    rx_attach: Receiver<MixInput>,
+    inputs: Vec<MixInput>,
+    // shared with MixStream
+    own_closed: Arc<AtomicBool>,
+    // format of the owner chunks
+    sample_bytes: usize,
+    channels: usize,
+    acc: Vec<f64>,
+    // input chunk converted to the owner format
+    converted: Vec<u8>,
+    limiter: Limiter,
+}
+
+impl MixBus {
+    pub fn new(rx_attach: Receiver<MixInput>, own_closed: Arc<AtomicBool>, sample_bytes: usize, channels: usize,
+               chunk_frames: usize) -> Self {
+        MixBus {
+            rx_attach,
+            inputs: Vec::new(),
+            own_closed,
+            sample_bytes,
+            channels,
+            acc: vec![0.0; chunk_frames * channels],
+            converted: vec![0u8; chunk_frames * channels * sample_bytes],
+            limiter: Limiter::new(),
+        }
+    }
+
+    /// Attaches new lines and handles the signals of the attached ones.
+    /// Returns true if some attached line is playing, i.e. the stream must keep running.
+    pub fn service(&mut self) -> bool {
+        for input in self.rx_attach.try_iter() {
+            debug!("PB MIX: attaching new line, {} lines mixed", self.inputs.len() + 2);
+            self.inputs.push(input);
+        }
+        self.inputs.retain_mut(|input| input.handle_signals());
+        // chunks written to a stopped line start it automatically
+        self.inputs.iter().any(|input| input.active || !input.sync.rx_dev.is_empty())
+    }
+
+    /// The owner line was closed while mixed lines stay open
+    pub fn own_closed(&self) -> bool {
+        self.own_closed.load(Ordering::Relaxed)
+    }
+
+    /// Mixes the owner chunk with the chunks of the attached lines into out, the chunks carry the mix gains of their lines.
+    /// written_frames are the frames written to the stream before this chunk, since its start/reset.
+    /// Returns false if the owner chunk can be written unchanged.
+    pub fn mix(&mut self, own: &[u8], out: &mut [u8], written_frames: u64) -> bool {
+        if self.inputs.is_empty() {
+            return false;
+        }
+        let chunk_frames = self.acc.len() / self.channels;
+        self.acc.fill(0.0);
+        add_samples(&mut self.acc, own, self.sample_bytes, 1.0);
+        for input in self.inputs.iter_mut() {
+            if let Some((mut chunk, repeated)) = input.next_chunk(chunk_frames) {
+                input.gain.process(&mut chunk);
+                if input.fade.processed(&input.gain, true) {
+                    input.finish_stop();
+                }
+                input.converter.convert(&chunk, &mut self.converted);
+                if repeated {
+                    apply_ramp(&mut self.converted, self.sample_bytes, self.channels, 1.0, 0.0);
+                } else {
+                    input.last_chunk = Some(chunk);
+                }
+                add_samples(&mut self.acc, &self.converted, self.sample_bytes, 1.0);
+                input.written_frames = written_frames + chunk_frames as u64;
+            }
+        }
+        // limiter ramped within the chunk
+        let prev_gain = self.limiter.gain();
+        let (start_gain, end_gain) = self.limiter.next_gains(&self.acc);
+        if start_gain < 1.0 && prev_gain == 1.0 {
+            warn!("PB MIX: mixed lines exceed full scale, limiting by {:.1} dB", 20.0 * start_gain.log10());
+        }
+        write_samples(&self.acc, out, self.sample_bytes, self.channels, start_gain, end_gain);
+        true
+    }
+
+    /// Confirms the drains of the lines whose last chunk the device has rendered, or of all when the stream stopped
+    pub fn rendered(&mut self, rendered_frames: u64, running: bool) {
+        for input in self.inputs.iter_mut() {
+            if let Some(target_frames) = input.drain_target_frames {
+                if rendered_frames >= target_frames || !running {
+                    debug!("PB MIX: mixed line drained, device rendered {} frames of {}", rendered_frames, target_frames);
+                    input.finish_drain();
+                    input.active = false;
+                }
+            }
+        }
+    }
+
+    /// The stream was reset, its clock position restarts from zero
+    pub fn stream_reset(&mut self) {
+        for input in self.inputs.iter_mut() {
+            input.written_frames = 0;
+            if input.drain_target_frames.is_some() {
+                // unplayed data were discarded by the reset
+                input.drain_target_frames = Some(0);
+            }
+        }
+    }
+}
+
+impl Drop for MixBus {
+    fn drop(&mut self) {
+        // lines not closed yet cannot play anymore
+        for input in self.inputs.iter() {
+            input.detached.store(true, Ordering::Relaxed);
+        }
+        for input in self.rx_attach.try_iter() {
+            input.detached.store(true, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use log::{debug, warn};
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::System::Threading::{AVRT_PRIORITY, AvRevertMmThreadCharacteristics, AvSetMmThreadCharacteristicsW,
+                                        AvSetMmThreadPriority, GetCurrentThread, SetThreadAffinityMask};
+
+use crate::error::{NativeError, Res};
+
+// Real-time settings of the inner device threads: MMCSS task, MMCSS priority, CPU affinity
+
+// same constants as in the java provider
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MmcssTask {
+    ProAudio = 0,
+    Audio = 1,
+    Playback = 2,
+    Capture = 3,
+}
+
+impl MmcssTask {
+    fn name(&self) -> &'static str {
+        match self {
+            MmcssTask::ProAudio => "Pro Audio",
+            MmcssTask::Audio => "Audio",
+            MmcssTask::Playback => "Playback",
+            MmcssTask::Capture => "Capture",
+        }
+    }
+}
+
+impl TryFrom<usize> for MmcssTask {
+    type Error = NativeError;
+
+    fn try_from(id: usize) -> Res<Self> {
+        match id {
+            0 => Ok(MmcssTask::ProAudio),
+            1 => Ok(MmcssTask::Audio),
+            2 => Ok(MmcssTask::Playback),
+            3 => Ok(MmcssTask::Capture),
+            _ => Err(NativeError::illegal_argument(&format!("Unknown MMCSS task {}", id))),
+        }
+    }
+}
+
+// same constants as in the java provider, 0 = keeping the MMCSS default
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MmcssPriority {
+    Default = 0,
+    VeryLow = 1,
+    Low = 2,
+    Normal = 3,
+    High = 4,
+    Critical = 5,
+}
+
+impl MmcssPriority {
+    fn to_avrt(self) -> Option<AVRT_PRIORITY> {
+        match self {
+            MmcssPriority::Default => None,
+            // AVRT_PRIORITY_VERYLOW = -2 ... AVRT_PRIORITY_CRITICAL = 2
+            priority => Some(AVRT_PRIORITY(priority as i32 - 3)),
+        }
+    }
+}
+
+impl TryFrom<usize> for MmcssPriority {
+    type Error = NativeError;
+
+    fn try_from(id: usize) -> Res<Self> {
+        match id {
+            0 => Ok(MmcssPriority::Default),
+            1 => Ok(MmcssPriority::VeryLow),
+            2 => Ok(MmcssPriority::Low),
+            3 => Ok(MmcssPriority::Normal),
+            4 => Ok(MmcssPriority::High),
+            5 => Ok(MmcssPriority::Critical),
+            _ => Err(NativeError::illegal_argument(&format!("Unknown MMCSS priority {}", id))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadConfig {
+    pub task: MmcssTask,
+    pub priority: MmcssPriority,
+    // 0 = no affinity change
+    pub affinity_mask: u64,
+}
+
+impl Default for ThreadConfig {
+    fn default() -> Self {
+        ThreadConfig { task: MmcssTask::ProAudio, priority: MmcssPriority::Default, affinity_mask: 0 }
+    }
+}
+
+/// Settings really applied to the inner thread, readable by the outer side
+#[derive(Default)]
+pub struct ThreadStatus {
+    // 0 = MMCSS registration failed or not done yet
+    task_idx: AtomicU32,
+    // MmcssPriority id, 0 = default or failed
+    priority: AtomicU32,
+    // 0 = no affinity or failed
+    affinity_mask: AtomicU64,
+}
+
+impl ThreadStatus {
+    /// (MMCSS task index, priority id, affinity mask)
+    pub fn snapshot(&self) -> (u32, u32, u64) {
+        (self.task_idx.load(Ordering::Relaxed),
+         self.priority.load(Ordering::Relaxed),
+         self.affinity_mask.load(Ordering::Relaxed))
+    }
+}
+
+/// MMCSS registration of the current thread, reverted on drop
+pub struct ThreadBoost {
+    handle: Option<HANDLE>,
+}
+
+impl ThreadBoost {
+    pub fn new() -> Self {
+        ThreadBoost { handle: None }
+    }
+
+    /// (Re)applies the config to the current thread, reporting the result to status
+    pub fn apply(&mut self, config: &ThreadConfig, status: &ThreadStatus, log_prefix: &str) {
+        self.revert();
+        let mut task_idx = 0;
+        let result = unsafe {
+            AvSetMmThreadCharacteristicsW(PCWSTR::from(&config.task.name().into()), &mut task_idx)
+        };
+        match result {
+            Ok(handle) => {
+                debug!("{}: thread raised priority, MMCSS task {:?}, task index: {}", log_prefix, config.task, task_idx);
+                self.handle = Some(handle);
+            }
+            Err(err) => {
+                warn!("{}: Failed to raise thread priority with MMCSS task {:?}: {}", log_prefix, config.task, err);
+                task_idx = 0;
+            }
+        }
+        status.task_idx.store(task_idx, Ordering::Relaxed);
+
+        let mut applied_priority = MmcssPriority::Default;
+        if let (Some(handle), Some(avrt_priority)) = (self.handle, config.priority.to_avrt()) {
+            if unsafe { AvSetMmThreadPriority(handle, avrt_priority) }.as_bool() {
+                debug!("{}: MMCSS priority set to {:?}", log_prefix, config.priority);
+                applied_priority = config.priority;
+            } else {
+                warn!("{}: Failed to set MMCSS priority {:?}", log_prefix, config.priority);
+            }
+        }
+        status.priority.store(applied_priority as u32, Ordering::Relaxed);
+
+        let mut applied_mask = 0;
+        if config.affinity_mask != 0 {
+            let prev_mask = unsafe { SetThreadAffinityMask(GetCurrentThread(), config.affinity_mask as usize) };
+            if prev_mask != 0 {
+                debug!("{}: CPU affinity set to {:#x}, was {:#x}", log_prefix, config.affinity_mask, prev_mask);
+                applied_mask = config.affinity_mask;
+            } else {
+                warn!("{}: Failed to set CPU affinity {:#x}", log_prefix, config.affinity_mask);
+            }
+        }
+        status.affinity_mask.store(applied_mask, Ordering::Relaxed);
+    }
+
+    fn revert(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            unsafe { AvRevertMmThreadCharacteristics(handle) };
+        }
+    }
+}
+
+impl Drop for ThreadBoost {
+    fn drop(&mut self) {
+        self.revert();
+    }
+}
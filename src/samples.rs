@@ -0,0 +1,306 @@
+// Processing of raw interleaved little-endian signed integer samples as used in the exclusive mode and by java,
+// and their conversion from/to the shared-mode engine format.
+// Samples narrower than 32 bits are left-aligned to i32 so that one code path handles 16, 24 and 24-in-32 bits.
+
+/// reads one sample of sample_bytes (2, 3 or 4) as left-aligned i32
+fn read_sample(bytes: &[u8]) -> i32 {
+    match bytes.len() {
+        2 => i32::from_le_bytes([0, 0, bytes[0], bytes[1]]),
+        3 => i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]),
+        _ => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    }
+}
+
+/// writes left-aligned i32 back to sample_bytes (2, 3 or 4), keeping only the upper bytes
+fn write_sample(bytes: &mut [u8], value: i32) {
+    let le = value.to_le_bytes();
+    let sample_bytes = bytes.len();
+    bytes.copy_from_slice(&le[4 - sample_bytes..]);
+}
+
+/// Multiplies samples by gain linearly changing from start_gain at the first frame to end_gain after the last frame.
+/// Integer samples only (the line formats), float samples of the engine format must never be passed.
+pub fn apply_ramp(data: &mut [u8], sample_bytes: usize, channels: usize, start_gain: f64, end_gain: f64) {
+    let frame_bytes = sample_bytes * channels;
+    let frames = data.len() / frame_bytes;
+    if frames == 0 {
+        return;
+    }
+    let step = (end_gain - start_gain) / frames as f64;
+    for (idx, frame) in data.chunks_exact_mut(frame_bytes).enumerate() {
+        let gain = start_gain + step * idx as f64;
+        for sample in frame.chunks_exact_mut(sample_bytes) {
+            let value = read_sample(sample) as f64 * gain;
+            write_sample(sample, value as i32);
+        }
+    }
+}
+
+/// Adds samples multiplied by gain to the accumulator, in the left-aligned i32 scale.
+pub fn add_samples(acc: &mut [f64], data: &[u8], sample_bytes: usize, gain: f64) {
+    for (sum, sample) in acc.iter_mut().zip(data.chunks_exact(sample_bytes)) {
+        *sum += read_sample(sample) as f64 * gain;
+    }
+}
+
+/// Writes the accumulated samples multiplied by gain linearly changing from start_gain to end_gain, clipped to full scale.
+pub fn write_samples(acc: &[f64], data: &mut [u8], sample_bytes: usize, channels: usize, start_gain: f64, end_gain: f64) {
+    let frames = acc.len() / channels;
+    if frames == 0 {
+        return;
+    }
+    let step = (end_gain - start_gain) / frames as f64;
+    let frame_bytes = sample_bytes * channels;
+    for (idx, (sums, frame)) in acc.chunks_exact(channels).zip(data.chunks_exact_mut(frame_bytes)).enumerate() {
+        let gain = start_gain + step * idx as f64;
+        for (sum, sample) in sums.iter().zip(frame.chunks_exact_mut(sample_bytes)) {
+            let value = (sum * gain).clamp(i32::MIN as f64, i32::MAX as f64);
+            write_sample(sample, value as i32);
+        }
+    }
+}
+
+/// Clears the bits below valid_bits of samples in containers wider than valid_bits (e.g. 24-in-32)
+pub fn truncate_to_valid_bits(data: &mut [u8], sample_bytes: usize, valid_bits: usize) {
+    let container_bits = 8 * sample_bytes;
+    if valid_bits == 0 || valid_bits >= container_bits {
+        return;
+    }
+    let mask = !((1i32 << (32 - valid_bits)) - 1);
+    for sample in data.chunks_exact_mut(sample_bytes) {
+        write_sample(sample, read_sample(sample) & mask);
+    }
+}
+
+// fraction of the limiter gain reduction recovered per chunk
+const LIMITER_RELEASE: f64 = 0.05;
+// limiter gain considered fully released
+const LIMITER_RELEASED: f64 = 0.9999;
+// full scale of the left-aligned i32 samples
+const FULL_SCALE: f64 = i32::MAX as f64;
+
+/// Peak limiter of accumulated samples: instant attack to keep the chunk peak at full scale, gradual release
+pub struct Limiter {
+    gain: f64,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Limiter { gain: 1.0 }
+    }
+
+    /// Gain applied at the end of the previous chunk
+    pub fn gain(&self) -> f64 {
+        self.gain
+    }
+
+    /// (start gain, end gain) of the ramp to apply to the chunk
+    pub fn next_gains(&mut self, acc: &[f64]) -> (f64, f64) {
+        let peak = acc.iter().fold(0.0f64, |peak, sum| peak.max(sum.abs()));
+        let target_gain = if peak > FULL_SCALE { FULL_SCALE / peak } else { 1.0 };
+        let start_gain = self.gain.min(target_gain);
+        let mut end_gain = (self.gain + (1.0 - self.gain) * LIMITER_RELEASE).min(target_gain);
+        if end_gain > LIMITER_RELEASED {
+            end_gain = 1.0;
+        }
+        self.gain = end_gain;
+        (start_gain, end_gain)
+    }
+}
+
+/// Sample format of the device side in shared mode (engine mix format)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DevSampleFormat {
+    // signed integer with the given sample bytes
+    Int(usize),
+    Float32,
+}
+
+impl DevSampleFormat {
+    fn sample_bytes(&self) -> usize {
+        match self {
+            DevSampleFormat::Int(bytes) => *bytes,
+            DevSampleFormat::Float32 => 4,
+        }
+    }
+
+    /// reads one sample as left-aligned i32, float samples are clipped to full scale
+    fn read(&self, bytes: &[u8]) -> i32 {
+        match self {
+            DevSampleFormat::Int(_) => read_sample(bytes),
+            DevSampleFormat::Float32 => {
+                let value = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                // saturating cast
+                (value as f64 * 2_147_483_648.0).round() as i32
+            }
+        }
+    }
+
+    fn write(&self, bytes: &mut [u8], value: i32) {
+        match self {
+            DevSampleFormat::Int(_) => write_sample(bytes, value),
+            DevSampleFormat::Float32 => {
+                let float_value = value as f32 / 2_147_483_648.0;
+                bytes.copy_from_slice(&float_value.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Converts java frames (signed integer) to the shared-mode engine format and back, incl. channel mapping.
+#[derive(Debug)]
+pub struct SampleConverter {
+    src_format: DevSampleFormat,
+    src_channels: usize,
+    dst_format: DevSampleFormat,
+    dst_channels: usize,
+    // clears the bits below the valid bits of the java format
+    dst_mask: i32,
+}
+
+impl SampleConverter {
+    /// From java frames to the device format (playback)
+    pub fn new(src_sample_bytes: usize, src_channels: usize, dst_format: DevSampleFormat, dst_channels: usize) -> Self {
+        SampleConverter { src_format: DevSampleFormat::Int(src_sample_bytes), src_channels, dst_format, dst_channels, dst_mask: !0 }
+    }
+
+    /// From the device format to java frames (capture)
+    pub fn from_device(src_format: DevSampleFormat, src_channels: usize, dst_sample_bytes: usize, dst_valid_bits: usize,
+                       dst_channels: usize) -> Self {
+        let dst_mask = (!0u32 << (32 - dst_valid_bits.clamp(1, 32))) as i32;
+        SampleConverter { src_format, src_channels, dst_format: DevSampleFormat::Int(dst_sample_bytes), dst_channels, dst_mask }
+    }
+
+    pub fn src_frame_bytes(&self) -> usize {
+        self.src_format.sample_bytes() * self.src_channels
+    }
+
+    pub fn dst_frame_bytes(&self) -> usize {
+        self.dst_format.sample_bytes() * self.dst_channels
+    }
+
+    /// Source channel value for the destination channel:
+    /// same channel if available, mono source duplicated, downmix to mono averaged, missing channels silent
+    fn map_channel(&self, src_frame: &[u8], dst_ch: usize) -> i32 {
+        let src_sample_bytes = self.src_format.sample_bytes();
+        let sample_at = |ch: usize| self.src_format.read(&src_frame[ch * src_sample_bytes..(ch + 1) * src_sample_bytes]);
+        if self.dst_channels == 1 && self.src_channels > 1 {
+            let sum: i64 = (0..self.src_channels).map(|ch| sample_at(ch) as i64).sum();
+            (sum / self.src_channels as i64) as i32
+        } else if dst_ch < self.src_channels {
+            sample_at(dst_ch)
+        } else if self.src_channels == 1 {
+            sample_at(0)
+        } else {
+            0
+        }
+    }
+
+    /// Converts all complete frames of src into dst, returns number of converted frames
+    pub fn convert(&self, src: &[u8], dst: &mut [u8]) -> usize {
+        let src_frame_bytes = self.src_frame_bytes();
+        let dst_frame_bytes = self.dst_frame_bytes();
+        let dst_sample_bytes = self.dst_format.sample_bytes();
+        let mut frames = 0;
+        for (src_frame, dst_frame) in src.chunks_exact(src_frame_bytes).zip(dst.chunks_exact_mut(dst_frame_bytes)) {
+            for (dst_ch, dst_sample) in dst_frame.chunks_exact_mut(dst_sample_bytes).enumerate() {
+                let value = self.map_channel(src_frame, dst_ch);
+                self.dst_format.write(dst_sample, value & self.dst_mask);
+            }
+            frames += 1;
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_bytes_16(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|sample| sample.to_le_bytes()).collect()
+    }
+
+    fn from_bytes_16(data: &[u8]) -> Vec<i16> {
+        data.chunks_exact(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]])).collect()
+    }
+
+    #[test]
+    fn convert_int_float_roundtrip() {
+        let samples = [0i16, 1, -1, 12345, -12345, i16::MAX, i16::MIN, 77];
+        let src = to_bytes_16(&samples);
+        let to_dev = SampleConverter::new(2, 2, DevSampleFormat::Float32, 2);
+        let mut dev = vec![0u8; samples.len() * 4];
+        assert_eq!(to_dev.convert(&src, &mut dev), 4);
+        let from_dev = SampleConverter::from_device(DevSampleFormat::Float32, 2, 2, 16, 2);
+        let mut dst = vec![0u8; src.len()];
+        assert_eq!(from_dev.convert(&dev, &mut dst), 4);
+        assert_eq!(dst, src);
+    }
+
+    #[test]
+    fn convert_maps_channels() {
+        let mono = SampleConverter::new(2, 1, DevSampleFormat::Int(2), 2);
+        let mut dst = vec![0u8; 8];
+        mono.convert(&to_bytes_16(&[100, -200]), &mut dst);
+        assert_eq!(from_bytes_16(&dst), [100, 100, -200, -200]);
+
+        let downmix = SampleConverter::new(2, 2, DevSampleFormat::Int(2), 1);
+        let mut dst = vec![0u8; 4];
+        downmix.convert(&to_bytes_16(&[100, 300, -100, -300]), &mut dst);
+        assert_eq!(from_bytes_16(&dst), [200, -200]);
+
+        let missing = SampleConverter::new(2, 2, DevSampleFormat::Int(2), 4);
+        let mut dst = vec![0xffu8; 8];
+        missing.convert(&to_bytes_16(&[1, 2]), &mut dst);
+        assert_eq!(from_bytes_16(&dst), [1, 2, 0, 0]);
+    }
+
+    #[test]
+    fn convert_clears_bits_below_valid_bits() {
+        let converter = SampleConverter::from_device(DevSampleFormat::Int(4), 1, 4, 24, 1);
+        let mut dst = vec![0u8; 4];
+        converter.convert(&0x1234_5678i32.to_le_bytes(), &mut dst);
+        assert_eq!(i32::from_le_bytes([dst[0], dst[1], dst[2], dst[3]]), 0x1234_5600);
+    }
+
+    #[test]
+    fn convert_clips_float_over_full_scale() {
+        let converter = SampleConverter::from_device(DevSampleFormat::Float32, 1, 2, 16, 1);
+        let src: Vec<u8> = [1.5f32, -1.5].iter().flat_map(|value| value.to_le_bytes()).collect();
+        let mut dst = vec![0u8; 4];
+        converter.convert(&src, &mut dst);
+        assert_eq!(from_bytes_16(&dst), [i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn write_samples_ramps_and_clips() {
+        let mut acc = vec![0.0; 4];
+        add_samples(&mut acc, &to_bytes_16(&[1000, 1000, 1000, 1000]), 2, 1.0);
+        let mut data = vec![0u8; 8];
+        write_samples(&acc, &mut data, 2, 1, 1.0, 0.0);
+        assert_eq!(from_bytes_16(&data), [1000, 750, 500, 250]);
+
+        let acc = [2.0 * FULL_SCALE, -2.0 * FULL_SCALE];
+        let mut data = vec![0u8; 4];
+        write_samples(&acc, &mut data, 2, 2, 1.0, 1.0);
+        assert_eq!(from_bytes_16(&data), [i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn limiter_attacks_instantly_and_releases_gradually() {
+        let mut limiter = Limiter::new();
+        assert_eq!(limiter.next_gains(&[0.5 * FULL_SCALE]), (1.0, 1.0));
+        assert_eq!(limiter.next_gains(&[-2.0 * FULL_SCALE]), (0.5, 0.5));
+        let (start_gain, end_gain) = limiter.next_gains(&[0.0]);
+        assert_eq!(start_gain, 0.5);
+        assert!((end_gain - (0.5 + 0.5 * LIMITER_RELEASE)).abs() < 1e-12);
+        let mut chunks = 0;
+        while limiter.gain() < 1.0 {
+            limiter.next_gains(&[0.0]);
+            chunks += 1;
+            assert!(chunks < 1000, "limiter never released");
+        }
+        assert_eq!(limiter.gain(), 1.0);
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Fixed-bucket timing histograms of the inner loop phases, updated by the inner thread without locking.
+
+// upper bucket bounds in microseconds, the last bucket collects everything above
+pub const BUCKET_BOUNDS_US: [u64; 11] = [50, 100, 250, 500, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000];
+pub const BUCKETS: usize = BUCKET_BOUNDS_US.len() + 1;
+
+#[derive(Debug, Clone, Copy)]
+pub enum TimingPhase {
+    // loop time outside of wait_for_event and the device transfer
+    Outside = 0,
+    // writing to (playback) / reading from (capture) the device incl. passing the chunk
+    Transfer = 1,
+    // blocked in wait_for_event
+    Wait = 2,
+    // deviation of the interval between consecutive events from the device period
+    Jitter = 3,
+}
+
+pub const PHASES: [TimingPhase; 4] = [TimingPhase::Outside, TimingPhase::Transfer, TimingPhase::Wait, TimingPhase::Jitter];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Histogram {
+    fn record(&self, us: u64) {
+        let idx = BUCKET_BOUNDS_US.iter().position(|&bound| us < bound).unwrap_or(BUCKETS - 1);
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> [u64; BUCKETS] {
+        let mut counts = [0; BUCKETS];
+        for (count, bucket) in counts.iter_mut().zip(self.buckets.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        counts
+    }
+}
+
+#[derive(Default)]
+pub struct LoopTimings {
+    histograms: [Histogram; PHASES.len()],
+}
+
+impl LoopTimings {
+    pub fn record(&self, phase: TimingPhase, elapsed: Duration) {
+        self.histograms[phase as usize].record(elapsed.as_micros() as u64);
+    }
+
+    /// Records the event interval deviation from the expected device period
+    pub fn record_interval(&self, interval: Duration, period: Duration) {
+        let jitter = if interval > period { interval - period } else { period - interval };
+        self.record(TimingPhase::Jitter, jitter);
+    }
+
+    /// Bucket counts of all phases, in PHASES order
+    pub fn snapshot(&self) -> Vec<u64> {
+        self.histograms.iter().flat_map(|histogram| histogram.snapshot()).collect()
+    }
+
+    /// One line per phase, for logging
+    pub fn format(&self) -> Vec<String> {
+        PHASES.iter().map(|&phase| {
+            let counts = self.histograms[phase as usize].snapshot();
+            let mut line = format!("{:?}:", phase);
+            for (idx, count) in counts.iter().enumerate() {
+                match BUCKET_BOUNDS_US.get(idx) {
+                    Some(bound) => write!(line, " <{}us: {}", bound, count).unwrap(),
+                    None => write!(line, " >={}us: {}", BUCKET_BOUNDS_US[idx - 1], count).unwrap(),
+                }
+            }
+            line
+        }).collect()
+    }
+}
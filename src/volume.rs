@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::ptr;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+
+use crossbeam_channel::{bounded, Receiver, Sender, unbounded};
+use jni::JNIEnv;
+use jni::objects::{JClass, JObject, JValue};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use windows::core::{GUID, HSTRING, implement};
+use windows::Win32::Media::Audio::{AUDIO_VOLUME_NOTIFICATION_DATA, IMMDevice, IMMDeviceEnumerator, MMDeviceEnumerator};
+use windows::Win32::Media::Audio::Endpoints::{IAudioEndpointVolume, IAudioEndpointVolumeCallback, IAudioEndpointVolumeCallback_Impl};
+use windows::Win32::System::Com::{CLSCTX_ALL, CoCreateInstance};
+
+use crate::error::{DeviceContext, NativeError, Res};
+use crate::java_call::StaticCallback;
+use crate::wasapi_impl::{do_initialize_wasapi, get_device_endpoint};
+
+// Hardware volume and mute of the device endpoint (IAudioEndpointVolume). Exclusive streams bypass the engine mixer,
+// the endpoint volume is the only volume control left for them (applied by the driver/hardware if supported,
+// by the engine otherwise, i.e. without effect on exclusive streams).
+// Changes are reported to the java provider by a watcher thread per device:
+// static void SimpleMixer.onEndpointVolume(String deviceID, float volumeDb, boolean mute, float[] channelsDb)
+
+const NOTIFY_METHOD: &str = "onEndpointVolume";
+const NOTIFY_METHOD_SIG: &str = "(Ljava/lang/String;FZ[F)V";
+
+// event context of changes made by this library, not reported back to java
+const EVENT_CONTEXT: GUID = GUID::from_u128(0x5c0b_1f3e_8a47_4d2b_9e61_c2a4_7f3d_0b95);
+
+/// Current endpoint volume in dB
+pub struct EndpointVolume {
+    pub volume_db: f32,
+    pub min_db: f32,
+    pub max_db: f32,
+    pub step_db: f32,
+    pub mute: bool,
+    pub channels_db: Vec<f32>,
+}
+
+/// Opens the endpoint volume of the mixer device, the interface is bound to the calling (COM initialized) thread
+fn open_endpoint(device_id: &str) -> Res<(IAudioEndpointVolume, String)> {
+    let (endpoint_id, device_name) = get_device_endpoint(device_id)?;
+    let endpoint = unsafe {
+        let enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+        let device: IMMDevice = enumerator.GetDevice(&HSTRING::from(endpoint_id))?;
+        device.Activate::<IAudioEndpointVolume>(CLSCTX_ALL, ptr::null())
+    }.device_ctx(&device_name)?;
+    Ok((endpoint, device_name))
+}
+
+fn read_volume(endpoint: &IAudioEndpointVolume) -> windows::core::Result<EndpointVolume> {
+    let (mut min_db, mut max_db, mut step_db) = (0.0f32, 0.0f32, 0.0f32);
+    unsafe {
+        endpoint.GetVolumeRange(&mut min_db, &mut max_db, &mut step_db)?;
+        let channels = endpoint.GetChannelCount()?;
+        let channels_db = (0..channels)
+            .map(|ch| endpoint.GetChannelVolumeLevel(ch))
+            .collect::<windows::core::Result<Vec<f32>>>()?;
+        Ok(EndpointVolume {
+            volume_db: endpoint.GetMasterVolumeLevel()?,
+            min_db,
+            max_db,
+            step_db,
+            mute: endpoint.GetMute()?.as_bool(),
+            channels_db,
+        })
+    }
+}
+
+fn check_range(endpoint: &IAudioEndpointVolume, volume_db: f32) -> Res<()> {
+    let (mut min_db, mut max_db, mut step_db) = (0.0f32, 0.0f32, 0.0f32);
+    unsafe { endpoint.GetVolumeRange(&mut min_db, &mut max_db, &mut step_db)? };
+    if !(min_db..=max_db).contains(&volume_db) {
+        let msg = format!("Volume {} dB outside of the endpoint range {} to {} dB", volume_db, min_db, max_db);
+        return Err(NativeError::illegal_argument(&msg));
+    }
+    Ok(())
+}
+
+pub fn get_endpoint_volume(device_id: &str) -> Res<EndpointVolume> {
+    let (endpoint, device_name) = open_endpoint(device_id)?;
+    read_volume(&endpoint).device_ctx(&device_name)
+}
+
+pub fn set_endpoint_volume(device_id: &str, volume_db: f32) -> Res<()> {
+    let (endpoint, device_name) = open_endpoint(device_id)?;
+    check_range(&endpoint, volume_db).device_ctx(&device_name)?;
+    debug!("Device {}: setting endpoint volume {} dB", device_name, volume_db);
+    unsafe { endpoint.SetMasterVolumeLevel(volume_db, &EVENT_CONTEXT) }.device_ctx(&device_name)
+}
+
+pub fn set_endpoint_channel_volume(device_id: &str, channel: usize, volume_db: f32) -> Res<()> {
+    let (endpoint, device_name) = open_endpoint(device_id)?;
+    let channels = unsafe { endpoint.GetChannelCount() }.device_ctx(&device_name)? as usize;
+    if channel >= channels {
+        let msg = format!("Channel {} outside of the {} endpoint channels", channel, channels);
+        return Err(NativeError::illegal_argument(&msg).with_device(&device_name));
+    }
+    check_range(&endpoint, volume_db).device_ctx(&device_name)?;
+    debug!("Device {}: setting endpoint channel {} volume {} dB", device_name, channel, volume_db);
+    unsafe { endpoint.SetChannelVolumeLevel(channel as u32, volume_db, &EVENT_CONTEXT) }.device_ctx(&device_name)
+}
+
+pub fn set_endpoint_mute(device_id: &str, mute: bool) -> Res<()> {
+    let (endpoint, device_name) = open_endpoint(device_id)?;
+    debug!("Device {}: setting endpoint mute {}", device_name, mute);
+    unsafe { endpoint.SetMute(mute, &EVENT_CONTEXT) }.device_ctx(&device_name)
+}
+
+enum WatchMsg {
+    Changed,
+    Stop,
+}
+
+/// Called by the endpoint on a system thread, only passes the change to the watcher thread
+#[implement(IAudioEndpointVolumeCallback)]
+struct VolumeCallback {
+    tx_watch: Sender<WatchMsg>,
+}
+
+impl IAudioEndpointVolumeCallback_Impl for VolumeCallback {
+    fn OnNotify(&self, pnotify: *mut AUDIO_VOLUME_NOTIFICATION_DATA) -> windows::core::Result<()> {
+        let own_change = unsafe { pnotify.as_ref() }.map_or(false, |data| data.guidEventContext == EVENT_CONTEXT);
+        if !own_change {
+            self.tx_watch.send(WatchMsg::Changed).unwrap_or(());
+        }
+        Ok(())
+    }
+}
+
+/// Calls the java provider from the watcher thread, attached to the JVM as daemon
+pub struct VolumeNotifier {
+    callback: StaticCallback,
+}
+
+impl VolumeNotifier {
+    /// mixer_class: the java class receiving the changes, must be resolved in a java thread
+    pub fn new(env: JNIEnv, mixer_class: JClass) -> Res<Self> {
+        let callback = StaticCallback::new(env, mixer_class, NOTIFY_METHOD, NOTIFY_METHOD_SIG)?;
+        Ok(VolumeNotifier { callback })
+    }
+
+    fn notify(&self, device_id: &str, volume: &EndpointVolume) {
+        let env = match self.callback.attach() {
+            Ok(env) => env,
+            Err(err) => {
+                warn!("Failed to attach volume watcher to JVM: {}", err);
+                return;
+            }
+        };
+        let result = self.callback.call(env, |env| {
+            let jchannels = env.new_float_array(volume.channels_db.len() as i32)?;
+            env.set_float_array_region(jchannels, 0, &volume.channels_db)?;
+            Ok([JValue::from(env.new_string(device_id)?), JValue::Float(volume.volume_db),
+                JValue::Bool(volume.mute as u8), JValue::from(JObject::from(jchannels))])
+        });
+        if let Err(err) = result {
+            warn!("Failed to report endpoint volume change of device {} to JVM: {}", device_id, err);
+        }
+    }
+}
+
+struct Watch {
+    tx_watch: Sender<WatchMsg>,
+    handle: JoinHandle<()>,
+}
+
+lazy_static! {
+    // watcher threads keyed by the mixer device ID
+    static ref WATCHES: Mutex<HashMap<String, Watch>> = Mutex::new(HashMap::new());
+}
+
+/// Starts reporting endpoint volume changes of the device to the notifier, or stops with None
+pub fn watch_endpoint_volume(device_id: String, notifier: Option<VolumeNotifier>) -> Res<()> {
+    // the watcher thread may be reporting a change to java, not joining it under the lock
+    let old_watch = WATCHES.lock()?.remove(&device_id);
+    if let Some(watch) = old_watch {
+        stop_watch(watch);
+    }
+    let notifier = match notifier {
+        Some(notifier) => notifier,
+        None => return Ok(()),
+    };
+    let (tx_watch, rx_watch) = unbounded();
+    let (tx_started, rx_started) = bounded(1);
+    let tx_cb = tx_watch.clone();
+    let device_id_cloned = device_id.clone();
+    let handle = thread::Builder::new()
+        .name("WasapiVolumeWatch".to_string())
+        .spawn(move || {
+            // the endpoint interface lives in this thread
+            let registered = do_initialize_wasapi().and_then(|_| register_callback(&device_id_cloned, tx_cb));
+            let (endpoint, callback) = match registered {
+                Ok(registered) => {
+                    tx_started.send(Ok(())).unwrap_or(());
+                    registered
+                }
+                Err(err) => {
+                    tx_started.send(Err(err)).unwrap_or(());
+                    return;
+                }
+            };
+            watch_loop(&device_id_cloned, &endpoint, &rx_watch, &notifier);
+            if let Err(err) = unsafe { endpoint.UnregisterControlChangeNotify(&callback) } {
+                warn!("Device {}: failed to unregister endpoint volume callback: {}", device_id_cloned, err);
+            }
+        })?;
+    rx_started.recv()??;
+    let replaced = WATCHES.lock()?.insert(device_id, Watch { tx_watch, handle });
+    if let Some(watch) = replaced {
+        // started by a concurrent call meanwhile
+        stop_watch(watch);
+    }
+    Ok(())
+}
+
+fn stop_watch(watch: Watch) {
+    watch.tx_watch.send(WatchMsg::Stop).unwrap_or(());
+    watch.handle.join().unwrap_or(());
+}
+
+fn register_callback(device_id: &str, tx_watch: Sender<WatchMsg>) -> Res<(IAudioEndpointVolume, IAudioEndpointVolumeCallback)> {
+    let (endpoint, device_name) = open_endpoint(device_id)?;
+    let callback: IAudioEndpointVolumeCallback = VolumeCallback { tx_watch }.into();
+    unsafe { endpoint.RegisterControlChangeNotify(&callback) }.device_ctx(&device_name)?;
+    debug!("Device {}: watching endpoint volume changes", device_name);
+    Ok((endpoint, callback))
+}
+
+fn watch_loop(device_id: &str, endpoint: &IAudioEndpointVolume, rx_watch: &Receiver<WatchMsg>, notifier: &VolumeNotifier) {
+    while let Ok(msg) = rx_watch.recv() {
+        if let WatchMsg::Stop = msg {
+            break;
+        }
+        // turning a knob produces bursts of notifications, reporting only the resulting state
+        if rx_watch.try_iter().any(|msg| matches!(msg, WatchMsg::Stop)) {
+            break;
+        }
+        match read_volume(endpoint) {
+            Ok(volume) => notifier.notify(device_id, &volume),
+            Err(err) => warn!("Device {}: failed to read changed endpoint volume: {}", device_id, err),
+        }
+    }
+    debug!("Device {}: stopped watching endpoint volume changes", device_id);
+}
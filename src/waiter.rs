@@ -0,0 +1,176 @@
+use std::cmp;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::{CloseHandle, HANDLE};
+use windows::Win32::System::Threading::{CreateWaitableTimerExW, SetWaitableTimer, WaitForSingleObject};
+
+use crate::client::EventHandle;
+use crate::error::{NativeError, Res};
+
+// Waiting of the inner loops for the device: on the device event, or by polling the buffer padding on a timer
+// for drivers which do not signal the event (reliably).
+// The polling mode initializes the client without event callbacks (no event handle), for drivers failing
+// the event-driven initialization. The Auto mode keeps the event-driven client and only ignores the event
+// after switching to polling.
+
+// synchapi.h / winnt.h values
+const CREATE_WAITABLE_TIMER_HIGH_RESOLUTION: u32 = 0x2;
+const TIMER_ALL_ACCESS: u32 = 0x1F0003;
+const INFINITE: u32 = 0xFFFFFFFF;
+
+// max. wait for the first event after the stream start in the Auto mode
+const AUTO_EVENT_TIMEOUT: Duration = Duration::from_millis(250);
+// shortest poll interval, the timer resolution is around 0.5 ms
+const MIN_POLL_INTERVAL: Duration = Duration::from_micros(500);
+
+// same constants as in the java provider
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WaitMode {
+    // waiting for the device event
+    Event = 0,
+    // polling the device buffer on a high-resolution timer
+    Poll = 1,
+    // event mode, switching to polling when no event arrives after the stream start
+    Auto = 2,
+}
+
+impl TryFrom<usize> for WaitMode {
+    type Error = NativeError;
+
+    fn try_from(id: usize) -> Res<Self> {
+        match id {
+            0 => Ok(WaitMode::Event),
+            1 => Ok(WaitMode::Poll),
+            2 => Ok(WaitMode::Auto),
+            _ => Err(NativeError::illegal_argument(&format!("Unknown wait mode {}", id))),
+        }
+    }
+}
+
+/// High-resolution waitable timer, falling back to thread::sleep when not supported (before Windows 10 1803)
+pub struct PollTimer {
+    handle: Option<HANDLE>,
+}
+
+impl PollTimer {
+    pub fn new(log_prefix: &str) -> Self {
+        let result = unsafe {
+            CreateWaitableTimerExW(ptr::null(), PCWSTR::null(), CREATE_WAITABLE_TIMER_HIGH_RESOLUTION, TIMER_ALL_ACCESS)
+        };
+        let handle = match result {
+            Ok(handle) => Some(handle),
+            Err(err) => {
+                warn!("{}: High-resolution timer not available, polling with sleep: {}", log_prefix, err);
+                None
+            }
+        };
+        PollTimer { handle }
+    }
+
+    pub fn sleep(&self, duration: Duration) {
+        if let Some(handle) = self.handle {
+            // negative = relative time, in 100ns units
+            let due_time = -((duration.as_nanos() / 100) as i64);
+            if unsafe { SetWaitableTimer(handle, &due_time, 0, None, ptr::null(), false) }.as_bool() {
+                unsafe { WaitForSingleObject(handle, INFINITE) };
+                return;
+            }
+        }
+        sleep(duration);
+    }
+}
+
+impl Drop for PollTimer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            unsafe { CloseHandle(handle) };
+        }
+    }
+}
+
+pub struct DeviceWaiter {
+    // None for a client initialized without event callbacks
+    handle: Option<EventHandle>,
+    // Auto until the first event arrives or times out
+    mode: WaitMode,
+    // mode in use, readable by the outer side
+    used_mode: Arc<AtomicUsize>,
+    poll_interval: Duration,
+    // created on switching to polling
+    timer: Option<PollTimer>,
+    log_prefix: &'static str,
+}
+
+impl DeviceWaiter {
+    /// period: expected interval between device events
+    pub fn new(handle: Option<EventHandle>, used_mode: Arc<AtomicUsize>, period: Duration, log_prefix: &'static str) -> Self {
+        // only valid modes are stored by do_set_wait_mode
+        let mode = match handle {
+            Some(_) => WaitMode::try_from(used_mode.load(Ordering::Relaxed)).unwrap_or(WaitMode::Auto),
+            None => WaitMode::Poll,
+        };
+        let mut waiter = DeviceWaiter {
+            handle,
+            mode,
+            used_mode,
+            poll_interval: cmp::max(period / 4, MIN_POLL_INTERVAL),
+            timer: None,
+            log_prefix,
+        };
+        waiter.set_mode(mode);
+        waiter
+    }
+
+    fn set_mode(&mut self, mode: WaitMode) {
+        self.mode = mode;
+        if mode == WaitMode::Poll && self.timer.is_none() {
+            debug!("{}: polling the device every {:?}", self.log_prefix, self.poll_interval);
+            self.timer = Some(PollTimer::new(self.log_prefix));
+        }
+        self.used_mode.store(mode as usize, Ordering::Relaxed);
+    }
+
+    /// Waits for the device event, or polls is_ready until it returns true.
+    /// Returns false on timeout.
+    pub fn wait<F>(&mut self, timeout: Duration, is_ready: F) -> Res<bool>
+        where F: FnMut() -> Res<bool> {
+        match (self.mode, self.handle.as_ref()) {
+            (WaitMode::Event, Some(handle)) => Ok(handle.wait_for_event(timeout.as_millis() as u32).is_ok()),
+            (WaitMode::Auto, Some(handle)) => {
+                let probe = cmp::min(timeout, AUTO_EVENT_TIMEOUT);
+                if handle.wait_for_event(probe.as_millis() as u32).is_ok() {
+                    debug!("{}: device signals events, using event mode", self.log_prefix);
+                    self.set_mode(WaitMode::Event);
+                    return Ok(true);
+                }
+                warn!("{}: no device event within {:?} after start, switching to polling mode", self.log_prefix, probe);
+                self.set_mode(WaitMode::Poll);
+                self.poll(timeout.saturating_sub(probe), is_ready)
+            }
+            // polling mode, or a client without event callbacks
+            _ => self.poll(timeout, is_ready),
+        }
+    }
+
+    fn poll<F>(&self, timeout: Duration, mut is_ready: F) -> Res<bool>
+        where F: FnMut() -> Res<bool> {
+        let start = Instant::now();
+        loop {
+            if is_ready()? {
+                return Ok(true);
+            }
+            if start.elapsed() >= timeout {
+                return Ok(false);
+            }
+            if let Some(timer) = self.timer.as_ref() {
+                timer.sleep(self.poll_interval);
+            }
+        }
+    }
+}
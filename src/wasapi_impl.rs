@@ -1,30 +1,68 @@
-use std::{error, fmt, thread};
+use std::thread;
 use std::cmp;
-use std::collections::HashSet;
-use std::rc::Rc;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::thread::sleep;
-use std::time::{Duration, Instant};
-
-use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError, unbounded};
-use log::{debug, error, trace, warn};
-use wasapi::{AudioClient, BufferFlags, Device, DeviceCollection, Direction, DisconnectReason, Handle, initialize_sta, ShareMode, WaveFormat};
-use windows::core::PCWSTR;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread::{JoinHandle, sleep};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, SendTimeoutError, TrySendError, unbounded};
+use lazy_static::lazy_static;
+use log::{debug, error, info, Level, trace, warn};
+use wasapi::{AudioClient, Device, DeviceCollection, Direction, initialize_sta, SampleType, ShareMode, WaveFormat};
 use windows::Win32::Foundation::{RPC_E_CHANGED_MODE, S_FALSE};
-use windows::Win32::System::Threading::AvSetMmThreadCharacteristicsW;
-
-use crate::{MixerDesc, Res};
+use windows::Win32::Media::Audio::{AudioSessionDisconnectReason, DisconnectReasonFormatChanged};
+
+use crate::MixerDesc;
+use crate::client::{BufferFlags, EventHandle, StreamClient};
+use crate::drift::{ClockDrift, DriftEstimator};
+use crate::duplex::{DuplexBus, DuplexBusEntry, DuplexCapture, DuplexPosition, get_duplex_bus, get_duplex_device, register_duplex_bus,
+                    set_duplex_device, unregister_duplex_bus};
+use crate::error::{DeviceContext, ErrorKind, NativeError, Res};
 use crate::formats::{Format, get_possible_formats, WV_FMTS_BY_FORMAT};
+use crate::gain::{FadeStop, GainStage, LineGain, MAX_GAIN_DB};
+use crate::logging::{current_stream_context, enter_stream_context, log_event, LogEvent, StreamLogContext};
+use crate::mixer::{get_mix_bus, MAX_MIX_LINES, MixBus, MixBusEntry, MixInput, MixStream, register_mix_bus, unregister_mix_bus};
+use crate::mmcss::{ThreadBoost, ThreadConfig, ThreadStatus};
+use crate::samples::{apply_ramp, DevSampleFormat, SampleConverter};
+use crate::timing::{LoopTimings, TimingPhase};
+use crate::waiter::{DeviceWaiter, WaitMode};
 
 // defined in JAVA
 const NOT_SPECIFIED: i32 = -1;
 
+// max. time for the inner thread to confirm the flush
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(1000);
+// max. time for the inner thread to stop the stream and release the device
+pub const CLOSE_TIMEOUT: Duration = Duration::from_millis(2000);
+// blocking do_write/do_read check for pending flush/close in this period
+const INTERRUPT_CHECK_PERIOD: Duration = Duration::from_millis(5);
+
+// log targets of the inner loops, their per-chunk logs can be filtered separately (e.g. wasapi_impl::playback_loop=trace)
+const PB_LOOP_TARGET: &str = concat!(module_path!(), "::playback_loop");
+const CAPT_LOOP_TARGET: &str = concat!(module_path!(), "::capture_loop");
+
+lazy_static! {
+    // wait modes selected for device IDs, applied when opening a line
+    static ref DEVICE_WAIT_MODES: Mutex<HashMap<String, WaitMode>> = Mutex::new(HashMap::new());
+}
+
+/// Owned by the java thread writing to/reading from the line, locked for the whole do_write/do_read.
+/// Flush and drain lock it after interrupting the blocked writer/reader.
+struct LineBuffers {
+    leftovers: Vec<u8>,
+    capt_last_chunk_nbr: u64,
+}
+
+/// Shared by all java threads calling the line methods concurrently: immutable config, channels, atomics
+/// and the mutex-protected LineBuffers.
 //#[derive(Debug)]
 pub struct RuntimeData {
     device_id: String,
-    device_name: String,
+    pub device_name: String,
     dir: Direction,
+    rate: usize,
+    channels: usize,
     play_tx_dev: Option<Sender<Vec<u8>>>,
     play_draining_rx_dev: Option<Receiver<Vec<u8>>>,
     capt_rx_dev: Option<Receiver<(u64, Vec<u8>)>>,
@@ -34,13 +72,60 @@ pub struct RuntimeData {
     bufferfill_bytes: Arc<AtomicUsize>,
     chunk_frames: usize,
     frame_bytes: usize,
-    leftovers: Vec<u8>,
-    leftovers_pos: Arc<AtomicUsize>,
+    buffers: Mutex<LineBuffers>,
+    // updated under the buffers lock, read lock-free
+    leftovers_pos: AtomicUsize,
     start_signal: Arc<AtomicBool>,
     stop_signal: Arc<AtomicBool>,
     exit_signal: Arc<AtomicBool>,
-    capt_last_chunk_nbr: u64,
-    capt_flushed_cnt: usize,
+    // None after joined in do_close, lines of the device mixer keep it in mix_stream
+    inner_handle: Mutex<Option<JoinHandle<Res<()>>>>,
+    capt_flushed_cnt: AtomicUsize,
+    // bytes discarded by flushes, for byte position
+    flushed_bytes: AtomicU64,
+    // silence padding the last partial chunk of a drain, not yet confirmed as rendered
+    drain_padding_bytes: AtomicU64,
+    // interrupting blocked do_write/do_read
+    flushing: AtomicBool,
+    closing: AtomicBool,
+    flush_signal: Arc<AtomicBool>,
+    rx_flushed: Receiver<bool>,
+    play_underrun_policy: Arc<AtomicUsize>,
+    play_underruns: Arc<XrunStats>,
+    play_drain_signal: Arc<AtomicBool>,
+    play_rx_drained: Option<Receiver<()>>,
+    capt_overrun_policy: Arc<AtomicUsize>,
+    capt_missed_event_policy: Arc<AtomicUsize>,
+    capt_overruns: Arc<XrunStats>,
+    // set by the inner thread when the device reports a discontinuity
+    capt_dev_discontinuity: Arc<AtomicBool>,
+    // set when data returned by do_read are not continuous, cleared when queried
+    capt_discontinuity: AtomicBool,
+    stats: Arc<LineStats>,
+    timings: Arc<LoopTimings>,
+    thread_config: Arc<Mutex<ThreadConfig>>,
+    thread_config_signal: Arc<AtomicBool>,
+    thread_status: Arc<ThreadStatus>,
+    // wait mode used by the inner loop, Auto until resolved
+    wait_mode: Arc<AtomicUsize>,
+    // shared-mode stream, captured chunks vary in size
+    shared: bool,
+    // software gain applied by the loop processing the line chunks
+    line_gain: Arc<LineGain>,
+    // set when the stream the line is mixed into has ended
+    mix_detached: Arc<AtomicBool>,
+    // this line opened the stream of the device mixer
+    mix_owner: bool,
+    // stream of the device mixer, None for lines without mixing
+    mix_stream: Option<Arc<MixStream>>,
+    // position in the shared sample counter, None for lines without own playback loop or duplex capture
+    duplex_pos: Option<Arc<DuplexPosition>>,
+    // capture line of a duplex pair: attached to the playback loop of the render device
+    duplex_attached: Option<Arc<AtomicBool>>,
+    // of the loop driving the line
+    clock_drift: Arc<ClockDrift>,
+    // device, direction and handle attached to log records of the line
+    pub log_ctx: Arc<StreamLogContext>,
     //outer_file: Box<dyn Write>,
 }
 
@@ -50,31 +135,189 @@ pub enum Disconnected {
     Error,
 }
 
-#[derive(Debug)]
-pub struct DeviceError {
-    desc: String,
+impl Disconnected {
+    pub fn from_reason(reason: AudioSessionDisconnectReason) -> Self {
+        if reason == DisconnectReasonFormatChanged { Disconnected::FormatChange } else { Disconnected::Error }
+    }
+}
+
+// same constants as in the java provider
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnderrunPolicy {
+    // stopping the stream, restarted by the next chunk
+    Stop = 0,
+    // keeping the stream running, writing silence
+    Silence = 1,
+    // keeping the stream running, writing the last chunk faded out, then silence
+    RepeatFade = 2,
 }
 
-impl fmt::Display for DeviceError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.desc)
+impl TryFrom<usize> for UnderrunPolicy {
+    type Error = NativeError;
+
+    fn try_from(id: usize) -> Res<Self> {
+        match id {
+            0 => Ok(UnderrunPolicy::Stop),
+            1 => Ok(UnderrunPolicy::Silence),
+            2 => Ok(UnderrunPolicy::RepeatFade),
+            _ => Err(NativeError::illegal_argument(&format!("Unknown underrun policy {}", id))),
+        }
     }
 }
 
-impl error::Error for DeviceError {
-    fn description(&self) -> &str {
-        &self.desc
+// same constants as in the java provider
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverrunPolicy {
+    // dropping the newly captured chunk
+    DropNewest = 0,
+    // dropping the oldest chunk in the queue to make room for the new one
+    DropOldest = 1,
+}
+
+// same constants as in the java provider
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissedEventPolicy {
+    // only logging and counting
+    Warn = 0,
+    // resetting the stream, the missed chunks are skipped in the chunk numbering (gap in do_read)
+    Reset = 1,
+    // resetting the stream and queueing silent chunks for the missed time (continuous chunk numbering)
+    ResetSilence = 2,
+}
+
+impl TryFrom<usize> for MissedEventPolicy {
+    type Error = NativeError;
+
+    fn try_from(id: usize) -> Res<Self> {
+        match id {
+            0 => Ok(MissedEventPolicy::Warn),
+            1 => Ok(MissedEventPolicy::Reset),
+            2 => Ok(MissedEventPolicy::ResetSilence),
+            _ => Err(NativeError::illegal_argument(&format!("Unknown missed event policy {}", id))),
+        }
     }
 }
 
-impl DeviceError {
-    pub fn new(desc: &str) -> Self {
-        DeviceError {
-            desc: desc.to_owned(),
+impl TryFrom<usize> for OverrunPolicy {
+    type Error = NativeError;
+
+    fn try_from(id: usize) -> Res<Self> {
+        match id {
+            0 => Ok(OverrunPolicy::DropNewest),
+            1 => Ok(OverrunPolicy::DropOldest),
+            _ => Err(NativeError::illegal_argument(&format!("Unknown overrun policy {}", id))),
         }
     }
 }
 
+/// Xrun counters updated by the inner thread, readable by the outer side
+#[derive(Default)]
+pub struct XrunStats {
+    count: AtomicU64,
+    frames: AtomicU64,
+    last_time_ms: AtomicU64,
+}
+
+impl XrunStats {
+    /// new xrun occurrence
+    pub(crate) fn record(&self, frames: usize) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.frames.fetch_add(frames as u64, Ordering::Relaxed);
+        self.last_time_ms.store(now_millis(), Ordering::Relaxed);
+    }
+
+    /// continuing xrun, only adding the affected frames
+    pub(crate) fn add_frames(&self, frames: usize) {
+        self.frames.fetch_add(frames as u64, Ordering::Relaxed);
+    }
+
+    /// (count, affected frames, time of last xrun in millis since epoch)
+    pub fn snapshot(&self) -> (u64, u64, u64) {
+        (self.count.load(Ordering::Relaxed),
+         self.frames.load(Ordering::Relaxed),
+         self.last_time_ms.load(Ordering::Relaxed))
+    }
+}
+
+/// Line health counters updated by the inner thread, readable by the outer side
+pub struct LineStats {
+    // written to / read from the device
+    pub(crate) chunks: AtomicU64,
+    missed_events: AtomicU64,
+    zero_frame_reads: AtomicU64,
+    silent_flags: AtomicU64,
+    discontinuity_flags: AtomicU64,
+    timestamp_errors: AtomicU64,
+    // resets recovering from missed events
+    stream_resets: AtomicU64,
+    // time spent in wait_for_event
+    wait_cnt: AtomicU64,
+    wait_total_us: AtomicU64,
+    wait_min_us: AtomicU64,
+    wait_max_us: AtomicU64,
+}
+
+impl Default for LineStats {
+    fn default() -> Self {
+        LineStats {
+            chunks: AtomicU64::new(0),
+            missed_events: AtomicU64::new(0),
+            zero_frame_reads: AtomicU64::new(0),
+            silent_flags: AtomicU64::new(0),
+            discontinuity_flags: AtomicU64::new(0),
+            timestamp_errors: AtomicU64::new(0),
+            stream_resets: AtomicU64::new(0),
+            wait_cnt: AtomicU64::new(0),
+            wait_total_us: AtomicU64::new(0),
+            // no wait yet
+            wait_min_us: AtomicU64::new(u64::MAX),
+            wait_max_us: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LineStats {
+    pub(crate) fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_wait(&self, elapsed: Duration) {
+        let us = elapsed.as_micros() as u64;
+        self.wait_cnt.fetch_add(1, Ordering::Relaxed);
+        self.wait_total_us.fetch_add(us, Ordering::Relaxed);
+        self.wait_min_us.fetch_min(us, Ordering::Relaxed);
+        self.wait_max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    /// [chunks, xruns, missed events, zero-frame reads, silent flags, discontinuity flags, timestamp errors,
+    /// stream resets, wait min us, wait avg us, wait max us]
+    fn snapshot(&self, xruns: u64) -> Vec<u64> {
+        let wait_cnt = self.wait_cnt.load(Ordering::Relaxed);
+        let (wait_min_us, wait_avg_us) = if wait_cnt > 0 {
+            (self.wait_min_us.load(Ordering::Relaxed), self.wait_total_us.load(Ordering::Relaxed) / wait_cnt)
+        } else {
+            (0, 0)
+        };
+        vec![
+            self.chunks.load(Ordering::Relaxed),
+            xruns,
+            self.missed_events.load(Ordering::Relaxed),
+            self.zero_frame_reads.load(Ordering::Relaxed),
+            self.silent_flags.load(Ordering::Relaxed),
+            self.discontinuity_flags.load(Ordering::Relaxed),
+            self.timestamp_errors.load(Ordering::Relaxed),
+            self.stream_resets.load(Ordering::Relaxed),
+            wait_min_us,
+            wait_avg_us,
+            self.wait_max_us.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
 pub struct PlaySyncData {
     pub rx_dev: Receiver<Vec<u8>>,
     pub tx_cb: Sender<Disconnected>,
@@ -82,44 +325,101 @@ pub struct PlaySyncData {
     pub start_signal: Arc<AtomicBool>,
     pub stop_signal: Arc<AtomicBool>,
     pub exit_signal: Arc<AtomicBool>,
+    pub underrun_policy: Arc<AtomicUsize>,
+    pub underruns: Arc<XrunStats>,
+    pub drain_signal: Arc<AtomicBool>,
+    pub tx_drained: Sender<()>,
+    pub flush_signal: Arc<AtomicBool>,
+    pub tx_flushed: Sender<bool>,
+    pub stats: Arc<LineStats>,
+    pub timings: Arc<LoopTimings>,
+    pub thread_config: Arc<Mutex<ThreadConfig>>,
+    pub thread_config_signal: Arc<AtomicBool>,
+    pub thread_status: Arc<ThreadStatus>,
+    pub wait_mode: Arc<AtomicUsize>,
+    pub clock_drift: Arc<ClockDrift>,
 }
 
 pub struct CaptSyncData {
     pub tx_dev: Sender<(u64, Vec<u8>)>,
+    // for dropping the oldest queued chunks on overrun
+    pub rx_dev_draining: Receiver<(u64, Vec<u8>)>,
     pub rx_prealloc: Receiver<Vec<u8>>,
+    // returning buffers of chunks which could not be queued
+    pub tx_prealloc: Sender<Vec<u8>>,
     pub tx_cb: Sender<Disconnected>,
     pub wasapi_bufferfill_bytes: Arc<AtomicUsize>,
     pub start_signal: Arc<AtomicBool>,
     pub stop_signal: Arc<AtomicBool>,
     pub exit_signal: Arc<AtomicBool>,
+    pub flush_signal: Arc<AtomicBool>,
+    pub tx_flushed: Sender<bool>,
+    pub overrun_policy: Arc<AtomicUsize>,
+    pub missed_event_policy: Arc<AtomicUsize>,
+    pub overruns: Arc<XrunStats>,
+    pub dev_discontinuity: Arc<AtomicBool>,
+    pub stats: Arc<LineStats>,
+    pub timings: Arc<LoopTimings>,
+    pub thread_config: Arc<Mutex<ThreadConfig>>,
+    pub thread_config_signal: Arc<AtomicBool>,
+    pub thread_status: Arc<ThreadStatus>,
+    pub wait_mode: Arc<AtomicUsize>,
+    pub clock_drift: Arc<ClockDrift>,
 }
 
-enum DeviceState {
+pub enum DeviceState {
     Ok(usize),
-    Error(String),
+    Error(NativeError),
 }
 
 struct DeviceTimeTracker {
     log_prefix: String,
     prev_dev_time: Option<f64>,
     accumulated_frame_time: f64,
+    // for measuring intervals between consecutive events
+    prev_event: Option<Instant>,
+    drift: DriftEstimator,
+    clock_drift: Arc<ClockDrift>,
 }
 
 impl DeviceTimeTracker {
-    pub fn new(log_prefix: String) -> DeviceTimeTracker {
+    pub fn new(log_prefix: String, clock_drift: Arc<ClockDrift>) -> DeviceTimeTracker {
         DeviceTimeTracker {
+            drift: DriftEstimator::new(log_prefix.clone()),
             log_prefix,
             prev_dev_time: None,
             accumulated_frame_time: 0.,
+            prev_event: None,
+            clock_drift,
         }
     }
 
     pub fn reset(&mut self) {
         self.prev_dev_time = None;
         self.accumulated_frame_time = 0.;
+        self.prev_event = None;
+        // the device clock stopped or jumped
+        self.drift.restart();
     }
 
-    pub fn event_missing(&mut self, dev_time: f64, frame_time: f64) -> bool {
+    /// Feeds the clock position of the running stream to the drift estimator
+    pub fn track_drift(&mut self, dev_time: f64, qpc_pos: u64) {
+        // zero = invalid position, see event_missing
+        if dev_time != 0. {
+            self.drift.update(dev_time, qpc_pos, &self.clock_drift);
+        }
+    }
+
+    /// Time since the previous event, None for the first event after (re)start
+    pub fn event_interval(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let interval = self.prev_event.map(|prev| now - prev);
+        self.prev_event = Some(now);
+        interval
+    }
+
+    /// Returns the missed device time in seconds if an event was missed
+    pub fn event_missing(&mut self, dev_time: f64, frame_time: f64) -> Option<f64> {
         if dev_time == 0. {
             // invalid value, because we cannot distinguish between S_OK with real dev_time=0 and S_FALSE
             // see S_OK vs. S_FALSE in https://learn.microsoft.com/en-us/windows/win32/api/audioclient/nf-audioclient-iaudioclock-getposition#remarks
@@ -130,7 +430,7 @@ impl DeviceTimeTracker {
                 self.accumulated_frame_time += frame_time;
             }
             // not updating self.prev_dev_time, keeping value from previous check
-            return false;
+            return None;
         } else {
             if self.prev_dev_time.is_some() {
                 let prev_dev_time = self.prev_dev_time.unwrap();
@@ -142,11 +442,11 @@ impl DeviceTimeTracker {
                 // 1 event time corresponds to 1 frame_time,
                 // therefore checking whether elapsed_dev_time is significantly larger than elapsed_frame_time
                 if elapsed_frame_time > 0. && elapsed_dev_time > elapsed_frame_time + 0.5 * frame_time {
-                    warn!("{}: Missed event: device time grew by {}s, expected {}s",
+                    log_event!(LogEvent::MissedEvent, Level::Warn, "{}: Missed event: device time grew by {}s, expected {}s",
                     self.log_prefix, elapsed_dev_time, elapsed_frame_time);
 
                     self.reset();
-                    return true;
+                    return Some(elapsed_dev_time - elapsed_frame_time);
                 }
             }
             // storing dev_time for next check
@@ -154,7 +454,7 @@ impl DeviceTimeTracker {
             // since self.prev_dev_time contains current dev_time now (i.e. next check will cover only one event time),
             // accumulated frame_time from previous events must be cleared
             self.accumulated_frame_time = 0.;
-            return false;
+            return None;
         }
     }
 }
@@ -177,7 +477,7 @@ pub fn do_initialize_wasapi() -> Res<()> {
                     Ok(())
                 }
                 // fatal errors
-                _ => { Err(Box::new(err)) }
+                _ => { Err(err.into()) }
             }
         }
     };
@@ -185,8 +485,8 @@ pub fn do_initialize_wasapi() -> Res<()> {
 
 pub fn do_get_device_cnt() -> Res<u32> {
     let (playCollection, captCollection) = get_colls()?;
-    let mut cnt = playCollection.get_nbr_devices()?;
-    cnt = cnt + captCollection.get_nbr_devices()?;
+    // exclusive render, exclusive capture, shared render, shared capture
+    let cnt = 2 * (playCollection.get_nbr_devices()? + captCollection.get_nbr_devices()?);
     Ok(cnt)
 }
 
@@ -197,39 +497,63 @@ fn get_colls() -> Res<(DeviceCollection, DeviceCollection)> {
 }
 
 pub fn do_get_mixer_desc(idx: u32) -> Res<MixerDesc> {
-    let (dev, _) = get_device_at_idx(idx)?;
+    let (dev, dir, sharemode) = get_device_at_idx(idx)?;
     let name = dev.get_friendlyname()?;
+    let (max_lines, prefix) = match sharemode {
+        // the engine mixes any number of shared streams
+        ShareMode::Shared => (NOT_SPECIFIED, "SHARED"),
+        // exclusive playback lines are mixed in software
+        ShareMode::Exclusive if dir == Direction::Render => (MAX_MIX_LINES as i32, "EXCL"),
+        ShareMode::Exclusive => (1, "EXCL"),
+    };
     let desc = MixerDesc {
         // for now using idx
         deviceID: idx.to_string(),
-        max_lines: 1,
-        name: format!("EXCL: {}", name),
+        max_lines,
+        name: format!("{}: {}", prefix, name),
         description: dev.get_description()?,
     };
     Ok(desc)
 }
 
-fn get_device_at_idx(idx: u32) -> Res<(Device, Direction)> {
+/// Mixer indices: exclusive render devices, exclusive capture devices, shared render devices, shared capture devices
+fn get_device_at_idx(idx: u32) -> Res<(Device, Direction, ShareMode)> {
     let (playCollection, captCollection) = get_colls()?;
     let playCnt = playCollection.get_nbr_devices()?;
-    let (coll, colIdx, dir) = if playCnt > idx {
-        (playCollection, idx, Direction::Render)
+    let captCnt = captCollection.get_nbr_devices()?;
+    let (coll, colIdx, dir, sharemode) = if playCnt > idx {
+        (playCollection, idx, Direction::Render, ShareMode::Exclusive)
+    } else if playCnt + captCnt > idx {
+        (captCollection, idx - playCnt, Direction::Capture, ShareMode::Exclusive)
+    } else if 2 * playCnt + captCnt > idx {
+        (playCollection, idx - playCnt - captCnt, Direction::Render, ShareMode::Shared)
     } else {
-        (captCollection, idx - playCnt, Direction::Capture)
+        (captCollection, idx - 2 * playCnt - captCnt, Direction::Capture, ShareMode::Shared)
     };
     let dev = coll.get_device_at_index(colIdx)?;
-    Ok((dev, dir))
+    Ok((dev, dir, sharemode))
 }
 
-fn get_device_by_id(device_id: &str) -> Res<(Device, Direction)> {
+fn get_device_by_id(device_id: &str) -> Res<(Device, Direction, ShareMode)> {
     let idx = device_id.parse::<u32>()?;
-    let (dev, dir) = get_device_at_idx(idx)?;
-    Ok((dev, dir))
+    get_device_at_idx(idx)
+}
+
+/// (endpoint ID of the IMMDevice, friendly name) of the mixer device
+pub fn get_device_endpoint(device_id: &str) -> Res<(String, String)> {
+    let (dev, _, _) = get_device_by_id(device_id)?;
+    Ok((dev.get_id()?, dev.get_friendlyname()?))
 }
 
 pub fn do_get_formats(device_id: String, dir: &Direction) -> Res<Vec<Format>> {
-    let (dev, dev_dir) = get_device_by_id(&device_id)?;
-    let fmts = if *dir == dev_dir { get_device_formats(dev)? } else { vec!() };
+    let (dev, dev_dir, sharemode) = get_device_by_id(&device_id)?;
+    let fmts = if *dir != dev_dir {
+        vec!()
+    } else if let ShareMode::Shared = sharemode {
+        get_shared_formats()?
+    } else {
+        get_device_formats(dev)?
+    };
     Ok(fmts)
 }
 
@@ -287,6 +611,21 @@ fn get_device_formats(dev: Device) -> Res<Vec<Format>> {
     Ok(formats)
 }
 
+/// The shared mode converts in the library and the engine, all predefined java formats are usable
+fn get_shared_formats() -> Res<Vec<Format>> {
+    let mut formats: Vec<Format> = WV_FMTS_BY_FORMAT.lock()?.keys().cloned().collect();
+    let validbits: HashSet<i32> = formats.iter().map(|format| format.validbits).collect();
+    for validbits in validbits {
+        formats.push(Format {
+            validbits,
+            frame_bytes: NOT_SPECIFIED,
+            channels: NOT_SPECIFIED,
+            rate: NOT_SPECIFIED,
+        });
+    }
+    Ok(formats)
+}
+
 /// lowest common multiple
 fn lcm(n1: usize, n2: usize) -> usize {
     let (mut x, mut y) = if n1 > n2 {
@@ -303,20 +642,8 @@ fn lcm(n1: usize, n2: usize) -> usize {
     n1 * n2 / y
 }
 
-pub fn do_open_dev(device_id: String, dir: &Direction, rate: usize, validbits: usize, frame_bytes: usize,
-                   channels: usize, buffer_bytes: usize) -> Res<RuntimeData> {
-    let (_device, device_name, audio_client) = get_device_details(&device_id, dir)?;
-    debug!("Opening {} device {}: rate: {}, validbits: {}, frame_bytes: {}, channels: {}, buffer_bytes: {}",
-        dir, device_name, rate, validbits, frame_bytes, channels, buffer_bytes);
-    let (_def_period_ns00, min_period_ns00) = audio_client.get_periods()?;
-    debug!(
-        "{}: default period {}, min period {}",
-        dir,
-        _def_period_ns00, min_period_ns00
-    );
-
-    let is_playback = *dir == Direction::Render;
-
+/// Device period around 30 ms aligned to frames (and 128 bytes for IntelHDA)
+fn get_exclusive_period(dir: &Direction, rate: usize, frame_bytes: usize, channels: usize, min_period_ns00: i64) -> i64 {
     // period around 30 ms
     let approx_period_ns00 = cmp::max(30 * 10_000, min_period_ns00);
     let align_segment_bytes = if channels <= 16 {
@@ -338,9 +665,64 @@ pub fn do_open_dev(device_id: String, dir: &Direction, rate: usize, validbits: u
         // adding one more ns00 segment
         period_ns00 += align_segment_ns00 as i64;
     }
+    period_ns00
+}
+
+pub fn do_open_dev(device_id: String, dir: &Direction, rate: usize, validbits: usize, frame_bytes: usize,
+                   channels: usize, buffer_bytes: usize) -> Res<RuntimeData> {
+    let log_ctx = Arc::new(StreamLogContext::new(&device_id, &dir.to_string()));
+    let _ctx_guard = enter_stream_context(&log_ctx);
+    let (_device, device_name, audio_client, sharemode) = get_device_details(&device_id, dir)?;
+    debug!("Opening {} device {} in {:?} mode: rate: {}, validbits: {}, frame_bytes: {}, channels: {}, buffer_bytes: {}",
+        dir, device_name, sharemode, rate, validbits, frame_bytes, channels, buffer_bytes);
+    let (def_period_ns00, min_period_ns00) = audio_client.get_periods()?;
+    debug!(
+        "{}: default period {}, min period {}",
+        dir,
+        def_period_ns00, min_period_ns00
+    );
+
+    let is_playback = *dir == Direction::Render;
+    // exclusive playback lines opened while another line plays on the device are mixed into its stream
+    let mix_bus = match sharemode {
+        ShareMode::Exclusive if is_playback => get_mix_bus(&device_id)?,
+        _ => None,
+    };
+    if let Some(bus) = mix_bus.as_ref() {
+        bus.reserve_line(rate)?;
+        debug!("PB: device {} already playing, mixing the new line into its stream", device_name);
+    }
+    // capture lines of a duplex pair are driven by the playback loop of the render device
+    let duplex_bus = match get_duplex_device(&device_id)? {
+        Some(render_device_id) if !is_playback => match get_duplex_bus(&render_device_id)? {
+            Some(bus) => Some(bus),
+            None => {
+                let msg = format!("Duplex capture device {}: open the playback line of device {} first", device_name, render_device_id);
+                return Err(NativeError::illegal_state(&msg));
+            }
+        },
+        _ => None,
+    };
+    if let Some(bus) = duplex_bus.as_ref() {
+        bus.reserve_capture(rate)?;
+        debug!("CAPT: device {} is captured in duplex with the playback stream", device_name);
+    }
+
+    let period_ns00 = match (&sharemode, duplex_bus.as_ref()) {
+        // the engine period, the shared stream cannot run faster than the engine
+        (ShareMode::Shared, _) => def_period_ns00,
+        // both streams of a duplex pair run with the same period
+        (ShareMode::Exclusive, Some(bus)) => bus.period_ns00,
+        (ShareMode::Exclusive, None) => get_exclusive_period(dir, rate, frame_bytes, channels, min_period_ns00),
+    };
     debug!("{}: Using device period {}", dir, period_ns00);
     // this code assumes device.Initialize will use closely similar buffer to dev_period
-    let estimated_chunk_frames = (rate as i64 * period_ns00 / 10_000_000) as usize;
+    let estimated_chunk_frames = match (mix_bus.as_ref(), duplex_bus.as_ref()) {
+        // mixed lines must use the chunks of the bus owner
+        (Some(bus), _) => bus.chunk_frames,
+        (None, Some(bus)) => bus.chunk_frames,
+        (None, None) => (rate as i64 * period_ns00 / 10_000_000) as usize,
+    };
     let chunks = ((buffer_bytes as f32 / frame_bytes as f32) / estimated_chunk_frames as f32) as usize;
     trace!("{}: Using {} chunks in buffer => total estimated {} bytes", dir, chunks, chunks * estimated_chunk_frames * frame_bytes);
     let (play_tx_dev, play_rx_dev, play_draining_rx_dev) = if is_playback {
@@ -349,11 +731,11 @@ pub fn do_open_dev(device_id: String, dir: &Direction, rate: usize, validbits: u
     } else {
         (None, None, None)
     };
-    let (capt_tx_dev, capt_rx_dev) = if is_playback {
-        (None, None)
+    let (capt_tx_dev, capt_rx_dev, capt_rx_dev_draining) = if is_playback {
+        (None, None, None)
     } else {
         let (tx, rx) = bounded(chunks);
-        (Some(tx), Some(rx))
+        (Some(tx), Some(rx.clone()), Some(rx))
     };
 
     let (capt_tx_prealloc, capt_rx_prealloc) = if is_playback {
@@ -380,7 +762,9 @@ pub fn do_open_dev(device_id: String, dir: &Direction, rate: usize, validbits: u
     let bufferfill_frames = Arc::new(AtomicUsize::new(0));
     let bufferfill_bytes_cloned = bufferfill_frames.clone();
     let device_id_cloned = device_id.clone();
+    let device_name_cloned = device_name.clone();
     let dir_cloned = dir.clone();
+    let log_ctx_cloned = log_ctx.clone();
 
     let start_signal = Arc::new(AtomicBool::new(false));
     let stop_signal = Arc::new(AtomicBool::new(false));
@@ -390,106 +774,236 @@ pub fn do_open_dev(device_id: String, dir: &Direction, rate: usize, validbits: u
     let stop_signal_cloned = stop_signal.clone();
     let exit_signal_cloned = exit_signal.clone();
 
-    // wasapi device loop
-    // TODO - joining the thread somehow?
-    let _innerhandle = thread::Builder::new()
-        .name(format!("Wasapi{}Inner", dir).to_string())
-        .spawn(move || {
-            // new thread requires initializing wasapi (STA)
-            if let Err(err) = do_initialize_wasapi() {
-                let msg = format!("{}: error: {}", &dir_cloned, err);
-                tx_state_dev.send(DeviceState::Error(msg)).unwrap_or(());
-                return;
-            }
-            let (_device, audio_client, handle, client_buffer_frames) =
-                match device_open(
-                    &device_id_cloned,
-                    &dir_cloned,
-                    rate,
-                    validbits,
-                    frame_bytes,
-                    channels,
-                    period_ns00,
-                ) {
-                    Ok((_device, audio_client, handle)) => {
-                        let client_buffer_frames = match audio_client.get_bufferframecount() {
-                            Ok(frames) => { frames }
-                            Err(err) => {
-                                let msg = format!("PB: error: {}", err);
-                                tx_state_dev.send(DeviceState::Error(msg)).unwrap_or(());
-                                return;
-                            }
-                        } as usize;
-                        tx_state_dev.send(DeviceState::Ok(client_buffer_frames)).unwrap_or(());
-                        (_device, audio_client, handle, client_buffer_frames)
-                    }
-                    Err(err) => {
-                        let msg = format!("PB: error: {}", err);
-                        tx_state_dev.send(DeviceState::Error(msg)).unwrap_or(());
-                        return;
-                    }
-                };
-            trace!("client_buffer_frames: {}", client_buffer_frames);
+    let flush_signal = Arc::new(AtomicBool::new(false));
+    let flush_signal_cloned = flush_signal.clone();
+    // inner thread confirms the flush, reporting whether the stream was running
+    let (tx_flushed, rx_flushed) = bounded(1);
+
+    let play_underrun_policy = Arc::new(AtomicUsize::new(UnderrunPolicy::Stop as usize));
+    let play_underruns = Arc::new(XrunStats::default());
+    let play_underrun_policy_cloned = play_underrun_policy.clone();
+    let play_underruns_cloned = play_underruns.clone();
+    let play_drain_signal = Arc::new(AtomicBool::new(false));
+    let play_drain_signal_cloned = play_drain_signal.clone();
+    let capt_overrun_policy = Arc::new(AtomicUsize::new(OverrunPolicy::DropNewest as usize));
+    let capt_overruns = Arc::new(XrunStats::default());
+    let capt_dev_discontinuity = Arc::new(AtomicBool::new(false));
+    let capt_overrun_policy_cloned = capt_overrun_policy.clone();
+    let capt_missed_event_policy = Arc::new(AtomicUsize::new(MissedEventPolicy::Warn as usize));
+    let capt_missed_event_policy_cloned = capt_missed_event_policy.clone();
+    let capt_overruns_cloned = capt_overruns.clone();
+    let capt_dev_discontinuity_cloned = capt_dev_discontinuity.clone();
+    let stats = Arc::new(LineStats::default());
+    let stats_cloned = stats.clone();
+    let timings = Arc::new(LoopTimings::default());
+    let timings_cloned = timings.clone();
+    let thread_config = Arc::new(Mutex::new(ThreadConfig::default()));
+    let thread_config_signal = Arc::new(AtomicBool::new(false));
+    let thread_status = Arc::new(ThreadStatus::default());
+    let thread_config_cloned = thread_config.clone();
+    let thread_config_signal_cloned = thread_config_signal.clone();
+    let thread_status_cloned = thread_status.clone();
+    let open_wait_mode = get_device_wait_mode(&device_id)?;
+    let wait_mode = Arc::new(AtomicUsize::new(open_wait_mode as usize));
+    let wait_mode_cloned = wait_mode.clone();
+    let (play_tx_drained, play_rx_drained) = if is_playback {
+        let (tx, rx) = bounded(1);
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
+    let line_gain = Arc::new(LineGain::default());
+    let gain_stage = GainStage::new(line_gain.clone(), rate, frame_bytes / channels, channels, validbits);
+    let mix_detached = Arc::new(AtomicBool::new(false));
+    // the owner line closed while the stream keeps running for the mixed lines
+    let mix_own_closed = Arc::new(AtomicBool::new(false));
+    let mix_own_closed_cloned = mix_own_closed.clone();
+    // lines joining the stream of this line
+    let (tx_attach, rx_attach) = unbounded();
+    // capture line of a duplex pair joining the stream of this line
+    let (tx_duplex_attach, rx_duplex_attach) = unbounded();
+    let duplex_pos = Arc::new(DuplexPosition::default());
+    let duplex_pos_cloned = duplex_pos.clone();
+    let capture_attached = match duplex_bus.as_ref() {
+        Some(bus) => bus.capture_attached.clone(),
+        None => Arc::new(AtomicBool::new(false)),
+    };
+    let capture_attached_cloned = capture_attached.clone();
+    // lines without own loop report the drift of the loop driving them
+    let clock_drift = match (mix_bus.as_ref(), duplex_bus.as_ref()) {
+        (Some(bus), _) => bus.clock_drift.clone(),
+        (None, Some(bus)) => bus.clock_drift.clone(),
+        (None, None) => Arc::new(ClockDrift::default()),
+    };
 
+    let (play_sync, capt_sync) = if is_playback {
+        let sync = PlaySyncData {
+            rx_dev: play_rx_dev.unwrap(),
+            tx_cb: tx_disconnectreason,
+            wasapi_bufferfill_bytes: bufferfill_bytes_cloned,
+            start_signal: start_signal_cloned,
+            stop_signal: stop_signal_cloned,
+            exit_signal: exit_signal_cloned,
+            underrun_policy: play_underrun_policy_cloned,
+            underruns: play_underruns_cloned,
+            drain_signal: play_drain_signal_cloned,
+            tx_drained: play_tx_drained.unwrap(),
+            flush_signal: flush_signal_cloned,
+            tx_flushed,
+            stats: stats_cloned,
+            timings: timings_cloned,
+            thread_config: thread_config_cloned,
+            thread_config_signal: thread_config_signal_cloned,
+            thread_status: thread_status_cloned,
+            wait_mode: wait_mode_cloned,
+            clock_drift: clock_drift.clone(),
+        };
+        (Some(sync), None)
+    } else {
+        let sync = CaptSyncData {
+            tx_dev: capt_tx_dev.unwrap(),
+            rx_dev_draining: capt_rx_dev_draining.unwrap(),
+            rx_prealloc: capt_rx_prealloc.unwrap(),
+            tx_prealloc: capt_tx_prealloc.clone().unwrap(),
+            tx_cb: tx_disconnectreason,
+            wasapi_bufferfill_bytes: bufferfill_bytes_cloned,
+            start_signal: start_signal_cloned,
+            stop_signal: stop_signal_cloned,
+            exit_signal: exit_signal_cloned,
+            flush_signal: flush_signal_cloned,
+            tx_flushed,
+            overrun_policy: capt_overrun_policy_cloned,
+            missed_event_policy: capt_missed_event_policy_cloned,
+            overruns: capt_overruns_cloned,
+            dev_discontinuity: capt_dev_discontinuity_cloned,
+            stats: stats_cloned,
+            timings: timings_cloned,
+            thread_config: thread_config_cloned,
+            thread_config_signal: thread_config_signal_cloned,
+            thread_status: thread_status_cloned,
+            wait_mode: wait_mode_cloned,
+            clock_drift: clock_drift.clone(),
+        };
+        (None, Some(sync))
+    };
 
-            let result = if is_playback {
-                let sync = PlaySyncData {
-                    rx_dev: play_rx_dev.unwrap(),
-                    tx_cb: tx_disconnectreason,
-                    wasapi_bufferfill_bytes: bufferfill_bytes_cloned,
-                    start_signal: start_signal_cloned,
-                    stop_signal: stop_signal_cloned,
-                    exit_signal: exit_signal_cloned,
-                };
-                playback_loop(
-                    audio_client,
-                    handle,
-                    frame_bytes,
-                    client_buffer_frames,
-                    rate,
-                    sync,
-                )
-            } else {
-                let sync = CaptSyncData {
-                    tx_dev: capt_tx_dev.unwrap(),
-                    rx_prealloc: capt_rx_prealloc.unwrap(),
-                    tx_cb: tx_disconnectreason,
-                    wasapi_bufferfill_bytes: bufferfill_bytes_cloned,
-                    start_signal: start_signal_cloned,
-                    stop_signal: stop_signal_cloned,
-                    exit_signal: exit_signal_cloned,
-                };
-                capture_loop(
-                    audio_client,
-                    handle,
-                    frame_bytes,
-                    client_buffer_frames,
-                    rate,
-                    sync,
-                )
-            };
-            if let Err(err) = result {
-                let msg = format!("{}: Looping failed with error: {:?}", dir_cloned, err);
-                error!("{}", msg);
-                tx_state_dev.send(DeviceState::Error(msg)).unwrap_or(());
-            }
-        })?;
-    let real_chunk_frames = match rx_state_dev.recv() {
-        Ok(DeviceState::Ok(frames)) => {
-            frames
+    let (mut inner_handle, real_chunk_frames) = match (mix_bus.as_ref(), duplex_bus.as_ref()) {
+        (Some(bus), _) => {
+            // no own device thread, the loop of the bus owner services the line
+            let converter = SampleConverter::new(frame_bytes / channels, channels, DevSampleFormat::Int(bus.sample_bytes), bus.channels);
+            bus.attach(MixInput::new(play_sync.unwrap(), converter, gain_stage, mix_detached.clone()))?;
+            (None, bus.chunk_frames)
         }
-        Ok(DeviceState::Error(msg)) => {
-            return Err(Box::new(DeviceError { desc: msg }));
+        (None, Some(bus)) => {
+            // no own device thread, the playback loop of the render device opens and reads the capture device
+            let capture = DuplexCapture::new(capt_sync.unwrap(), device_id.clone(), validbits, frame_bytes, channels,
+                                             gain_stage, duplex_pos.clone(), log_ctx.clone(), tx_state_dev);
+            bus.attach(capture)?;
+            (None, recv_device_state(&rx_state_dev, &device_name)?)
         }
-        Err(err) => {
-            return Err(Box::new(err));
+        (None, None) => {
+            // wasapi device loop, joined in do_close
+            // the AudioClient is released when the thread finishes
+            let inner_handle = thread::Builder::new()
+                .name(format!("Wasapi{}Inner", dir).to_string())
+                .spawn(move || {
+                    let _ctx_guard = enter_stream_context(&log_ctx_cloned);
+                    // new thread requires initializing wasapi (STA)
+                    if let Err(err) = do_initialize_wasapi() {
+                        error!("{}: error: {}", &dir_cloned, err);
+                        tx_state_dev.send(DeviceState::Error(err)).unwrap_or(());
+                        // reported to the opening side, nothing to report on close
+                        return Ok(());
+                    }
+                    let (_device, audio_client, handle, converter, client_buffer_frames) =
+                        match device_open(
+                            &device_id_cloned,
+                            &dir_cloned,
+                            rate,
+                            validbits,
+                            frame_bytes,
+                            channels,
+                            period_ns00,
+                            open_wait_mode,
+                        ) {
+                            Ok((_device, audio_client, handle, converter, chunk_frames)) => {
+                                tx_state_dev.send(DeviceState::Ok(chunk_frames)).unwrap_or(());
+                                (_device, audio_client, handle, converter, chunk_frames)
+                            }
+                            Err(err) => {
+                                error!("{}: error: {}", &dir_cloned, err);
+                                tx_state_dev.send(DeviceState::Error(err)).unwrap_or(());
+                                // reported to the opening side, nothing to report on close
+                                return Ok(());
+                            }
+                        };
+                    trace!("client_buffer_frames: {}", client_buffer_frames);
+
+
+                    let result = if is_playback {
+                        let mix_bus = MixBus::new(rx_attach, mix_own_closed_cloned, frame_bytes / channels, channels,
+                                                  client_buffer_frames);
+                        let duplex = DuplexBus::new(rx_duplex_attach, rate, period_ns00, client_buffer_frames,
+                                                    duplex_pos_cloned, capture_attached_cloned);
+                        playback_loop(
+                            audio_client,
+                            handle,
+                            converter,
+                            mix_bus,
+                            duplex,
+                            gain_stage,
+                            frame_bytes,
+                            channels,
+                            client_buffer_frames,
+                            rate,
+                            play_sync.unwrap(),
+                        )
+                    } else {
+                        capture_loop(
+                            audio_client,
+                            handle,
+                            converter,
+                            gain_stage,
+                            frame_bytes,
+                            client_buffer_frames,
+                            rate,
+                            capt_sync.unwrap(),
+                        )
+                    };
+                    // reported to the closing side by joining the thread
+                    if let Err(err) = &result {
+                        error!("{}: Looping failed with error: {}", dir_cloned, err);
+                    }
+                    result.device_ctx(&device_name_cloned)
+                })?;
+            (Some(inner_handle), recv_device_state(&rx_state_dev, &device_name)?)
         }
     };
+    let mix_stream = match mix_bus.as_ref() {
+        Some(bus) => Some(bus.stream.clone()),
+        None if is_playback && matches!(sharemode, ShareMode::Exclusive) => {
+            // the stream loop is joined by the last closed line of the mixer
+            let stream = Arc::new(MixStream::new(mix_own_closed, exit_signal.clone()));
+            stream.set_handle(inner_handle.take())?;
+            let entry = MixBusEntry::new(tx_attach, rate, frame_bytes / channels, channels, real_chunk_frames,
+                                         stream.clone(), clock_drift.clone());
+            register_mix_bus(&device_id, entry)?;
+            Some(stream)
+        }
+        _ => None,
+    };
+    if mix_bus.is_none() && is_playback && matches!(sharemode, ShareMode::Exclusive) {
+        let entry = DuplexBusEntry::new(tx_duplex_attach, rate, period_ns00, real_chunk_frames, capture_attached.clone(),
+                                        clock_drift.clone());
+        register_duplex_bus(&device_id, entry)?;
+    }
 
     let rtd = RuntimeData {
         device_id,
         device_name,
         dir: dir.clone(),
+        rate,
+        channels,
         play_tx_dev,
         play_draining_rx_dev,
         capt_rx_dev,
@@ -500,14 +1014,48 @@ pub fn do_open_dev(device_id: String, dir: &Direction, rate: usize, validbits: u
         bufferfill_bytes: bufferfill_frames,
         chunk_frames: real_chunk_frames,
         frame_bytes,
-        // 1 chunk of bytes
-        leftovers: vec![0; real_chunk_frames * frame_bytes as usize],
-        leftovers_pos: Arc::new(AtomicUsize::new(0)),
+        buffers: Mutex::new(LineBuffers {
+            // 1 chunk of bytes
+            leftovers: vec![0; real_chunk_frames * frame_bytes as usize],
+            capt_last_chunk_nbr: 0,
+        }),
+        leftovers_pos: AtomicUsize::new(0),
         start_signal,
         stop_signal,
         exit_signal,
-        capt_last_chunk_nbr: 0,
-        capt_flushed_cnt: 0,
+        inner_handle: Mutex::new(inner_handle),
+        capt_flushed_cnt: AtomicUsize::new(0),
+        flushed_bytes: AtomicU64::new(0),
+        drain_padding_bytes: AtomicU64::new(0),
+        flushing: AtomicBool::new(false),
+        closing: AtomicBool::new(false),
+        flush_signal,
+        rx_flushed,
+        play_underrun_policy,
+        play_underruns,
+        play_drain_signal,
+        play_rx_drained,
+        capt_overrun_policy,
+        capt_missed_event_policy,
+        capt_overruns,
+        capt_dev_discontinuity,
+        capt_discontinuity: AtomicBool::new(false),
+        stats,
+        timings,
+        thread_config,
+        thread_config_signal,
+        thread_status,
+        wait_mode,
+        shared: matches!(sharemode, ShareMode::Shared),
+        line_gain,
+        mix_detached,
+        mix_owner: mix_bus.is_none() && mix_stream.is_some(),
+        mix_stream,
+        // the playback loop counts its own line also without duplex capture
+        duplex_pos: if mix_bus.is_none() && (is_playback || duplex_bus.is_some()) { Some(duplex_pos) } else { None },
+        duplex_attached: duplex_bus.map(|_| capture_attached),
+        clock_drift,
+        log_ctx: log_ctx.clone(),
         //outer_file: File::create("outer.raw").map(|f| Box::new(f) as Box<dyn Write>).unwrap(),
     };
 
@@ -538,6 +1086,16 @@ pub fn do_open_dev(device_id: String, dir: &Direction, rate: usize, validbits: u
     Ok(rtd)
 }
 
+/// Waits for the inner thread to report the opened device, returns the real chunk frames
+fn recv_device_state(rx_state_dev: &Receiver<DeviceState>, device_name: &str) -> Res<usize> {
+    match rx_state_dev.recv() {
+        Ok(DeviceState::Ok(frames)) => Ok(frames),
+        // the open error was already reported to the opening side
+        Ok(DeviceState::Error(err)) => Err(err.with_device(device_name)),
+        Err(err) => Err(err.into()),
+    }
+}
+
 pub fn do_get_buffer_bytes(rtd: &RuntimeData, dir: &Direction) -> Res<usize> {
     check_direction_from_rt(rtd, dir, "get_buffer_bytes")?;
     // total bytes storable in all chunks in the FIFO tx_dev
@@ -561,10 +1119,252 @@ pub fn do_stop(rtd: &RuntimeData, dir: &Direction) -> Res<()> {
     Ok(())
 }
 
-pub fn do_write(rtd: &mut RuntimeData, java_buffer: &[u8], offset: usize, data_len: usize) -> Res<usize> {
+pub fn do_set_underrun_policy(rtd: &RuntimeData, policy: UnderrunPolicy) -> Res<()> {
+    check_direction_from_rt(rtd, &Direction::Render, "set_underrun_policy")?;
+    debug!("PB: device {}: using underrun policy {:?}", rtd.device_name, policy);
+    rtd.play_underrun_policy.store(policy as usize, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn do_get_underrun_info(rtd: &RuntimeData) -> Res<(u64, u64, u64)> {
+    check_direction_from_rt(rtd, &Direction::Render, "get_underrun_info")?;
+    Ok(rtd.play_underruns.snapshot())
+}
+
+pub fn do_set_overrun_policy(rtd: &RuntimeData, policy: OverrunPolicy) -> Res<()> {
+    check_direction_from_rt(rtd, &Direction::Capture, "set_overrun_policy")?;
+    debug!("CAPT: device {}: using overrun policy {:?}", rtd.device_name, policy);
+    rtd.capt_overrun_policy.store(policy as usize, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn do_set_missed_event_policy(rtd: &RuntimeData, policy: MissedEventPolicy) -> Res<()> {
+    check_direction_from_rt(rtd, &Direction::Capture, "set_missed_event_policy")?;
+    debug!("CAPT: device {}: using missed event policy {:?}", rtd.device_name, policy);
+    rtd.capt_missed_event_policy.store(policy as usize, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Applied by the inner thread in its next loop iteration
+pub fn do_set_thread_config(rtd: &RuntimeData, config: ThreadConfig) -> Res<()> {
+    debug!("{}: device {}: using thread config {:?}", rtd.dir, rtd.device_name, config);
+    *rtd.thread_config.lock()? = config;
+    rtd.thread_config_signal.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// (MMCSS task index, priority id, affinity mask) applied to the inner thread
+pub fn do_get_thread_info(rtd: &RuntimeData) -> Res<(u32, u32, u64)> {
+    Ok(rtd.thread_status.snapshot())
+}
+
+pub fn do_set_wait_mode(device_id: String, mode: WaitMode) -> Res<()> {
+    // checking the device exists
+    get_device_by_id(&device_id)?;
+    debug!("Device {}: using wait mode {:?} for lines opened from now on", device_id, mode);
+    DEVICE_WAIT_MODES.lock()?.insert(device_id, mode);
+    Ok(())
+}
+
+fn get_device_wait_mode(device_id: &str) -> Res<WaitMode> {
+    Ok(DEVICE_WAIT_MODES.lock()?.get(device_id).copied().unwrap_or(WaitMode::Auto))
+}
+
+pub fn do_get_wait_mode(rtd: &RuntimeData) -> Res<WaitMode> {
+    WaitMode::try_from(rtd.wait_mode.load(Ordering::Relaxed))
+}
+
+pub fn do_set_mix_gain(rtd: &RuntimeData, gain: f32) -> Res<()> {
+    check_direction_from_rt(rtd, &Direction::Render, "set_mix_gain")?;
+    if gain.is_nan() || gain < 0.0 {
+        let msg = format!("Invalid mix gain {}", gain);
+        return Err(NativeError::illegal_argument(&msg));
+    }
+    debug!("PB: device {}: using mix gain {}", rtd.device_name, gain);
+    rtd.line_gain.set_mix_gain(gain);
+    Ok(())
+}
+
+pub fn do_set_gain(rtd: &RuntimeData, gain_db: f32, ramp_ms: u32) -> Res<()> {
+    if gain_db.is_nan() || gain_db > MAX_GAIN_DB {
+        let msg = format!("Invalid gain {} dB, max. {} dB", gain_db, MAX_GAIN_DB);
+        return Err(NativeError::illegal_argument(&msg));
+    }
+    debug!("{}: device {}: software gain {} dB, ramp {} ms", rtd.dir, rtd.device_name, gain_db, ramp_ms);
+    rtd.line_gain.set(gain_db, ramp_ms);
+    Ok(())
+}
+
+pub fn do_set_gain_limiter(rtd: &RuntimeData, enabled: bool) -> Res<()> {
+    rtd.line_gain.set_limiter(enabled);
+    Ok(())
+}
+
+pub fn do_set_fade(rtd: &RuntimeData, fade_ms: u32) -> Res<()> {
+    debug!("{}: device {}: start/stop fades {} ms", rtd.dir, rtd.device_name, fade_ms);
+    rtd.line_gain.set_fade(fade_ms);
+    Ok(())
+}
+
+pub fn do_set_duplex_device(device_id: String, render_device_id: String) -> Res<()> {
+    let (_device, dir, sharemode) = get_device_by_id(&device_id)?;
+    check_direction(&dir, &Direction::Capture, &device_id, "set_duplex_device")?;
+    if let ShareMode::Shared = sharemode {
+        let msg = format!("Duplex streams require an exclusive capture device, device ID {} is shared", device_id);
+        return Err(NativeError::illegal_argument(&msg));
+    }
+    if render_device_id.is_empty() {
+        debug!("Device {}: capture lines opened from now on run their own loop", device_id);
+        return set_duplex_device(&device_id, None);
+    }
+    let (_render_device, render_dir, render_sharemode) = get_device_by_id(&render_device_id)?;
+    check_direction(&render_dir, &Direction::Render, &render_device_id, "set_duplex_device")?;
+    if let ShareMode::Shared = render_sharemode {
+        let msg = format!("Duplex streams require an exclusive render device, device ID {} is shared", render_device_id);
+        return Err(NativeError::illegal_argument(&msg));
+    }
+    debug!("Device {}: capture lines opened from now on are driven by the playback loop of device {}", device_id, render_device_id);
+    set_duplex_device(&device_id, Some(render_device_id))
+}
+
+/// (frame position of the line, shared counter value) of the first frame of the last chunk transferred by the loop
+pub fn do_get_duplex_pos(rtd: &RuntimeData) -> Res<(u64, u64)> {
+    match rtd.duplex_pos.as_ref() {
+        Some(pos) => pos.snapshot(),
+        None => {
+            let msg = format!("{}: line of device {} has no duplex position", rtd.dir, rtd.device_name);
+            Err(NativeError::illegal_state(&msg))
+        }
+    }
+}
+
+/// Stream latency of the device client in frames, 0 before the stream started
+pub fn do_get_stream_latency(rtd: &RuntimeData) -> Res<u64> {
+    match rtd.duplex_pos.as_ref() {
+        Some(pos) => Ok(pos.latency()),
+        None => {
+            let msg = format!("{}: line of device {} has no duplex position", rtd.dir, rtd.device_name);
+            Err(NativeError::illegal_state(&msg))
+        }
+    }
+}
+
+/// (device time / QPC time ratio, real device rate, window of the estimate in seconds), zeros before the first estimate
+pub fn do_get_clock_drift(rtd: &RuntimeData) -> Res<(f64, f64, f64)> {
+    let (ratio, window_s) = rtd.clock_drift.snapshot();
+    Ok((ratio, ratio * rtd.rate as f64, window_s))
+}
+
+/// Ratio of the device clock of the line to the device clock of the other line, 0 if any is not estimated yet
+pub fn do_get_relative_drift(rtd: &RuntimeData, other: &RuntimeData) -> Res<f64> {
+    let (ratio, _) = rtd.clock_drift.snapshot();
+    let (other_ratio, _) = other.clock_drift.snapshot();
+    if ratio == 0. || other_ratio == 0. {
+        return Ok(0.);
+    }
+    Ok(ratio / other_ratio)
+}
+
+pub fn do_get_overrun_info(rtd: &RuntimeData) -> Res<(u64, u64, u64)> {
+    check_direction_from_rt(rtd, &Direction::Capture, "get_overrun_info")?;
+    Ok(rtd.capt_overruns.snapshot())
+}
+
+/// Returns whether data read since the last call were discontinuous (dropped chunks, device discontinuity). Clears the flag.
+pub fn do_get_discontinuity(rtd: &RuntimeData) -> Res<bool> {
+    check_direction_from_rt(rtd, &Direction::Capture, "get_discontinuity")?;
+    Ok(rtd.capt_discontinuity.swap(false, Ordering::Relaxed))
+}
+
+pub fn do_get_stats(rtd: &RuntimeData) -> Res<Vec<u64>> {
+    // underruns for playback, overruns (dropped chunks) for capture
+    let (xruns, _, _) = if rtd.dir == Direction::Render {
+        rtd.play_underruns.snapshot()
+    } else {
+        rtd.capt_overruns.snapshot()
+    };
+    Ok(rtd.stats.snapshot(xruns))
+}
+
+/// Bucket counts of the loop timing histograms, see timing::PHASES
+pub fn do_get_timings(rtd: &RuntimeData) -> Res<Vec<u64>> {
+    Ok(rtd.timings.snapshot())
+}
+
+/// Format of the line data as exchanged with java
+pub struct LineFormat {
+    pub dir: Direction,
+    pub rate: usize,
+    pub sample_bytes: usize,
+    pub channels: usize,
+    pub chunk_frames: usize,
+}
+
+pub fn get_line_format(rtd: &RuntimeData) -> LineFormat {
+    LineFormat {
+        dir: rtd.dir.clone(),
+        rate: rtd.rate,
+        sample_bytes: rtd.frame_bytes / rtd.channels,
+        channels: rtd.channels,
+        chunk_frames: rtd.chunk_frames,
+    }
+}
+
+/// Frames written by java and not passed to the device yet: queued chunks and leftovers
+pub fn get_queued_frames(rtd: &RuntimeData) -> Res<usize> {
+    check_direction_from_rt(rtd, &Direction::Render, "get_queued_frames")?;
+    let queued_bytes = rtd.play_tx_dev.as_ref().unwrap().len() * rtd.chunk_frames * rtd.frame_bytes
+        + rtd.leftovers_pos.load(Ordering::Relaxed);
+    Ok(queued_bytes / rtd.frame_bytes)
+}
+
+pub fn is_closing(rtd: &RuntimeData) -> bool {
+    rtd.closing.load(Ordering::Relaxed)
+}
+
+fn is_interrupted(rtd: &RuntimeData) -> bool {
+    rtd.flushing.load(Ordering::Relaxed) || rtd.closing.load(Ordering::Relaxed)
+}
+
+/// Blocking send of the chunk to the inner loop, returns false if interrupted by flush/close.
+/// With deadline, fails with a timeout error when the queue stays full until the deadline.
+fn send_play_chunk(rtd: &RuntimeData, chunk: Vec<u8>, deadline: Option<Instant>) -> Res<bool> {
+    let tx = rtd.play_tx_dev.as_ref().unwrap();
+    let mut chunk = chunk;
+    loop {
+        if is_interrupted(rtd) {
+            debug!("PB: write interrupted by flush/close, discarding chunk");
+            return Ok(false);
+        }
+        if rtd.mix_detached.load(Ordering::Relaxed) {
+            let msg = "PB: the mixed stream has ended, line cannot play anymore";
+            return Err(NativeError::illegal_state(msg).with_device(&rtd.device_name));
+        }
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline {
+                let msg = "PB: queue to the inner thread stayed full until the deadline";
+                return Err(NativeError::timeout(msg).with_device(&rtd.device_name));
+            }
+        }
+        match tx.send_timeout(chunk, INTERRUPT_CHECK_PERIOD) {
+            Ok(_) => return Ok(true),
+            Err(SendTimeoutError::Timeout(returned)) => {
+                // queue full, trying again
+                chunk = returned;
+            }
+            Err(err) => {
+                error!("{}", err.to_string());
+                return Err(err.into());
+            }
+        }
+    }
+}
+
+pub fn do_write(rtd: &RuntimeData, java_buffer: &[u8], offset: usize, data_len: usize) -> Res<usize> {
     trace!("PB: do_write: java_buffer {} bytes, offset {} bytes, writing {} bytes", java_buffer.len(), offset, data_len);
 
     let chunk_bytes = rtd.chunk_frames * rtd.frame_bytes;
+    let mut buffers = rtd.buffers.lock()?;
 
     let mut data_to_write = &java_buffer[offset..(offset + data_len)];
     // rtd.outer_file.write_all(data_to_write);
@@ -577,7 +1377,7 @@ pub fn do_write(rtd: &mut RuntimeData, java_buffer: &[u8], offset: usize, data_l
         // just appending whole data_to_write to leftovers
         trace!("PB: write: leftovers_pos {} + data_len {} < chunk_bytes {}: only copying to leftovers",
         leftovers_pos, data_len, chunk_bytes);
-        rtd.leftovers[leftovers_pos..leftovers_pos + data_len].copy_from_slice(&data_to_write);
+        buffers.leftovers[leftovers_pos..leftovers_pos + data_len].copy_from_slice(&data_to_write);
         rtd.leftovers_pos.store(leftovers_pos + data_len, Ordering::Relaxed);
         // finished, no chunk to be sent to the inner thread
         return Ok(data_len);
@@ -590,18 +1390,16 @@ pub fn do_write(rtd: &mut RuntimeData, java_buffer: &[u8], offset: usize, data_l
         unsafe { chunk.set_len(chunk_bytes); }
         trace!("PB: new chunk with leftovers and data: length: {}, chunk_bytes: {}", chunk.len(), chunk_bytes);
 
-        chunk[0..leftovers_pos].copy_from_slice(&rtd.leftovers[0..leftovers_pos]);
+        chunk[0..leftovers_pos].copy_from_slice(&buffers.leftovers[0..leftovers_pos]);
         let bytes_from_data = chunk_bytes - leftovers_pos;
         chunk[leftovers_pos..].copy_from_slice(&data_to_write[0..bytes_from_data]);
+        if !send_play_chunk(rtd, chunk, None)? {
+            // flush discards the leftovers
+            return Ok(0);
+        }
         // leftovers are empty now
         leftovers_pos = 0;
-        match rtd.play_tx_dev.as_ref().unwrap().send(chunk) {
-            Ok(_) => {}
-            Err(err) => {
-                error!("{}", err.to_string());
-                return Err(Box::new(err));
-            }
-        }
+        rtd.leftovers_pos.store(0, Ordering::Relaxed);
         // updating data_to_write
         data_to_write = &data_to_write[bytes_from_data..];
     }
@@ -614,29 +1412,27 @@ pub fn do_write(rtd: &mut RuntimeData, java_buffer: &[u8], offset: usize, data_l
         trace!("PB: new chunk with data only: length: {}, chunk_bytes: {}", chunk.len(), chunk_bytes);
 
         chunk.copy_from_slice(&data_to_write[0..chunk_bytes]);
-        match rtd.play_tx_dev.as_ref().unwrap().send(chunk) {
-            Ok(_) => {}
-            Err(err) => {
-                error!("{}", err.to_string());
-                return Err(Box::new(err));
-            }
+        if !send_play_chunk(rtd, chunk, None)? {
+            // only the bytes sent so far were written
+            return Ok(data_len - data_to_write.len());
         }
         data_to_write = &data_to_write[chunk_bytes..];
     }
     if data_to_write.len() > 0 {
         // storing the leftovers
-        trace!("PB: storing to leftovers: leftovers length: {}, data_to_write length: {}", rtd.leftovers.len(), data_to_write.len());
+        trace!("PB: storing to leftovers: leftovers length: {}, data_to_write length: {}", buffers.leftovers.len(), data_to_write.len());
         leftovers_pos = data_to_write.len();
-        rtd.leftovers[0..leftovers_pos].copy_from_slice(data_to_write);
+        buffers.leftovers[0..leftovers_pos].copy_from_slice(data_to_write);
     }
     rtd.leftovers_pos.store(leftovers_pos, Ordering::Relaxed);
     Ok(data_len)
 }
 
-pub fn do_read(rtd: &mut RuntimeData, out_buffer: &mut [u8], offset: usize, data_len: usize) -> Res<usize> {
+pub fn do_read(rtd: &RuntimeData, out_buffer: &mut [u8], offset: usize, data_len: usize) -> Res<usize> {
     trace!("CAPT: do_read: input_buffer {} bytes, offset {} bytes, reading {} bytes", out_buffer.len(), offset, data_len);
+    let mut buffers = rtd.buffers.lock()?;
     let mut read_len = 0;
-    let mut expected_chunk_nbr = rtd.capt_last_chunk_nbr;
+    let mut expected_chunk_nbr = buffers.capt_last_chunk_nbr;
     let buffer = &mut out_buffer[offset..(offset + data_len)];
 
     // copying leftovers if any to the beginning of the output java_buffer
@@ -646,7 +1442,7 @@ pub fn do_read(rtd: &mut RuntimeData, out_buffer: &mut [u8], offset: usize, data
         if leftovers_pos <= data_len {
             trace!("CAPT: copying all {} leftover bytes to out buffer", leftovers_pos);
             // complete leftovers fit data_len, copying whole leftovers
-            buffer[0..leftovers_pos].copy_from_slice(&rtd.leftovers[0..leftovers_pos]);
+            buffer[0..leftovers_pos].copy_from_slice(&buffers.leftovers[0..leftovers_pos]);
             read_len += leftovers_pos;
             // cleared
             leftovers_pos = 0;
@@ -654,9 +1450,9 @@ pub fn do_read(rtd: &mut RuntimeData, out_buffer: &mut [u8], offset: usize, data
             trace!("CAPT: copying only data_len {} from total {} leftover bytes to out buffer",
                 data_len, leftovers_pos);
             // copying only data_len from leftovers
-            buffer[0..data_len].copy_from_slice(&rtd.leftovers[0..data_len]);
+            buffer[0..data_len].copy_from_slice(&buffers.leftovers[0..data_len]);
             // shifting remaining leftovers to start for next do_read
-            rtd.leftovers.copy_within(data_len.., 0);
+            buffers.leftovers.copy_within(data_len.., 0);
             leftovers_pos = leftovers_pos - data_len;
             trace!("CAPT: kept {} leftover bytes for the next do_read()", leftovers_pos);
             // out buffer is filled up
@@ -666,21 +1462,28 @@ pub fn do_read(rtd: &mut RuntimeData, out_buffer: &mut [u8], offset: usize, data
 
     // reading chunks from the inner loop
     while read_len < data_len {
-        // fully blocking
-        match rtd.capt_rx_dev.as_ref().unwrap().recv() {
+        if is_interrupted(rtd) {
+            debug!("CAPT: read interrupted by flush/close after {} bytes", read_len);
+            break;
+        }
+        // blocking, checking for interruption periodically
+        match rtd.capt_rx_dev.as_ref().unwrap().recv_timeout(INTERRUPT_CHECK_PERIOD) {
             Ok((chunk_nbr, data)) => {
                 trace!("CAPT: got chunk nbr {}, long {} bytes", chunk_nbr, data.len());
                 // 1 new + flushed in the meantime
-                expected_chunk_nbr += 1 + rtd.capt_flushed_cnt as u64;
-                // resetting
-                rtd.capt_flushed_cnt = 0;
+                expected_chunk_nbr += 1 + rtd.capt_flushed_cnt.swap(0, Ordering::Relaxed) as u64;
                 if chunk_nbr > expected_chunk_nbr {
                     warn!("CAPT: Samples were dropped, missing {} buffers", chunk_nbr - expected_chunk_nbr);
                     expected_chunk_nbr = chunk_nbr;
+                    rtd.capt_discontinuity.store(true, Ordering::Relaxed);
+                }
+                if rtd.capt_dev_discontinuity.swap(false, Ordering::Relaxed) {
+                    rtd.capt_discontinuity.store(true, Ordering::Relaxed);
                 }
                 let chunk_bytes = data.len();
                 let expected_chunk_bytes_for_exclusive = rtd.chunk_frames * rtd.frame_bytes;
-                if chunk_bytes != expected_chunk_bytes_for_exclusive {
+                // the engine packets of shared streams vary in size
+                if !rtd.shared && chunk_bytes != expected_chunk_bytes_for_exclusive {
                     warn!("CAPT: received chunk bytes {} do not correspond to expected chunk bytes {} for EXCLUSIVE access!!",
                         chunk_bytes, expected_chunk_bytes_for_exclusive);
                 }
@@ -701,24 +1504,33 @@ pub fn do_read(rtd: &mut RuntimeData, out_buffer: &mut [u8], offset: usize, data
                     // the rest goes to leftovers
                     leftovers_pos = chunk_bytes - available_space_bytes;
                     trace!("CAPT: copying the remaining {} bytes of the received chunk to leftovers", leftovers_pos);
-                    rtd.leftovers[0..leftovers_pos].copy_from_slice(&data[available_space_bytes..]);
+                    if buffers.leftovers.len() < leftovers_pos {
+                        // shared packets can exceed the chunk size
+                        buffers.leftovers.resize(leftovers_pos, 0);
+                    }
+                    buffers.leftovers[0..leftovers_pos].copy_from_slice(&data[available_space_bytes..]);
                 }
 
                 // Return the received buffer to the queue
                 rtd.capt_tx_prealloc.as_ref().unwrap().send(data)?;
             }
+            Err(RecvTimeoutError::Timeout) => {}
             Err(err) => {
+                if rtd.duplex_attached.as_ref().map_or(false, |attached| !attached.load(Ordering::Relaxed)) {
+                    let msg = "CAPT: the playback line driving the duplex stream was closed, line cannot capture anymore";
+                    return Err(NativeError::illegal_state(msg).with_device(&rtd.device_name));
+                }
                 error!("{}", err.to_string());
-                return Err(Box::new(err));
+                return Err(err.into());
             }
         }
     }
     // storing persistent data
     rtd.leftovers_pos.store(leftovers_pos, Ordering::Relaxed);
-    rtd.capt_last_chunk_nbr = expected_chunk_nbr;
+    buffers.capt_last_chunk_nbr = expected_chunk_nbr;
 
-    // returning only output_buffer, i.e. data_len
-    Ok(data_len)
+    // returning only the filled part of output_buffer, i.e. data_len unless interrupted
+    Ok(read_len)
 }
 
 pub fn do_get_avail_bytes(rtd: &RuntimeData, dir: &Direction) -> Res<usize> {
@@ -742,76 +1554,251 @@ pub fn do_get_avail_bytes(rtd: &RuntimeData, dir: &Direction) -> Res<usize> {
 pub fn do_get_byte_pos(rtd: &RuntimeData, dir: &Direction, java_byte_pos: u64) -> Res<u64> {
     check_direction_from_rt(rtd, &dir, "do_get_byte_pos")?;
     // TODO - reading extra data from audioclient?
+    let flushed_bytes = rtd.flushed_bytes.load(Ordering::Relaxed);
     let byte_pos = if *dir == Direction::Render {
         let queued_bytes = rtd.play_tx_dev.as_ref().unwrap().len() * rtd.chunk_frames * rtd.frame_bytes
             + rtd.leftovers_pos.load(Ordering::Relaxed);
+        // the padded drain chunk is queued in full, its padding was never sent by java
+        let padding_bytes = rtd.drain_padding_bytes.load(Ordering::Relaxed);
         // queued bytes are not played yet, however they are already part of java_byte_pos sent to native - must be subtracted
-        java_byte_pos - queued_bytes as u64
+        // flushed bytes were never played either
+        let played_bytes = (java_byte_pos + padding_bytes).saturating_sub(queued_bytes as u64 + flushed_bytes);
+        // rendered padding is silence beyond the java data
+        played_bytes.min(java_byte_pos.saturating_sub(flushed_bytes))
     } else {
         let queued_bytes = rtd.capt_rx_dev.as_ref().unwrap().len() * rtd.chunk_frames * rtd.frame_bytes
             + rtd.leftovers_pos.load(Ordering::Relaxed);
-        // already in java + what we already have captured in native
-        java_byte_pos + queued_bytes as u64
+        // already in java + what we already have captured in native + captured but flushed
+        java_byte_pos + queued_bytes as u64 + flushed_bytes
     };
     trace!("do_get_byte_pos: {}", byte_pos);
     Ok(byte_pos as u64)
 }
 
-pub fn do_close(rtd: &RuntimeData, dir: &Direction) -> Res<()> {
+pub fn do_close(rtd: &RuntimeData, dir: &Direction, timeout: Duration) -> Res<()> {
     check_direction_from_rt(rtd, dir, "do_close")?;
+    let _ctx_guard = enter_stream_context(&rtd.log_ctx);
     debug!("requested closing device {}", rtd.device_name);
+    // releasing java threads blocked in do_write/do_read
+    let first_close = !rtd.closing.swap(true, Ordering::Relaxed);
+    if let Some(stream) = rtd.mix_stream.as_ref() {
+        if !first_close {
+            debug!("device {} already closed", rtd.device_name);
+            return Ok(());
+        }
+        if !stream.release_line() {
+            if rtd.mix_owner {
+                // the loop keeps the stream running for the mixed lines, discarding the chunks of this line
+                stream.close_own_line();
+            } else {
+                // the loop detaches the line on the exit signal
+                rtd.exit_signal.store(true, Ordering::Relaxed);
+            }
+            log_event!(LogEvent::Close, Level::Info, "mixed line of device {} closed, {} lines keep the stream open",
+                rtd.device_name, stream.open_lines());
+            return Ok(());
+        }
+        // last line of the stream, no more lines join it
+        unregister_mix_bus(&rtd.device_id)?;
+        unregister_duplex_bus(&rtd.device_id)?;
+        rtd.exit_signal.store(true, Ordering::Relaxed);
+        let inner_handle = match stream.exit()? {
+            Some(handle) => handle,
+            None => {
+                debug!("device {} already closed", rtd.device_name);
+                return Ok(());
+            }
+        };
+        return join_inner(rtd, inner_handle, timeout);
+    }
     rtd.exit_signal.store(true, Ordering::Relaxed);
-    Ok(())
+    if let Some(attached) = rtd.duplex_attached.as_ref() {
+        // the playback loop of the render device releases the capture device on the exit signal
+        let deadline = Instant::now() + timeout;
+        while attached.load(Ordering::Relaxed) {
+            if Instant::now() > deadline {
+                let msg = format!("Closing: duplex playback loop did not release the device within {:?}", timeout);
+                return Err(NativeError::timeout(&msg).with_device(&rtd.device_name));
+            }
+            sleep(Duration::from_millis(5));
+        }
+        log_event!(LogEvent::Close, Level::Info, "duplex capture line of device {} closed", rtd.device_name);
+        return Ok(());
+    }
+    let inner_handle = match rtd.inner_handle.lock()?.take() {
+        Some(handle) => handle,
+        None => {
+            debug!("device {} already closed", rtd.device_name);
+            return Ok(());
+        }
+    };
+    join_inner(rtd, inner_handle, timeout)
 }
 
-pub fn do_drain(rtd: &RuntimeData) {
-    debug!("draining device {}", rtd.device_name);
-    if rtd.dir == Direction::Capture {
-        // stopping the capture device first
-        rtd.stop_signal.store(true, Ordering::Relaxed);
+fn join_inner(rtd: &RuntimeData, inner_handle: JoinHandle<Res<()>>, timeout: Duration) -> Res<()> {
+    // waiting for the inner thread to stop the stream and release the AudioClient
+    let deadline = Instant::now() + timeout;
+    while !inner_handle.is_finished() {
+        if Instant::now() > deadline {
+            // dropping the handle detaches the thread
+            let msg = format!("Closing: inner thread did not finish within {:?}", timeout);
+            return Err(NativeError::timeout(&msg).with_device(&rtd.device_name));
+        }
+        sleep(Duration::from_millis(5));
     }
-    loop {
-        if rtd.dir == Direction::Render {
-            if (rtd.play_tx_dev.as_ref().unwrap().len()) == 0 && rtd.bufferfill_bytes.load(Ordering::Relaxed) == 0 {
-                // card has already consumed all samples in the interthread and internal buffers
-                rtd.stop_signal.store(true, Ordering::Relaxed);
-                break;
+    match inner_handle.join() {
+        Ok(Ok(())) => {
+            log_event!(LogEvent::Close, Level::Info, "device {} closed", rtd.device_name);
+            for line in rtd.timings.format() {
+                info!("device {} loop timings: {}", rtd.device_name, line);
             }
-        } else {
-            if (rtd.capt_rx_dev.as_ref().unwrap().len()) == 0 {
-                // java has already consumed all captured data
-                break;
+            Ok(())
+        }
+        // error of the inner loop
+        Ok(Err(err)) => Err(err),
+        Err(_) => {
+            Err(NativeError::internal("Closing: inner thread panicked").with_device(&rtd.device_name))
+        }
+    }
+}
+
+/// Default drain timeout: duration of all queued chunks plus a safety margin
+pub fn get_default_drain_timeout(rtd: &RuntimeData) -> Duration {
+    let queue_chunks = if rtd.dir == Direction::Render {
+        rtd.play_tx_dev.as_ref().unwrap().capacity().unwrap()
+    } else {
+        rtd.capt_rx_dev.as_ref().unwrap().capacity().unwrap()
+    };
+    // queue + leftovers chunk + device buffer
+    let frames = (queue_chunks + 2) * rtd.chunk_frames;
+    Duration::from_millis((frames * 1000 / rtd.rate) as u64) + Duration::from_secs(1)
+}
+
+pub fn do_drain(rtd: &RuntimeData, timeout: Duration) -> Res<()> {
+    debug!("draining device {} with timeout {:?}", rtd.device_name, timeout);
+    let deadline = Instant::now() + timeout;
+    if rtd.dir == Direction::Render {
+        // stale confirmation from a previous timed-out drain
+        let rx_drained = rtd.play_rx_drained.as_ref().unwrap();
+        rx_drained.try_iter().count();
+        {
+            // waiting for a running do_write to finish
+            let buffers = rtd.buffers.lock()?;
+            // final partial chunk padded with silence
+            let leftovers_pos = rtd.leftovers_pos.load(Ordering::Relaxed);
+            if leftovers_pos > 0 {
+                let chunk_bytes = rtd.chunk_frames * rtd.frame_bytes;
+                trace!("PB: drain: padding {} leftover bytes to chunk of {} bytes", leftovers_pos, chunk_bytes);
+                let mut chunk = vec![0u8; chunk_bytes];
+                chunk[0..leftovers_pos].copy_from_slice(&buffers.leftovers[0..leftovers_pos]);
+                // the leftovers stay in place until sent, discarded and counted by a flush or kept after a timeout
+                if !send_play_chunk(rtd, chunk, Some(deadline))? {
+                    debug!("PB: drain of device {} interrupted by flush/close", rtd.device_name);
+                    return Ok(());
+                }
+                rtd.leftovers_pos.store(0, Ordering::Relaxed);
+                rtd.drain_padding_bytes.fetch_add((chunk_bytes - leftovers_pos) as u64, Ordering::Relaxed);
             }
         }
+        // inner loop stops the stream once the device has rendered all written frames
+        rtd.play_drain_signal.store(true, Ordering::Relaxed);
+        loop {
+            if is_interrupted(rtd) {
+                rtd.play_drain_signal.store(false, Ordering::Relaxed);
+                debug!("PB: drain of device {} interrupted by flush/close", rtd.device_name);
+                return Ok(());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match rx_drained.recv_timeout(cmp::min(remaining, INTERRUPT_CHECK_PERIOD)) {
+                Ok(()) => {
+                    // all queued chunks rendered, including the padding
+                    rtd.drain_padding_bytes.store(0, Ordering::Relaxed);
+                    debug!("PB: device {} drained", rtd.device_name);
+                    return Ok(());
+                }
+                Err(RecvTimeoutError::Timeout) if !remaining.is_zero() => {}
+                Err(err) => {
+                    rtd.play_drain_signal.store(false, Ordering::Relaxed);
+                    let msg = format!("PB: drain did not finish within {:?}: {}", timeout, err);
+                    return Err(NativeError::timeout(&msg).with_device(&rtd.device_name));
+                }
+            }
+        }
+    }
+    // stopping the capture device first
+    rtd.stop_signal.store(true, Ordering::Relaxed);
+    while rtd.capt_rx_dev.as_ref().unwrap().len() > 0 {
+        // java has not consumed all captured data yet
+        if Instant::now() > deadline {
+            let msg = format!("CAPT: drain did not finish within {:?}", timeout);
+            return Err(NativeError::timeout(&msg).with_device(&rtd.device_name));
+        }
         // checking situation every 5 ms
         sleep(Duration::from_millis(5));
     }
+    Ok(())
 }
 
-pub fn do_flush(rtd: &mut RuntimeData) -> Res<()> {
+pub fn do_flush(rtd: &RuntimeData) -> Res<()> {
     debug!("flushing device {}", rtd.device_name);
+    // blocked do_write/do_read return, releasing the buffers lock
+    rtd.flushing.store(true, Ordering::Relaxed);
+    let result = flush_queues(rtd);
+    rtd.flushing.store(false, Ordering::Relaxed);
+    // the inner thread waits until the queues are cleared
+    rtd.flush_signal.store(false, Ordering::Relaxed);
+    let was_running = result?;
+    if was_running {
+        debug!("restarting device {} after flush", rtd.device_name);
+        rtd.start_signal.store(true, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Returns true if the stream was running before flush
+fn flush_queues(rtd: &RuntimeData) -> Res<bool> {
+    // stale confirmation from a previous timed-out flush
+    rtd.rx_flushed.try_iter().count();
+    // the inner thread stops the stream and resets the device buffer
+    rtd.flush_signal.store(true, Ordering::Relaxed);
+    let was_running = match rtd.rx_flushed.recv_timeout(FLUSH_TIMEOUT) {
+        Ok(was_running) => was_running,
+        Err(err) => {
+            let msg = format!("Flushing: inner thread did not confirm within {:?}: {}", FLUSH_TIMEOUT, err);
+            return Err(NativeError::timeout(&msg).with_device(&rtd.device_name));
+        }
+    };
+    let _buffers = rtd.buffers.lock()?;
     // consuming all chunks in the interthread buffer
-    let cnt = if rtd.dir == Direction::Render {
-        rtd.play_draining_rx_dev.as_ref().unwrap().try_iter().count()
+    let (cnt, bytes) = if rtd.dir == Direction::Render {
+        let cnt = rtd.play_draining_rx_dev.as_ref().unwrap().try_iter().count();
+        (cnt, cnt * rtd.chunk_frames * rtd.frame_bytes)
     } else {
         let mut cnt = 0;
+        let mut bytes = 0;
         // received buffers must be returned to the prealloc channel
         for (_chunk_nbr, data) in rtd.capt_rx_dev.as_ref().unwrap().try_iter() {
+            bytes += data.len();
             rtd.capt_tx_prealloc.as_ref().unwrap().send(data)?;
             cnt += 1;
         }
-        rtd.capt_flushed_cnt += cnt;
-        cnt
+        rtd.capt_flushed_cnt.fetch_add(cnt, Ordering::Relaxed);
+        (cnt, bytes)
     };
-    trace!("flushed {} chunks from device {}", cnt, rtd.device_name);
-    Ok(())
+    let leftovers_bytes = rtd.leftovers_pos.swap(0, Ordering::Relaxed);
+    // a queued padded drain chunk was flushed with the others, otherwise its padding has been rendered
+    let padding_bytes = rtd.drain_padding_bytes.swap(0, Ordering::Relaxed);
+    let bytes = if cnt > 0 { (bytes as u64).saturating_sub(padding_bytes) } else { bytes as u64 };
+    rtd.flushed_bytes.fetch_add(bytes + leftovers_bytes as u64, Ordering::Relaxed);
+    trace!("flushed {} chunks and {} leftover bytes from device {}", cnt, leftovers_bytes, rtd.device_name);
+    Ok(was_running)
 }
 
 fn check_direction(device_dir: &Direction, checked_dir: &Direction, device_id: &str, fn_name: &str) -> Res<()> {
     if device_dir != checked_dir {
         let msg = format!("Called {} for device ID {} with wrong direction {}",
                           fn_name, device_id, checked_dir);
-        return Err(msg.into());
+        return Err(NativeError::illegal_argument(&msg));
     }
     Ok(())
 }
@@ -823,42 +1810,107 @@ fn check_direction_from_rt(rtd: &RuntimeData, dir: &Direction, fn_name: &str) ->
 pub fn device_open(
     device_id: &str,
     dir: &Direction, rate: usize, validbits: usize, frame_bytes: usize,
-    channels: usize, dev_period: i64) -> Res<(
+    channels: usize, dev_period: i64, wait_mode: WaitMode) -> Res<(
     Device,
-    AudioClient,
-    Handle,
+    StreamClient,
+    // None when initialized without event callbacks for polling
+    Option<EventHandle>,
+    // Some in shared mode
+    Option<SampleConverter>,
+    // frames transferred per period
+    usize,
 )> {
-    let sharemode = ShareMode::Exclusive;
-    let (device, dev_name, mut audio_client) = get_device_details(&device_id, &dir)?;
-
-    let wvformats = get_possible_formats(8 * frame_bytes / channels, validbits, rate, channels)?;
-    let wvformat = match find_supported_format(&dev_name, &audio_client, wvformats) {
-        Some(ok_wvformat) => {
-            debug!("Opening {} device {}: will use format {:?}", dir, dev_name, ok_wvformat);
-            ok_wvformat
+    let (device, dev_name, audio_client, sharemode) = get_device_details(&device_id, &dir)?;
+    // the polling mode initializes the client without event callbacks
+    let event_driven = wait_mode != WaitMode::Poll;
+
+    let (wvformat, converter, buffer_duration, convert_rate) = match sharemode {
+        ShareMode::Exclusive => {
+            let wvformats = get_possible_formats(8 * frame_bytes / channels, validbits, rate, channels)?;
+            let wvformat = match find_supported_format(&dev_name, &audio_client, wvformats) {
+                Some(ok_wvformat) => {
+                    debug!("Opening {} device {}: will use format {:?}", dir, dev_name, ok_wvformat);
+                    ok_wvformat
+                }
+                None => {
+                    let msg = format!("Opening {} device {}: no supported format found", dir, dev_name);
+                    return Err(NativeError::new(ErrorKind::UnsupportedFormat, &msg).with_device(&dev_name));
+                }
+            };
+            // the polled buffer holds two periods, the event-driven buffer is handed over whole every period
+            let buffer_duration = if event_driven { dev_period } else { 2 * dev_period };
+            (wvformat, None, buffer_duration, false)
         }
-        None => {
-            let msg = format!("Opening {} device {}: no supported format found", dir, dev_name);
-            return Err(msg.into());
+        ShareMode::Shared => {
+            let (wvformat, converter, convert_rate) =
+                get_shared_format(&audio_client, &dev_name, dir, rate, frame_bytes / channels, validbits, channels)?;
+            // two periods of buffer, the loop refills one period per event
+            (wvformat, Some(converter), 2 * dev_period, convert_rate)
         }
     };
-    match audio_client.initialize_client(
+    let (stream_client, handle) = match StreamClient::initialize(
+        &device,
         &wvformat,
+        buffer_duration,
         dev_period,
-        &dir,
         &sharemode,
-        false,
+        convert_rate,
+        event_driven,
     ) {
-        Ok(_) => {}
+        Ok(initialized) => initialized,
         Err(err) => {
-            error!("Calling method audio_client.initialize_client failed: {:?}\n", err);
+            let err = err.with_device(&dev_name);
+            error!("Initializing the stream client failed: {}\n", err);
             return Err(err);
         }
     };
     debug!("initialized {} device {} with device period {} and format {:?}", dir, device_id, dev_period, wvformat);
-    let handle = audio_client.set_get_eventhandle()?;
-    debug!("Opened Wasapi device {} in {}", dev_name, dir);
-    Ok((device, audio_client, handle))
+    let chunk_frames = if converter.is_none() && event_driven {
+        stream_client.get_bufferframecount()? as usize
+    } else {
+        // the buffer holds several periods, the loop transfers one period per chunk
+        (rate as i64 * dev_period / 10_000_000) as usize
+    };
+    debug!("Opened Wasapi device {} in {} {:?} mode, {}", dev_name, dir, sharemode,
+        if event_driven { "event-driven" } else { "polled" });
+    Ok((device, stream_client, handle, converter, chunk_frames))
+}
+
+/// Engine mix format (sample type, bits, channels) at the java rate, with the converter from the java format
+/// (playback) or to the java format (capture) and whether the engine must resample
+fn get_shared_format(audio_client: &AudioClient, dev_name: &str, dir: &Direction, rate: usize, sample_bytes: usize,
+                     validbits: usize, channels: usize)
+                     -> Res<(WaveFormat, SampleConverter, bool)> {
+    let mix_format = audio_client.get_mixformat()?;
+    debug!("Shared device {}: mix format {:?}", dev_name, mix_format);
+    let storebits = mix_format.get_bitspersample() as usize;
+    let sample_type = mix_format.get_subformat()?;
+    let dev_sample_format = match sample_type {
+        SampleType::Float if storebits == 32 => DevSampleFormat::Float32,
+        SampleType::Int if [16, 24, 32].contains(&storebits) => DevSampleFormat::Int(storebits / 8),
+        _ => {
+            let msg = format!("Shared device {}: unsupported mix format {:?}", dev_name, mix_format);
+            return Err(NativeError::new(ErrorKind::UnsupportedFormat, &msg).with_device(dev_name));
+        }
+    };
+    let dev_channels = mix_format.get_nchannels() as usize;
+    let mut wvformat = WaveFormat::new(
+        storebits,
+        mix_format.get_validbitspersample() as usize,
+        &sample_type,
+        rate,
+        dev_channels,
+        None,
+    );
+    wvformat.wave_fmt.dwChannelMask = mix_format.wave_fmt.dwChannelMask;
+    let converter = match dir {
+        Direction::Render => SampleConverter::new(sample_bytes, channels, dev_sample_format, dev_channels),
+        Direction::Capture => SampleConverter::from_device(dev_sample_format, dev_channels, sample_bytes, validbits, channels),
+    };
+    debug!("Shared device {}: converting java frames with {:?}", dev_name, converter);
+    // resampled by the engine (AUTOCONVERTPCM)
+    let convert_rate = rate != mix_format.get_samplespersec() as usize;
+    Ok((wvformat, converter, convert_rate))
 }
 
 fn find_supported_format(dev_name: &str, audio_client: &AudioClient, wvformats: Vec<WaveFormat>) -> Option<WaveFormat> {
@@ -876,186 +1928,457 @@ fn find_supported_format(dev_name: &str, audio_client: &AudioClient, wvformats:
     None
 }
 
-fn get_device_details(device_id: &str, dir: &Direction) -> Res<(Device, String, AudioClient)> {
-    let (device, dev_dir) = get_device_by_id(&device_id)?;
+/// Frames writable to the device buffer, from the padding as the polling mode cannot rely on events
+fn get_writable_frames(audio_client: &StreamClient) -> Res<usize> {
+    Ok((audio_client.get_bufferframecount()? - audio_client.get_current_padding()?) as usize)
+}
+
+fn get_device_details(device_id: &str, dir: &Direction) -> Res<(Device, String, AudioClient, ShareMode)> {
+    let (device, dev_dir, sharemode) = get_device_by_id(&device_id)?;
     check_direction(&dev_dir, &dir, &device_id, "device_open")?;
     let dev_name = device.get_friendlyname()?;
     debug!("Found device {}", dev_name);
     let audio_client = device.get_iaudioclient()?;
     trace!("Got iaudioclient");
-    Ok((device, dev_name, audio_client))
+    Ok((device, dev_name, audio_client, sharemode))
 }
 
 
 // Playback loop, play samples received from channel
 fn playback_loop(
-    audio_client: AudioClient,
-    handle: Handle,
+    audio_client: StreamClient,
+    handle: Option<EventHandle>,
+    // Some in shared mode
+    converter: Option<SampleConverter>,
+    mut mix_bus: MixBus,
+    mut duplex: DuplexBus,
+    mut gain: GainStage,
     frame_bytes: usize,
+    channels: usize,
     chunk_frames: usize,
     samplerate: usize,
     sync: PlaySyncData,
 ) -> Res<()> {
     let tx_cb = sync.tx_cb;
-    let mut callbacks = wasapi::EventCallbacks::new();
-    callbacks.set_disconnected_callback(move |reason| {
-        debug!("PB INNER: Disconnected, reason: {:?}", reason);
-        let simplereason = match reason {
-            DisconnectReason::FormatChanged => Disconnected::FormatChange,
-            _ => Disconnected::Error,
-        };
-        tx_cb.send(simplereason).unwrap_or(());
-    });
-    let callbacks_rc = Rc::new(callbacks);
-    let callbacks_weak = Rc::downgrade(&callbacks_rc);
+    // the callback is called from a COM thread
+    let log_ctx = current_stream_context();
+    let _session = audio_client.on_disconnected(move |reason| {
+        let _ctx_guard = log_ctx.as_ref().map(enter_stream_context);
+        log_event!(target: PB_LOOP_TARGET, LogEvent::Disconnect, Level::Warn, "PB INNER: Disconnected, reason: {:?}", reason);
+        tx_cb.send(Disconnected::from_reason(reason)).unwrap_or(());
+    })?;
     let clock = audio_client.get_audioclock()?;
 
-    let sessioncontrol = audio_client.get_audiosessioncontrol()?;
-    sessioncontrol.register_session_notification(callbacks_weak)?;
-
     // let mut waited_millis = 0;
-    // trace!("Waiting for data to start playback, will time out after one second");
+    // trace!(target: PB_LOOP_TARGET, "Waiting for data to start playback, will time out after one second");
     // while sync.rx_play.len() < 2 && waited_millis < 1000 {
     //     thread::sleep(Duration::from_millis(10));
     //     waited_millis += 10;
     // }
-    // debug!("Waited for data for {} ms", waited_millis);
+    // debug!(target: PB_LOOP_TARGET, "Waited for data for {} ms", waited_millis);
 
     // Raise priority
-    let mut task_idx = 0;
-    unsafe {
-        let _res = AvSetMmThreadCharacteristicsW(PCWSTR::from(&"Pro Audio".into()), &mut task_idx);
-    }
-    if task_idx > 0 {
-        trace!("PB INNER: thread raised priority, task index: {}", task_idx);
-    } else {
-        warn!("PB INNER: Failed to raise thread priority");
-    }
+    let mut boost = ThreadBoost::new();
+    sync.thread_config_signal.store(false, Ordering::Relaxed);
+    let config = *sync.thread_config.lock()?;
+    boost.apply(&config, &sync.thread_status, "PB INNER");
 
     audio_client.stop_stream()?;
     let mut running = false;
-    let mut time_tracker = DeviceTimeTracker::new("PB INNER".into());
+    let mut time_tracker = DeviceTimeTracker::new("PB INNER".into(), sync.clock_drift.clone());
     let device_freq = clock.get_frequency()? as f64;
     let render_client = audio_client.get_audiorenderclient()?;
     //let file_res: Result<Box<dyn Write>, std::io::Error> = File::create("inner.raw").map(|f| Box::new(f) as Box<dyn Write>);
     //let mut file = file_res.unwrap();
+    // chunk written to the device instead of missing data, allocated outside of the loop
+    let mut fill_chunk = vec![0u8; chunk_frames * frame_bytes];
+    // chunk mixed with other lines or with gain applied
+    let mut mix_chunk = vec![0u8; chunk_frames * frame_bytes];
+    // chunk converted to the engine format in shared mode
+    let mut dev_chunk = converter.as_ref().map(|conv| vec![0u8; chunk_frames * conv.dst_frame_bytes()]);
+    let mut last_chunk: Option<Vec<u8>> = None;
+    let mut in_underrun = false;
+    // stream stopped by UnderrunPolicy::Stop, an underrun only if the data resume without a stop/drain/flush
+    let mut starved_stop = false;
+    // frames written to the device since the stream start/reset, comparable with the clock position
+    let mut written_frames: u64 = 0;
+    let mut drain_target_frames: Option<u64> = None;
+    // fading out before stopping, then stopping at the next loop start
+    let mut fade = FadeStop::default();
+    // the line is stopped or closed while the stream keeps running for the mixed lines
+    let mut idle = false;
+    // the flush was confirmed, waiting for the outer side to clear the queue
+    let mut flush_confirmed = false;
+    // expected interval between device events
+    let period = Duration::from_secs_f64(chunk_frames as f64 / samplerate as f64);
+    let mut waiter = DeviceWaiter::new(handle, sync.wait_mode.clone(), period, "PB INNER");
+    let is_writable = || -> Res<bool> { Ok(get_writable_frames(&audio_client)? >= chunk_frames) };
     let mut now = Instant::now();
     loop {
         let buffer_free_frames = audio_client.get_available_space_in_frames()?;
-        trace!("PB INNER: New buffer frame count {}", buffer_free_frames);
+        trace!(target: PB_LOOP_TARGET, "PB INNER: New buffer frame count {}", buffer_free_frames);
+
+        // other lines playing through this stream
+        let mixing = mix_bus.service();
+        let own_closed = mix_bus.own_closed();
+        if own_closed {
+            // the stream runs until the last mixed line closes, the chunks left in the queue are not played
+            idle = true;
+            sync.rx_dev.try_iter().count();
+        }
 
         if sync.start_signal.load(Ordering::Relaxed) {
-            debug!("PB INNER: Starting inner loop, {}", if running {"stream is already running"} else {"starting stream"});
+            debug!(target: PB_LOOP_TARGET, "PB INNER: Starting inner loop, {}", if running {"stream is already running"} else {"starting stream"});
+            fade.start(&mut gain, running && !idle);
             if !running {
-                audio_client.start_stream()?;
+                duplex.start(&audio_client)?;
                 running = true;
                 time_tracker.reset();
             }
+            idle = false;
             sync.start_signal.store(false, Ordering::Relaxed);
             // staying in the loop
         }
-        if sync.stop_signal.load(Ordering::Relaxed) {
-            debug!("PB INNER: Stopping inner loop");
-            if running {
-                audio_client.stop_stream()?;
+        if sync.thread_config_signal.swap(false, Ordering::Relaxed) {
+            let config = *sync.thread_config.lock()?;
+            debug!(target: PB_LOOP_TARGET, "PB INNER: Applying thread config {:?}", config);
+            boost.apply(&config, &sync.thread_status, "PB INNER");
+        }
+        // the queued data keep playing until faded out
+        if fade.stop_requested(&sync.stop_signal, &mut gain, running && !idle) {
+            debug!(target: PB_LOOP_TARGET, "PB INNER: Stopping inner loop");
+            // the line ran out of data at its normal end
+            starved_stop = false;
+            if running && mixing {
+                debug!(target: PB_LOOP_TARGET, "PB INNER: stream keeps running for the mixed lines");
+                idle = true;
+                in_underrun = false;
+            } else if running {
+                duplex.stop(&audio_client)?;
                 running = false;
+                idle = false;
+                in_underrun = false;
                 time_tracker.reset();
             }
-            sync.stop_signal.store(false, Ordering::Relaxed);
+            if drain_target_frames.is_some() {
+                // stopping finishes the pending drain
+                drain_target_frames = None;
+                sync.drain_signal.store(false, Ordering::Relaxed);
+                sync.tx_drained.try_send(()).unwrap_or(());
+            }
             // staying in the loop
         }
         if sync.exit_signal.load(Ordering::Relaxed) {
-            debug!("PB INNER: Exiting inner loop");
+            debug!(target: PB_LOOP_TARGET, "PB INNER: Exiting inner loop");
             audio_client.stop_stream()?;
             sync.exit_signal.store(false, Ordering::Relaxed);
             //file.flush();
             return Ok(());
         }
+        if !sync.flush_signal.load(Ordering::Relaxed) {
+            flush_confirmed = false;
+        } else if !flush_confirmed {
+            flush_confirmed = true;
+            let was_running = running && !idle;
+            if running && mixing {
+                // the mixed lines keep playing, the chunks of this line already in the device buffer play out
+                debug!(target: PB_LOOP_TARGET, "PB INNER: Flushing, stream keeps running for the mixed lines");
+                idle = true;
+            } else {
+                debug!(target: PB_LOOP_TARGET, "PB INNER: Flushing, {}", if running {"stopping and resetting stream"} else {"resetting stream"});
+                if running {
+                    duplex.stop(&audio_client)?;
+                    running = false;
+                    idle = false;
+                }
+                // discarding samples in the device buffer
+                audio_client.reset_stream()?;
+                time_tracker.reset();
+                written_frames = 0;
+                mix_bus.stream_reset();
+            }
+            in_underrun = false;
+            starved_stop = false;
+            fade.cancel();
+            last_chunk = None;
+            sync.wasapi_bufferfill_bytes.store(0, Ordering::Relaxed);
+            if drain_target_frames.is_some() {
+                // nothing left to drain
+                drain_target_frames = None;
+                sync.drain_signal.store(false, Ordering::Relaxed);
+                sync.tx_drained.try_send(()).unwrap_or(());
+            }
+            // the outer side clears the queue and restarts the stream
+            sync.tx_flushed.try_send(was_running).unwrap_or(());
+            // not consuming chunks until the outer side has cleared the queue, the mixed lines keep playing meanwhile
+            while !running && sync.flush_signal.load(Ordering::Relaxed) && !sync.exit_signal.load(Ordering::Relaxed) {
+                sleep(Duration::from_millis(1));
+            }
+        }
 
 
+        if duplex.service() && running {
+            debug!(target: PB_LOOP_TARGET, "PB INNER: restarting stream to start the duplex capture in sync");
+            duplex.stop(&audio_client)?;
+            duplex.start(&audio_client)?;
+            time_tracker.reset();
+        }
+
         // reading from data channel with timeout 5ms
-        let chunk = match sync.rx_dev.recv_timeout(Duration::from_millis(5)) {
+        let mut write_fill = false;
+        let received = if own_closed || sync.flush_signal.load(Ordering::Relaxed) {
+            sleep(Duration::from_millis(5));
+            Err(RecvTimeoutError::Timeout)
+        } else {
+            sync.rx_dev.recv_timeout(Duration::from_millis(5))
+        };
+        let mut chunk = match received {
             Ok(chunk) => {
-                trace!("PB INNER: got chunk");
+                trace!(target: PB_LOOP_TARGET, "PB INNER: got chunk");
+                if starved_stop {
+                    // data resumed after stopping the starved stream, the gap was audible
+                    log_event!(target: PB_LOOP_TARGET, LogEvent::Underrun, Level::Debug, "PB INNER: data resumed after underrun stop");
+                    sync.underruns.record(0);
+                    starved_stop = false;
+                }
                 if !running {
-                    warn!("PB INNER: received chunk in stopped device, starting automatically!");
-                    audio_client.start_stream()?;
+                    warn!(target: PB_LOOP_TARGET, "PB INNER: received chunk in stopped device, starting automatically!");
+                    duplex.start(&audio_client)?;
                     running = true;
                     time_tracker.reset();
+                    gain.fade_in();
+                } else if idle {
+                    debug!(target: PB_LOOP_TARGET, "PB INNER: received chunk in stopped line, mixing it again");
+                    gain.fade_in();
+                }
+                idle = false;
+                if in_underrun {
+                    debug!(target: PB_LOOP_TARGET, "PB INNER: underrun finished, data available again");
+                    in_underrun = false;
                 }
                 Some(chunk)
             }
             Err(RecvTimeoutError::Timeout) => {
-                trace!("PB INNER: chunk receive timed out, no data");
+                trace!(target: PB_LOOP_TARGET, "PB INNER: chunk receive timed out, no data");
                 // sleeping is provided by recv_timeout(timeout)
-                if running {
-                    audio_client.stop_stream()?;
+                if sync.drain_signal.load(Ordering::Relaxed) {
+                    if !running || idle {
+                        debug!(target: PB_LOOP_TARGET, "PB INNER: drain requested in stopped stream, nothing to render");
+                        starved_stop = false;
+                        sync.drain_signal.store(false, Ordering::Relaxed);
+                        sync.tx_drained.try_send(()).unwrap_or(());
+                    } else {
+                        if drain_target_frames.is_none() {
+                            // all data incl. the final chunk are written, waiting for the device to render them
+                            debug!(target: PB_LOOP_TARGET, "PB INNER: draining, waiting for the device to render {} frames", written_frames);
+                            drain_target_frames = Some(written_frames);
+                        }
+                        // keeping the stream running with silence, not an underrun
+                        fill_chunk.fill(0);
+                        write_fill = true;
+                    }
+                } else if mixing {
+                    // the stream keeps running for the mixed lines, this line contributes silence
+                    if !running {
+                        debug!(target: PB_LOOP_TARGET, "PB INNER: starting stream for the mixed lines");
+                        duplex.start(&audio_client)?;
+                        running = true;
+                        time_tracker.reset();
+                        // the next chunks of this line start from silence
+                        idle = true;
+                    }
+                    fill_chunk.fill(0);
+                    write_fill = true;
+                } else if running && idle {
+                    // the mixed lines have stopped, nothing left to play
+                    debug!(target: PB_LOOP_TARGET, "PB INNER: no line playing, stopping stream");
+                    duplex.stop(&audio_client)?;
                     running = false;
+                    idle = false;
                     time_tracker.reset();
+                } else if running {
+                    // only valid policies are stored by do_set_underrun_policy
+                    let policy = UnderrunPolicy::try_from(sync.underrun_policy.load(Ordering::Relaxed)).unwrap_or(UnderrunPolicy::Stop);
+                    if policy == UnderrunPolicy::Stop {
+                        // counted only when the data resume, running out of data at the end of playback is no underrun
+                        debug!(target: PB_LOOP_TARGET, "PB INNER: no data, stopping stream");
+                        starved_stop = true;
+                        duplex.stop(&audio_client)?;
+                        running = false;
+                        time_tracker.reset();
+                    } else {
+                        write_fill = true;
+                        if !in_underrun {
+                            log_event!(target: PB_LOOP_TARGET, LogEvent::Underrun, Level::Warn, "PB INNER: underrun, writing {:?} until data available", policy);
+                            in_underrun = true;
+                            sync.underruns.record(chunk_frames);
+                            match (policy, last_chunk.as_ref()) {
+                                (UnderrunPolicy::RepeatFade, Some(last)) => {
+                                    fill_chunk.copy_from_slice(last);
+                                    apply_ramp(&mut fill_chunk, frame_bytes / channels, channels, 1.0, 0.0);
+                                }
+                                _ => fill_chunk.fill(0),
+                            }
+                        } else {
+                            sync.underruns.add_frames(chunk_frames);
+                            // the faded chunk is repeated only once
+                            fill_chunk.fill(0);
+                        }
+                    }
                 }
                 None
             }
+            Err(RecvTimeoutError::Disconnected) if mix_bus.own_closed() => {
+                // the line was closed while waiting, the stream keeps running for the mixed lines
+                None
+            }
             Err(RecvTimeoutError::Disconnected) => {
                 // while inner was waiting, the outer loop could have been closed
                 return if sync.exit_signal.load(Ordering::Relaxed) {
-                    debug!("PB INNER: Exiting inner loop");
+                    debug!(target: PB_LOOP_TARGET, "PB INNER: Exiting inner loop");
                     audio_client.stop_stream()?;
                     sync.exit_signal.store(false, Ordering::Relaxed);
                     //file.flush();
                     Ok(())
                 } else {
                     let msg = "PB INNER: data channel is closed although no exit was requested";
-                    error!("{}", msg);
+                    error!(target: PB_LOOP_TARGET, "{}", msg);
                     if running {
                         audio_client.stop_stream()?;
                     }
-                    Err(DeviceError::new(msg).into())
+                    Err(NativeError::internal(msg))
                 };
             }
         };
-        trace!("PB INNER: loop spent outside of wait_for_event {:?}", now.elapsed());
+        let outside = now.elapsed();
+        trace!(target: PB_LOOP_TARGET, "PB INNER: loop spent outside of wait_for_event {:?}", outside);
         now = Instant::now();
-        if chunk.is_some() {
-            let chunk = chunk.unwrap();
-            //let write_res = file.write_all(chunk.as_slice());
-            render_client.write_to_device(
-                chunk_frames,
-                frame_bytes,
-                chunk.as_slice(),
-                None,
-            )?;
+        if let Some(chunk) = chunk.as_mut() {
+            gain.process(chunk);
+        }
+        let data = match chunk.as_ref() {
+            Some(chunk) => Some(chunk.as_slice()),
+            None if write_fill => Some(fill_chunk.as_slice()),
+            None => None,
+        };
+        if let Some(data) = data {
+            let data = if mix_bus.mix(data, &mut mix_chunk, written_frames) {
+                mix_chunk.as_slice()
+            } else {
+                data
+            };
+            //let write_res = file.write_all(data);
+            match (converter.as_ref(), dev_chunk.as_mut()) {
+                (Some(conv), Some(dev_chunk)) => {
+                    // the shared buffer can still hold previous chunks, waiting for room for the whole chunk
+                    while (audio_client.get_available_space_in_frames()? as usize) < chunk_frames {
+                        if !waiter.wait(Duration::from_millis(1000), is_writable)? {
+                            error!(target: PB_LOOP_TARGET, "PB INNER: Error on shared playback, stopping stream");
+                            audio_client.stop_stream()?;
+                            return Err(NativeError::device("PB INNER: Error on shared playback"));
+                        }
+                    }
+                    conv.convert(data, dev_chunk);
+                    render_client.write_to_device(
+                        chunk_frames,
+                        conv.dst_frame_bytes(),
+                        dev_chunk,
+                    )?;
+                }
+                _ => {
+                    render_client.write_to_device(
+                        chunk_frames,
+                        frame_bytes,
+                        data,
+                    )?;
+                }
+            }
+            written_frames += chunk_frames as u64;
+            duplex.rendered(chunk_frames, chunk.is_some());
+            LineStats::inc(&sync.stats.chunks);
             // for reporting position
             sync.wasapi_bufferfill_bytes.store(chunk_frames * frame_bytes, Ordering::Relaxed);
-            trace!("PB INNER: write ok, loop spent writing data to device {:?}", now.elapsed());
+            let writing = now.elapsed();
+            trace!(target: PB_LOOP_TARGET, "PB INNER: write ok, loop spent writing data to device {:?}", writing);
+            // recording only loops with a device transfer, the idle ones wait in recv_timeout
+            sync.timings.record(TimingPhase::Outside, outside);
+            sync.timings.record(TimingPhase::Transfer, writing);
             now = Instant::now();
-            if handle.wait_for_event(1000).is_err() {
-                error!("PB INNER: Error on playback, stopping stream");
+            if !waiter.wait(Duration::from_millis(1000), is_writable)? {
+                error!(target: PB_LOOP_TARGET, "PB INNER: Error on playback, stopping stream");
                 audio_client.stop_stream()?;
-                return Err(DeviceError::new("PB INNER: Error on playback").into());
+                return Err(NativeError::device("PB INNER: Error on playback"));
             }
-            trace!("PB INNER: loop spent in wait_for_event {:?}", now.elapsed());
+            let waited = now.elapsed();
+            sync.stats.record_wait(waited);
+            sync.timings.record(TimingPhase::Wait, waited);
+            if let Some(interval) = time_tracker.event_interval() {
+                sync.timings.record_interval(interval, period);
+            }
+            trace!(target: PB_LOOP_TARGET, "PB INNER: loop spent in wait_for_event {:?}", waited);
+            // capture periods of a duplex pair, part of the next outside time
+            duplex.capture()?;
             now = Instant::now();
             // buffer empty
             sync.wasapi_bufferfill_bytes.store(0, Ordering::Relaxed);
         }
-        let pos = clock.get_position()?.0;
+        // nothing left to fade, stopping at the next loop start
+        fade.processed(&gain, chunk.is_some());
+        if chunk.is_some() {
+            // kept for RepeatFade underrun policy
+            last_chunk = chunk;
+        }
+        let (pos, qpc_pos) = clock.get_position()?;
         let device_time = pos as f64 / device_freq;
-        if time_tracker.event_missing(device_time, buffer_free_frames as f64 / samplerate as f64) {
-            warn!("PB INNER: Missed event");
+        if running {
+            time_tracker.track_drift(device_time, qpc_pos);
+        }
+        let rendered_frames = (device_time * samplerate as f64) as u64;
+        // the mixed lines drain on the same clock
+        mix_bus.rendered(rendered_frames, running);
+        if let Some(target_frames) = drain_target_frames {
+            if rendered_frames >= target_frames || !running {
+                debug!(target: PB_LOOP_TARGET, "PB INNER: drained, device rendered {} frames of {}", rendered_frames, target_frames);
+                if running {
+                    duplex.stop(&audio_client)?;
+                    running = false;
+                    time_tracker.reset();
+                }
+                drain_target_frames = None;
+                sync.drain_signal.store(false, Ordering::Relaxed);
+                sync.tx_drained.try_send(()).unwrap_or(());
+                continue;
+            }
+        }
+        // the shared engine absorbs late writes, events are not paced by this stream alone, a polled client has no events
+        if converter.is_none() && audio_client.is_event_driven() && time_tracker.event_missing(device_time, buffer_free_frames as f64 / samplerate as f64).is_some() {
+            warn!(target: PB_LOOP_TARGET, "PB INNER: Missed event");
+            LineStats::inc(&sync.stats.missed_events);
             if running {
-                warn!("PB INNER: resetting stream");
-                audio_client.stop_stream()?;
+                warn!(target: PB_LOOP_TARGET, "PB INNER: resetting stream");
+                LineStats::inc(&sync.stats.stream_resets);
+                duplex.stop(&audio_client)?;
                 audio_client.reset_stream()?;
-                audio_client.start_stream()?;
+                duplex.start(&audio_client)?;
                 time_tracker.reset();
+                // clock position restarts from zero
+                written_frames = 0;
+                mix_bus.stream_reset();
+                if drain_target_frames.is_some() {
+                    // unplayed data were discarded by the reset
+                    drain_target_frames = Some(0);
+                }
             }
         }
     }
 }
 
 fn capture_loop(
-    audio_client: AudioClient,
-    handle: Handle,
+    audio_client: StreamClient,
+    handle: Option<EventHandle>,
+    // Some in shared mode
+    converter: Option<SampleConverter>,
+    mut gain: GainStage,
     frame_bytes: usize,
     chunk_frames: usize,
     samplerate: usize,
@@ -1063,24 +2386,18 @@ fn capture_loop(
 ) -> Res<()> {
     let mut chunk_nbr: u64 = 0;
 
-    let mut callbacks = wasapi::EventCallbacks::new();
-    callbacks.set_disconnected_callback(move |reason| {
-        debug!("CAPT INNER: disconnected, reason: {:?}", reason);
-        let simplereason = match reason {
-            DisconnectReason::FormatChanged => Disconnected::FormatChange,
-            _ => Disconnected::Error,
-        };
-        sync.tx_cb.send(simplereason).unwrap_or(());
-    });
-
-    let callbacks_rc = Rc::new(callbacks);
-    let callbacks_weak = Rc::downgrade(&callbacks_rc);
-    let mut time_tracker = DeviceTimeTracker::new("CAPT INNER".into());
+    // cloned, the sync data are passed whole to the chunk helpers
+    let tx_cb = sync.tx_cb.clone();
+    // the callback is called from a COM thread
+    let log_ctx = current_stream_context();
+    let _session = audio_client.on_disconnected(move |reason| {
+        let _ctx_guard = log_ctx.as_ref().map(enter_stream_context);
+        log_event!(target: CAPT_LOOP_TARGET, LogEvent::Disconnect, Level::Warn, "CAPT INNER: disconnected, reason: {:?}", reason);
+        tx_cb.send(Disconnected::from_reason(reason)).unwrap_or(());
+    })?;
+    let mut time_tracker = DeviceTimeTracker::new("CAPT INNER".into(), sync.clock_drift.clone());
     let clock = audio_client.get_audioclock()?;
 
-    let sessioncontrol = audio_client.get_audiosessioncontrol()?;
-    sessioncontrol.register_session_notification(callbacks_weak)?;
-
     audio_client.stop_stream()?;
     let mut running = false;
     let mut inactive = false;
@@ -1088,37 +2405,47 @@ fn capture_loop(
     let mut saved_buffer: Option<Vec<u8>> = None;
 
     // Raise priority
-    let mut task_idx = 0;
-    unsafe {
-        let _res = AvSetMmThreadCharacteristicsW(PCWSTR::from(&"Pro Audio".into()), &mut task_idx);
-    }
-    if task_idx > 0 {
-        trace!("CAPT INNER: thread raised priority, task index: {}", task_idx);
-    } else {
-        warn!("CAPT INNER: Failed to raise thread priority");
-    }
+    let mut boost = ThreadBoost::new();
+    sync.thread_config_signal.store(false, Ordering::Relaxed);
+    let config = *sync.thread_config.lock()?;
+    boost.apply(&config, &sync.thread_status, "CAPT INNER");
     let device_freq = clock.get_frequency()? as f64;
     let max_duration = Duration::from_millis(100);
     let sleep_duration = Duration::from_millis(2);
 
     let capture_client = audio_client.get_audiocaptureclient()?;
-    //trace!("Starting capture stream");
+    //trace!(target: CAPT_LOOP_TARGET, "Starting capture stream");
     audio_client.stop_stream()?;
     let available_frames = audio_client.get_available_space_in_frames()?;
-    trace!("CAPT INNER: Available frames from dev: {}", available_frames);
-    if available_frames as usize != chunk_frames {
-        error!("CAPT INNER: available_frames {} != chunk_frames {} in EXCLUSIVE mode, failure in wasapi!", available_frames, chunk_frames);
-        return Err(DeviceError::new("CAPT INNER: Misbehaving EXCLUSIVE mode").into());
+    trace!(target: CAPT_LOOP_TARGET, "CAPT INNER: Available frames from dev: {}", available_frames);
+    // the shared buffer holds several engine packets, read as they come
+    let mut dev_chunk = match converter.as_ref() {
+        Some(conv) => Some(vec![0u8; audio_client.get_bufferframecount()? as usize * conv.src_frame_bytes()]),
+        None => None,
+    };
+    // the polled exclusive buffer holds two periods
+    if converter.is_none() && audio_client.is_event_driven() && available_frames as usize != chunk_frames {
+        error!(target: CAPT_LOOP_TARGET, "CAPT INNER: available_frames {} != chunk_frames {} in EXCLUSIVE mode, failure in wasapi!", available_frames, chunk_frames);
+        return Err(NativeError::device("CAPT INNER: Misbehaving EXCLUSIVE mode"));
     }
 
-    //trace!("Started capture stream");
+    //trace!(target: CAPT_LOOP_TARGET, "Started capture stream");
+    // expected interval between device events
+    let period = Duration::from_secs_f64(chunk_frames as f64 / samplerate as f64);
+    let mut waiter = DeviceWaiter::new(handle, sync.wait_mode.clone(), period, "CAPT INNER");
+    // fading out before stopping, then stopping at the next stop check
+    let mut fade = FadeStop::default();
+    // a whole chunk in the exclusive mode, any engine packet in the shared mode
+    let min_readable_frames = if converter.is_some() { 1 } else { chunk_frames };
+    let is_readable = || -> Res<bool> { Ok(audio_client.get_current_padding()? as usize >= min_readable_frames) };
     let mut now = Instant::now();
     loop {
-        trace!("CAPT INNER: capturing");
+        trace!(target: CAPT_LOOP_TARGET, "CAPT INNER: capturing");
 
         // handling signals
         if sync.start_signal.load(Ordering::Relaxed) {
-            debug!("CAPT INNER: Starting device");
+            debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: Starting device");
+            fade.start(&mut gain, running);
             if !running {
                 audio_client.start_stream()?;
                 running = true;
@@ -1127,67 +2454,106 @@ fn capture_loop(
             sync.start_signal.store(false, Ordering::Relaxed);
             // staying in the loop
         }
-        if sync.stop_signal.load(Ordering::Relaxed) {
-            debug!("CAPT INNER: Stopping device");
+        if sync.thread_config_signal.swap(false, Ordering::Relaxed) {
+            let config = *sync.thread_config.lock()?;
+            debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: Applying thread config {:?}", config);
+            boost.apply(&config, &sync.thread_status, "CAPT INNER");
+        }
+        // capturing until faded out
+        if fade.stop_requested(&sync.stop_signal, &mut gain, running) {
+            debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: Stopping device");
             if running {
                 audio_client.stop_stream()?;
                 running = false;
                 time_tracker.reset();
             }
-            sync.stop_signal.store(false, Ordering::Relaxed);
             // staying in the loop with running=false
             continue;
         }
         if sync.exit_signal.load(Ordering::Relaxed) {
-            debug!("CAPT INNER: Exiting inner loop");
+            debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: Exiting inner loop");
             audio_client.stop_stream()?;
             sync.exit_signal.store(false, Ordering::Relaxed);
             return Ok(());
         }
+        if sync.flush_signal.load(Ordering::Relaxed) {
+            debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: Flushing, {}", if running {"stopping and resetting stream"} else {"resetting stream"});
+            let was_running = running;
+            if running {
+                audio_client.stop_stream()?;
+                running = false;
+            }
+            fade.cancel();
+            // discarding samples in the device buffer
+            audio_client.reset_stream()?;
+            time_tracker.reset();
+            // the outer side clears the queue and restarts the stream
+            sync.tx_flushed.try_send(was_running).unwrap_or(());
+            while sync.flush_signal.load(Ordering::Relaxed) && !sync.exit_signal.load(Ordering::Relaxed) {
+                sleep(Duration::from_millis(1));
+            }
+            continue;
+        }
 
         if !running {
             // Stopped but not exiting: must stay in the capture loop but cannot read from the device.
             // Shortly wait to avoid CPU hogging and continue looping
             sleep(Duration::from_millis(2));
+            // idle time is not loop time
+            now = Instant::now();
             continue;
         }
 
-        trace!("CAPT INNER: loop spent outside of wait_for_event {:?}", now.elapsed());
+        let outside = now.elapsed();
+        trace!(target: CAPT_LOOP_TARGET, "CAPT INNER: loop spent outside of wait_for_event {:?}", outside);
+        sync.timings.record(TimingPhase::Outside, outside);
         now = Instant::now();
-        let timeout = 250;
-        if handle.wait_for_event(timeout).is_err() {
-            trace!("CAPT INNER: Timeout {}ms on event", timeout);
+        let timeout = Duration::from_millis(250);
+        // further shared packets queued meanwhile are read without waiting for the next event
+        let pending = converter.is_some() && audio_client.get_current_padding()? > 0;
+        if !pending && !waiter.wait(timeout, is_readable)? {
+            trace!(target: CAPT_LOOP_TARGET, "CAPT INNER: Timeout {:?} on event", timeout);
             if !inactive {
-                warn!("CAPT INNER: No data received within timeout of {}ms, inactive", timeout);
+                warn!(target: CAPT_LOOP_TARGET, "CAPT INNER: No data received within timeout of {:?}, inactive", timeout);
                 inactive = true;
             }
             // no data received, continue the loop
             now = Instant::now();
             continue;
         }
-        trace!("CAPT INNER: loop spent in wait_for_event {:?}", now.elapsed());
+        let waited = now.elapsed();
+        sync.stats.record_wait(waited);
+        sync.timings.record(TimingPhase::Wait, waited);
+        if let Some(interval) = time_tracker.event_interval() {
+            sync.timings.record_interval(interval, period);
+        }
+        trace!(target: CAPT_LOOP_TARGET, "CAPT INNER: loop spent in wait_for_event {:?}", waited);
         now = Instant::now();
 
         // no event timeout, should have received data
         if inactive {
-            trace!("CAPT INNER: resuming, data received");
+            trace!(target: CAPT_LOOP_TARGET, "CAPT INNER: resuming, data received");
             inactive = false;
         }
 
-        // while waiting for event (the largest wait in the loop), stop/exit signals could have arrived. Must check again
-        if sync.stop_signal.load(Ordering::Relaxed) {
-            debug!("CAPT INNER: Stopping device");
+        // while waiting for event (the largest wait in the loop), stop/exit/flush signals could have arrived. Must check again
+        if sync.flush_signal.load(Ordering::Relaxed) {
+            // handled at the loop start, the captured data would be flushed anyway
+            continue;
+        }
+        // capturing until faded out
+        if fade.stop_requested(&sync.stop_signal, &mut gain, running) {
+            debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: Stopping device");
             if running {
                 audio_client.stop_stream()?;
                 running = false;
                 time_tracker.reset();
             }
-            sync.stop_signal.store(false, Ordering::Relaxed);
             // staying in the loop with running=false
             continue;
         }
         if sync.exit_signal.load(Ordering::Relaxed) {
-            debug!("CAPT INNER: Exiting inner loop");
+            debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: Exiting inner loop");
             audio_client.stop_stream()?;
             sync.exit_signal.store(false, Ordering::Relaxed);
             return Ok(());
@@ -1200,7 +2566,7 @@ fn capture_loop(
                 buf
             }
             None => {
-                trace!("CAPT INNER: Getting preallocated chunk from return queue containing {} items", sync.rx_prealloc.len());
+                trace!(target: CAPT_LOOP_TARGET, "CAPT INNER: Getting preallocated chunk from return queue containing {} items", sync.rx_prealloc.len());
                 match sync.rx_prealloc.recv() {
                     Ok(buf) => { buf }
                     Err(err) => {
@@ -1208,86 +2574,197 @@ fn capture_loop(
                         // RecvError would be thrown.
                         // Checking for exit signal to ignore this error condition.
                         return if sync.exit_signal.load(Ordering::Relaxed) {
-                            debug!("CAPT INNER: Exiting inner loop");
+                            debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: Exiting inner loop");
                             audio_client.stop_stream()?;
                             sync.exit_signal.store(false, Ordering::Relaxed);
                             Ok(())
                         } else {
                             // real error
-                            error!("{}", err.to_string());
-                            Err(Box::new(err))
+                            error!(target: CAPT_LOOP_TARGET, "{}", err.to_string());
+                            Err(err.into())
                         };
                     }
                 }
             }
         };
 
-        // adjusting the preallocated buffer to actual length
-        // this will proceed only once for each pre-allocated chunk because exclusive mode has fixed buffer size (= available_frames)
-        let chunk_bytes = available_frames as usize * frame_bytes;
-        if data.len() != chunk_bytes {
-            data.resize(chunk_bytes, 0);
-        }
-        let mut frames_read: u32 = 0;
-        let mut flags: BufferFlags = BufferFlags::new(0);
-        let mut duration = Duration::from_millis(0);
-        while frames_read == 0 {
-            (frames_read, flags) = capture_client.read_from_device(frame_bytes as usize, &mut data[0..chunk_bytes])?;
-            if frames_read == 0 {
-                if duration > max_duration {
-                    warn!("CAPT INNER: reading from device took longer than {:?}, aborting", max_duration);
-                    break;
-                } else {
-                    debug!("CAPT INNER: read 0 frames, will try again after sleep {:?}", sleep_duration);
-                    sleep(sleep_duration);
-                    duration += sleep_duration;
+        let (frames_read, flags, chunk_bytes) = match (converter.as_ref(), dev_chunk.as_mut()) {
+            (Some(conv), Some(dev_chunk)) => {
+                // one engine packet per chunk, converted to the java format
+                let (frames_read, flags) = capture_client.read_from_device(conv.src_frame_bytes(), dev_chunk)?;
+                if frames_read == 0 {
+                    LineStats::inc(&sync.stats.zero_frame_reads);
+                    saved_buffer = Some(data);
+                    continue;
                 }
+                let chunk_bytes = frames_read as usize * frame_bytes;
+                data.resize(chunk_bytes, 0);
+                conv.convert(&dev_chunk[0..frames_read as usize * conv.src_frame_bytes()], &mut data);
+                (frames_read, flags, chunk_bytes)
             }
-        }
-        if frames_read != available_frames {
-            warn!("CAPT INNER: expected {} frames, got {} in EXCLUSIVE mode!",available_frames, frames_read);
-        }
-
-        if flags.silent {
-            debug!("CAPT INNER: buffer marked as silent");
-            // zeroing all captured samples
-            data.iter_mut().take(chunk_bytes).for_each(|val| *val = 0);
-        }
+            _ => {
+                // adjusting the preallocated buffer to actual length
+                // this will proceed only once for each pre-allocated chunk because exclusive mode has fixed period size (= chunk_frames)
+                let chunk_bytes = chunk_frames * frame_bytes;
+                if data.len() != chunk_bytes {
+                    data.resize(chunk_bytes, 0);
+                }
+                let mut frames_read: u32 = 0;
+                let mut flags: BufferFlags = BufferFlags::new(0);
+                let mut duration = Duration::from_millis(0);
+                while frames_read == 0 {
+                    (frames_read, flags) = capture_client.read_from_device(frame_bytes as usize, &mut data[0..chunk_bytes])?;
+                    if frames_read == 0 {
+                        LineStats::inc(&sync.stats.zero_frame_reads);
+                        if duration > max_duration {
+                            warn!(target: CAPT_LOOP_TARGET, "CAPT INNER: reading from device took longer than {:?}, aborting", max_duration);
+                            break;
+                        } else {
+                            debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: read 0 frames, will try again after sleep {:?}", sleep_duration);
+                            sleep(sleep_duration);
+                            duration += sleep_duration;
+                        }
+                    }
+                }
+                if frames_read as usize != chunk_frames {
+                    warn!(target: CAPT_LOOP_TARGET, "CAPT INNER: expected {} frames, got {} in EXCLUSIVE mode!", chunk_frames, frames_read);
+                }
+                (frames_read, flags, chunk_bytes)
+            }
+        };
 
-        if flags.data_discontinuity {
-            warn!("CAPT INNER: device reported a buffer overrun");
-        }
-        if flags.timestamp_error {
-            warn!("CAPT INNER: device reported a timestamp error");
-        }
+        handle_capt_flags(&sync, &flags, &mut data[0..chunk_bytes]);
+        gain.process(&mut data[0..chunk_bytes]);
+        // the fade-out finished with the last captured chunk, stopping at the next stop check
+        fade.processed(&gain, true);
 
-        trace!("CAPT INNER: Sending a new chunk nbr. {} to main queue which contains {} unconsumed chunks", chunk_nbr, sync.tx_dev.len());
-        match sync.tx_dev.try_send((chunk_nbr, data)) {
-            Ok(()) => {
-                trace!("CAPT INNER: Chunk nbr. {} sent OK", chunk_nbr);
-            }
-            Err(TrySendError::Full((nbr, data))) => {
-                debug!("CAPT INNER: Outer side not consuming chunks, dropping the captured chunk {}", nbr);
-                saved_buffer = Some(data);
-            }
-            Err(TrySendError::Disconnected(_)) => {
-                error!("CAPT INNER: Error sending , channel from inner thread to main disconnected");
+        saved_buffer = match send_capt_chunk(&sync, chunk_nbr, data, frames_read as usize, frame_bytes) {
+            Ok(buf) => buf,
+            Err(err) => {
                 audio_client.stop_stream()?;
-                return Err(DeviceError::new("CAPT INNER: Error sending, channel from inner thread to main disconnected").into());
+                return Err(err);
             }
-        }
+        };
         chunk_nbr += 1;
-        let pos = clock.get_position()?.0;
+        LineStats::inc(&sync.stats.chunks);
+        let transfer = now.elapsed();
+        trace!(target: CAPT_LOOP_TARGET, "CAPT INNER: loop spent reading data from device {:?}", transfer);
+        sync.timings.record(TimingPhase::Transfer, transfer);
+        now = Instant::now();
+        let (pos, qpc_pos) = clock.get_position()?;
         let device_time = pos as f64 / device_freq;
-        if time_tracker.event_missing(device_time, available_frames as f64 / samplerate as f64) {
-            warn!("CAPT INNER: Missed event");
-            // if running {
-            //     warn!("CAPT INNER: resetting stream");
-            //     audio_client.stop_stream()?;
-            //     audio_client.reset_stream()?;
-            //     audio_client.start_stream()?;
-            //     time_tracker.reset();
-            // }
+        time_tracker.track_drift(device_time, qpc_pos);
+        // the engine packets are not paced by this stream alone, a polled client has no events
+        let missed = if converter.is_none() && audio_client.is_event_driven() {
+            time_tracker.event_missing(device_time, chunk_frames as f64 / samplerate as f64)
+        } else {
+            None
+        };
+        if let Some(missed_time) = missed {
+            warn!(target: CAPT_LOOP_TARGET, "CAPT INNER: Missed event");
+            LineStats::inc(&sync.stats.missed_events);
+            // only valid policies are stored by do_set_missed_event_policy
+            let policy = MissedEventPolicy::try_from(sync.missed_event_policy.load(Ordering::Relaxed)).unwrap_or(MissedEventPolicy::Warn);
+            if policy != MissedEventPolicy::Warn && running {
+                warn!(target: CAPT_LOOP_TARGET, "CAPT INNER: resetting stream, policy {:?}", policy);
+                LineStats::inc(&sync.stats.stream_resets);
+                audio_client.stop_stream()?;
+                audio_client.reset_stream()?;
+                audio_client.start_stream()?;
+                time_tracker.reset();
+                // at least the chunk discarded from the device buffer by the reset
+                let missed_chunks = cmp::max(1, (missed_time * samplerate as f64 / chunk_frames as f64).round() as u64);
+                let mut skipped_chunks = missed_chunks;
+                if policy == MissedEventPolicy::ResetSilence {
+                    while skipped_chunks > 0 {
+                        // never blocking on the preallocated chunks, the rest is skipped
+                        let mut silence = match saved_buffer.take().or_else(|| sync.rx_prealloc.try_recv().ok()) {
+                            Some(buf) => buf,
+                            None => break,
+                        };
+                        silence.resize(chunk_bytes, 0);
+                        silence.fill(0);
+                        match sync.tx_dev.try_send((chunk_nbr, silence)) {
+                            Ok(()) => {
+                                chunk_nbr += 1;
+                                skipped_chunks -= 1;
+                            }
+                            Err(TrySendError::Full((_, buf))) | Err(TrySendError::Disconnected((_, buf))) => {
+                                saved_buffer = Some(buf);
+                                break;
+                            }
+                        }
+                    }
+                    debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: queued {} silent chunks for the missed time", missed_chunks - skipped_chunks);
+                }
+                if skipped_chunks > 0 {
+                    // reported by do_read as a gap in chunk numbers
+                    debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: skipping {} chunk numbers for the missed time", skipped_chunks);
+                    chunk_nbr += skipped_chunks;
+                }
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Applies the buffer flags of a chunk read from the capture device
+pub(crate) fn handle_capt_flags(sync: &CaptSyncData, flags: &BufferFlags, data: &mut [u8]) {
+    if flags.silent {
+        debug!(target: CAPT_LOOP_TARGET, "CAPT INNER: buffer marked as silent");
+        LineStats::inc(&sync.stats.silent_flags);
+        // zeroing all captured samples
+        data.fill(0);
+    }
+    if flags.data_discontinuity {
+        log_event!(target: CAPT_LOOP_TARGET, LogEvent::Overrun, Level::Warn, "CAPT INNER: device reported a buffer overrun");
+        sync.dev_discontinuity.store(true, Ordering::Relaxed);
+        LineStats::inc(&sync.stats.discontinuity_flags);
+    }
+    if flags.timestamp_error {
+        warn!(target: CAPT_LOOP_TARGET, "CAPT INNER: device reported a timestamp error");
+        LineStats::inc(&sync.stats.timestamp_errors);
+    }
+}
+
+/// Queues the captured chunk for the outer side, applying the overrun policy when the queue is full.
+/// Returns the buffer freed by dropping a chunk, for reuse by the next capture.
+pub(crate) fn send_capt_chunk(sync: &CaptSyncData, chunk_nbr: u64, data: Vec<u8>, frames: usize, frame_bytes: usize) -> Res<Option<Vec<u8>>> {
+    trace!(target: CAPT_LOOP_TARGET, "CAPT INNER: Sending a new chunk nbr. {} to main queue which contains {} unconsumed chunks", chunk_nbr, sync.tx_dev.len());
+    let mut freed_buffer = None;
+    match sync.tx_dev.try_send((chunk_nbr, data)) {
+        Ok(()) => {
+            trace!(target: CAPT_LOOP_TARGET, "CAPT INNER: Chunk nbr. {} sent OK", chunk_nbr);
+        }
+        Err(TrySendError::Full((nbr, data))) => {
+            // only valid policies are stored by do_set_overrun_policy
+            match OverrunPolicy::try_from(sync.overrun_policy.load(Ordering::Relaxed)).unwrap_or(OverrunPolicy::DropNewest) {
+                OverrunPolicy::DropNewest => {
+                    log_event!(target: CAPT_LOOP_TARGET, LogEvent::Overrun, Level::Debug, "CAPT INNER: Outer side not consuming chunks, dropping the captured chunk {}", nbr);
+                    sync.overruns.record(frames);
+                    freed_buffer = Some(data);
+                }
+                OverrunPolicy::DropOldest => {
+                    // the outer side may have consumed the queue in the meantime, then nothing is dropped
+                    if let Ok((oldest_nbr, oldest_data)) = sync.rx_dev_draining.try_recv() {
+                        log_event!(target: CAPT_LOOP_TARGET, LogEvent::Overrun, Level::Debug, "CAPT INNER: Outer side not consuming chunks, dropping the oldest queued chunk {}", oldest_nbr);
+                        sync.overruns.record(oldest_data.len() / frame_bytes);
+                        // reused for the next capture
+                        freed_buffer = Some(oldest_data);
+                    }
+                    // the inner thread is the only producer, the queue has room now
+                    if let Err(err) = sync.tx_dev.try_send((nbr, data)) {
+                        warn!(target: CAPT_LOOP_TARGET, "CAPT INNER: Failed sending chunk {} after dropping the oldest one: {}", nbr, err);
+                        sync.overruns.record(frames);
+                        // keeping the buffer in the pool, the unbounded channel never blocks
+                        sync.tx_prealloc.send(err.into_inner().1).unwrap_or(());
+                    }
+                }
+            }
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            error!(target: CAPT_LOOP_TARGET, "CAPT INNER: Error sending , channel from inner thread to main disconnected");
+            return Err(NativeError::internal("CAPT INNER: Error sending, channel from inner thread to main disconnected"));
+        }
+    }
+    Ok(freed_buffer)
+}